@@ -0,0 +1,81 @@
+// Includes
+
+use std::path::Path;
+use std::process::ExitCode;
+
+// Helpers
+
+fn read_file(path: &Path) -> Result<Vec<u8>, String> {
+    std::fs::read(path).map_err(|e| format!("Could not read {}: {}.", path.display(), e))
+}
+
+fn read_entry(arg: &str) -> Result<(String, Vec<u8>), String> {
+    // Files are stored under their own name unless one is given.
+    let (name, path) = match arg.split_once('=') {
+        Some((name, path)) => (name.to_string(), Path::new(path)),
+        None => match Path::new(arg).file_name() {
+            Some(name) => (name.to_string_lossy().into_owned(), Path::new(arg)),
+            None => return Err(format!("{} is not a file.", arg)),
+        },
+    };
+
+    if name == mldl::MANIFEST_ENTRY {
+        return Err(format!("{} is reserved for the manifest.", name));
+    }
+
+    Ok((name, read_file(path)?))
+}
+
+fn pack(manifest: &str, output: &str, args: &[String]) -> Result<(), String> {
+    let mut files = vec![(
+        mldl::MANIFEST_ENTRY.to_string(),
+        read_file(Path::new(manifest))?,
+    )];
+
+    for arg in args {
+        let (name, data) = read_entry(arg)?;
+
+        if files.iter().any(|(n, _)| *n == name) {
+            return Err(format!("{} is packed twice.", name));
+        }
+
+        files.push((name, data));
+    }
+
+    let buffer = mldl::write_package(&files);
+
+    // Refuse to write a package the loader would reject.
+    let package = mldl::parse_package(&buffer).map_err(|e| format!("Invalid package: {}", e))?;
+
+    std::fs::write(output, &buffer).map_err(|e| format!("Could not write {}: {}.", output, e))?;
+
+    println!(
+        "{} {}: {} binaries, {} assets, {} bytes.",
+        package.manifest.id,
+        package.manifest.version,
+        package.manifest.binaries.len(),
+        package.assets().count(),
+        buffer.len()
+    );
+
+    Ok(())
+}
+
+// Main
+
+fn main() -> ExitCode {
+    let args = std::env::args().collect::<Vec<_>>();
+
+    if args.len() < 3 {
+        eprintln!("Usage: mlpack <manifest> <output> [[name=]file]...");
+        return ExitCode::FAILURE;
+    }
+
+    match pack(&args[1], &args[2], &args[3..]) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("{}", e);
+            ExitCode::FAILURE
+        }
+    }
+}
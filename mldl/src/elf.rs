@@ -1,55 +1,827 @@
-#![allow(unused)]
-
 // Includes
 
 use goblin::elf::*;
 use goblin::*;
 
+use crate::section::{read_struct, SectionReader};
 use crate::types::*;
 
+use std::collections::HashMap;
+use std::ops::Range;
+
+// Globals
+
+const DYN_SECTION: &str = ".mldyn";
+const HOOK_SECTION: &str = ".mlhook";
+const FINGERPRINT_SECTION: &str = ".mlfp";
+const TEXT_SECTION: &str = ".text";
+
+const BUILD_ID_NOTE: &str = ".note.gnu.build-id";
+
+// Types
+
+// Pointers in RELA images are zero on disk, the addends hold their values.
+type Relocations = HashMap<u64, u64>;
+
+struct FileImage<'a> {
+    elf: &'a Elf<'a>,
+    buffer: &'a [u8],
+    relocs: Relocations,
+    lib_offset: usize,
+}
+
+struct MappedSegment<'a> {
+    vaddr: u64,
+    data: &'a [u8],
+}
+
+struct SectionLocation {
+    kind: u32,
+    vaddr: u64,
+    size: u64,
+}
+
+// Mapped images are already relocated, their pointers are absolute.
+struct MappedImage<'a> {
+    bias: u64,
+    is_64: bool,
+    segments: Vec<MappedSegment<'a>>,
+    sections: Vec<SectionLocation>,
+}
+
+trait Image {
+    fn is_64(&self) -> bool;
+    fn open_section(&self, name: &'static str) -> error::Result<Option<SectionReader<'_>>>;
+    fn fix(&self, at: u64, raw: u64) -> u64;
+    fn string(&self, addr: u64) -> Option<&str>;
+    fn bytes(&self, addr: u64, size: usize) -> Option<Vec<u8>>;
+    fn address(&self, addr: u64) -> usize;
+}
+
 // Helpers
 
-unsafe fn dump_dyn<T>(
-    mut lib_offset: usize,
-    base: u64,
-    elf: &Elf,
-    dyn_table: &mut DynamicTable,
-) -> error::Result<()>
+fn find_section<'a>(elf: &'a Elf, name: &str) -> Option<&'a SectionHeader> {
+    elf.section_headers
+        .iter()
+        .find(|s| elf.shdr_strtab.get_at(s.sh_name) == Some(name))
+}
+
+fn find_raw_range(elf: &Elf, vaddr: u64) -> Option<Range<usize>> {
+    for ph in &elf.program_headers {
+        if ph.p_type == program_header::PT_LOAD
+            && vaddr >= ph.p_vaddr
+            && vaddr - ph.p_vaddr < ph.p_filesz
+        {
+            let start = usize::try_from((vaddr - ph.p_vaddr).checked_add(ph.p_offset)?).ok()?;
+            let end = usize::try_from(ph.p_offset.checked_add(ph.p_filesz)?).ok()?;
+            return Some(start..end);
+        }
+    }
+
+    None
+}
+
+fn get_relocations(elf: &Elf) -> Relocations {
+    // Exported data is referenced through its symbol, it still resolves to the binary itself.
+    let symbol = |index: usize| match index {
+        0 => Some(0),
+        _ => elf
+            .dynsyms
+            .get(index)
+            .filter(|sym| sym.st_shndx != section_header::SHN_UNDEF as usize)
+            .map(|sym| sym.st_value),
+    };
+
+    elf.dynrelas
+        .iter()
+        .filter_map(|r| {
+            Some((
+                r.r_offset,
+                symbol(r.r_sym)?.wrapping_add(r.r_addend? as u64),
+            ))
+        })
+        .collect()
+}
+
+fn locator_kind(name: &str) -> u32 {
+    match name {
+        DYN_SECTION => LOCATOR_DYN,
+        HOOK_SECTION => LOCATOR_HOOK,
+        _ => LOCATOR_FINGERPRINT,
+    }
+}
+
+fn align_note(size: usize) -> usize {
+    (size + 3) & !3
+}
+
+fn find_locators(data: &[u8], vaddr: u64) -> Vec<SectionLocation> {
+    let mut locators = Vec::new();
+    let mut offset = 0usize;
+
+    // Each note is a 12 byte header followed by the padded name and descriptor.
+    while let Some([name_size, desc_size, note_type]) = read_struct::<[u32; 3]>(data, offset) {
+        let name = offset + 12;
+        let desc = name + align_note(name_size as usize);
+        let next = desc + align_note(desc_size as usize);
+
+        let owner = match data.get(name..name + name_size as usize) {
+            Some(owner) if next <= data.len() => owner,
+            _ => break,
+        };
+
+        if note_type == NT_ML_SECTIONS && owner == ML_NOTE_NAME {
+            let size = std::mem::size_of::<SecLocator>();
+
+            for i in 0..desc_size as usize / size {
+                let locator = read_struct::<SecLocator>(data, desc + i * size).unwrap();
+                let at = vaddr + (desc + i * size + 4) as u64;
+
+                // Offsets are relative to their own field, the linker resolves them.
+                locators.push(SectionLocation {
+                    kind: locator.kind,
+                    vaddr: at.wrapping_add(locator.offset as i64 as u64),
+                    size: locator.size as u64,
+                });
+            }
+        }
+
+        offset = next;
+    }
+
+    locators
+}
+
+// File
+
+impl Image for FileImage<'_> {
+    fn is_64(&self) -> bool {
+        self.elf.is_64
+    }
+
+    fn open_section(&self, name: &'static str) -> error::Result<Option<SectionReader<'_>>> {
+        match find_section(self.elf, name) {
+            Some(sec) if sec.sh_type != section_header::SHT_NOBITS => {
+                SectionReader::new(name, self.buffer, sec.sh_offset, sec.sh_size, sec.sh_addr)
+                    .map(Some)
+            }
+            _ => Ok(None),
+        }
+    }
+
+    fn fix(&self, at: u64, raw: u64) -> u64 {
+        *self.relocs.get(&at).unwrap_or(&raw)
+    }
+
+    fn string(&self, addr: u64) -> Option<&str> {
+        crate::section::read_cstr(self.buffer.get(find_raw_range(self.elf, addr)?)?)
+    }
+
+    fn bytes(&self, addr: u64, size: usize) -> Option<Vec<u8>> {
+        self.buffer
+            .get(find_raw_range(self.elf, addr)?)?
+            .get(..size)
+            .map(<[u8]>::to_vec)
+    }
+
+    fn address(&self, addr: u64) -> usize {
+        self.lib_offset + (addr as usize)
+    }
+}
+
+// Mapped
+
+impl<'a> MappedImage<'a> {
+    fn find(&self, vaddr: u64) -> Option<&'a [u8]> {
+        self.segments
+            .iter()
+            .find(|s| vaddr >= s.vaddr && vaddr - s.vaddr < s.data.len() as u64)
+            .map(|s| &s.data[(vaddr - s.vaddr) as usize..])
+    }
+}
+
+impl Image for MappedImage<'_> {
+    fn is_64(&self) -> bool {
+        self.is_64
+    }
+
+    fn open_section(&self, name: &'static str) -> error::Result<Option<SectionReader<'_>>> {
+        let locator = match self.sections.iter().find(|l| l.kind == locator_kind(name)) {
+            Some(l) => l,
+            None => return Ok(None),
+        };
+
+        match self.find(locator.vaddr) {
+            Some(data) => SectionReader::new(name, data, 0, locator.size, locator.vaddr).map(Some),
+            None => Err(error::Error::Malformed(format!(
+                "{} is out of bounds!",
+                name
+            ))),
+        }
+    }
+
+    fn fix(&self, _at: u64, raw: u64) -> u64 {
+        raw
+    }
+
+    fn string(&self, addr: u64) -> Option<&str> {
+        crate::section::read_cstr(self.find(addr.wrapping_sub(self.bias))?)
+    }
+
+    fn bytes(&self, addr: u64, size: usize) -> Option<Vec<u8>> {
+        self.find(addr.wrapping_sub(self.bias))?
+            .get(..size)
+            .map(<[u8]>::to_vec)
+    }
+
+    fn address(&self, addr: u64) -> usize {
+        addr as usize
+    }
+}
+
+// Dumper
+
+fn dump_dyn<T, I>(image: &I, dyn_table: &mut DynamicTable) -> error::Result<()>
 where
     T: SecDynamic,
+    I: Image,
 {
-    todo!("Unimplemented!")
+    let mut reader = match image.open_section(DYN_SECTION)? {
+        Some(r) => r,
+        None => return Ok(()),
+    };
+
+    let ptr_size = if image.is_64() { 8 } else { 4 };
+
+    while let Some((at, entry)) = reader.next::<T>()? {
+        let fix = |index: u64, raw: u64| image.fix(at + index * ptr_size, raw);
+        let address = fix(0, entry.address());
+
+        if reader.terminates(address == 0)? {
+            break;
+        }
+
+        let sym = match image.string(fix(1, entry.sym())) {
+            Some(s) => s.to_string(),
+            None => return Err(reader.error("could not read the symbol name")),
+        };
+
+        let record = match fix(2, entry.record()) {
+            0 => String::from(""),
+            va => match image.string(va) {
+                Some(s) => s.to_string(),
+                None => return Err(reader.error("could not read the record name")),
+            },
+        };
+
+        dyn_table.push(DynamicEntry {
+            address: image.address(address),
+            sym,
+            record,
+        });
+    }
+
+    Ok(())
+}
+
+fn read_expected<E, I>(
+    image: &I,
+    ext: &E,
+    fix: impl Fn(u64, u64) -> u64,
+) -> Option<(Vec<u8>, Vec<u8>)>
+where
+    E: SecExpect,
+    I: Image,
+{
+    let size = ext.size() as usize;
+    let bytes = image.bytes(fix(0, ext.bytes()), size)?;
+
+    let mask = match fix(1, ext.mask()) {
+        0 => Vec::new(),
+        va => image.bytes(va, size)?,
+    };
+
+    Some((bytes, mask))
 }
 
-unsafe fn dump_hooks<T>(
-    mut lib_offset: usize,
-    base: u64,
-    elf: &Elf,
-    hook_table: &mut HookTable,
-) -> error::Result<()>
+#[allow(deprecated)]
+fn dump_hooks<T, E, R, N, I>(image: &I, hook_table: &mut HookTable) -> error::Result<()>
 where
     T: SecHook,
+    E: SecExpect,
+    R: SecTarget,
+    N: SecRecord,
+    I: Image,
 {
-    todo!("Unimplemented!")
+    let mut reader = match image.open_section(HOOK_SECTION)? {
+        Some(r) => r,
+        None => return Ok(()),
+    };
+
+    let ptr_size = if image.is_64() { 8 } else { 4 };
+
+    while let Some((at, entry)) = reader.next::<T>()? {
+        let fix = |index: u64, raw: u64| image.fix(at + index * ptr_size, raw);
+        let target = fix(0, entry.target());
+
+        if reader.terminates(target == 0)? {
+            break;
+        }
+
+        let flags = entry.flags();
+        let dispatcher = (flags & FLAG_DISPATCHER) != 0;
+        let locking = !dispatcher && (flags & FLAG_LOCKING) != 0;
+        let callback = fix(1, entry.callback());
+
+        let target = match (flags >> TARGET_SHIFT) & TARGET_MASK {
+            TARGET_ADDRESS if (flags & FLAG_DYNAMIC) != 0 => match image.string(target) {
+                Some(s) => HookTarget::Export {
+                    module: String::new(),
+                    symbol: s.to_string(),
+                },
+                None => return Err(reader.error("could not read the target symbol")),
+            },
+            TARGET_ADDRESS => HookTarget::Address(image.address(target)),
+            kind if kind > TARGET_RVA => return Err(reader.error("unknown target kind")),
+            kind => {
+                let record = match image
+                    .bytes(target, std::mem::size_of::<R>())
+                    .and_then(|b| read_struct::<R>(&b, 0))
+                {
+                    Some(r) => r,
+                    None => return Err(reader.error("could not read the target record")),
+                };
+
+                let fix = |index: u64, raw: u64| image.fix(target + index * ptr_size, raw);
+
+                crate::read_target(
+                    kind,
+                    fix(0, record.module()),
+                    fix(1, record.name()),
+                    record.value(),
+                    |va| image.string(va).map(str::to_string),
+                )
+                .map_err(|e| reader.error(e))?
+            }
+        };
+
+        // The expected bytes record follows its entry.
+        let (expected, expected_mask) = match (flags & FLAG_EXPECT) != 0 {
+            true => {
+                let (ext_at, ext) = reader.extension::<E>()?;
+                let ext_fix = |index: u64, raw: u64| image.fix(ext_at + index * ptr_size, raw);
+
+                match read_expected(image, &ext, ext_fix) {
+                    Some(e) => e,
+                    None => return Err(reader.error("could not read the expected bytes")),
+                }
+            }
+            false => (Vec::new(), Vec::new()),
+        };
+
+        // Record names follow the expected bytes.
+        let record = match (flags & FLAG_RECORD) != 0 {
+            true => {
+                let (ext_at, ext) = reader.extension::<N>()?;
+
+                match image.fix(ext_at, ext.name()) {
+                    0 => String::new(),
+                    va => match image.string(va) {
+                        Some(s) => s.to_string(),
+                        None => return Err(reader.error("could not read the record name")),
+                    },
+                }
+            }
+            false => String::new(),
+        };
+
+        let hook = HookEntry {
+            dynamic: target.is_dynamic(),
+            target,
+            callback: image.address(callback),
+            dispatcher,
+            locking,
+            preload: !dispatcher && (flags & FLAG_PRELOAD) != 0,
+            optional: !dispatcher && (flags & FLAG_OPTIONAL) != 0,
+            priority: !dispatcher && (flags & FLAG_PRIORITY) != 0,
+            guard: !dispatcher && (flags & FLAG_GUARD) != 0,
+            target_conv: CallConv::from_flags(flags, CONV_TARGET_SHIFT),
+            hook_conv: CallConv::from_flags(flags, CONV_HOOK_SHIFT),
+            args: ((flags >> ARGS_SHIFT) & ARGS_MASK) as usize,
+            expected,
+            expected_mask,
+            record,
+        };
+
+        // Dispatchers only carry their address, like in PE images.
+        let hook = match dispatcher {
+            true => HookEntry {
+                target_conv: CallConv::Cdecl,
+                hook_conv: CallConv::Cdecl,
+                args: 0,
+                ..hook
+            },
+            false => hook,
+        };
+
+        match (dispatcher, locking) {
+            (true, _) => hook_table.dispatchers.push(hook),
+            (false, true) => hook_table.locking_hooks.push(hook),
+            (false, false) => hook_table.hooks.push(hook),
+        }
+    }
+
+    Ok(())
 }
 
-// ELF
+fn dump_fingerprints<T, I>(image: &I, fp_table: &mut FingerprintTable) -> error::Result<()>
+where
+    T: SecFingerprint,
+    I: Image,
+{
+    let mut reader = match image.open_section(FINGERPRINT_SECTION)? {
+        Some(r) => r,
+        None => return Ok(()),
+    };
 
-pub fn dump_elf(offset: usize, base: u64, elf: &Elf) -> error::Result<(DynamicTable, HookTable)> {
-    let mut dyn_table = DynamicTable::new();
-    let mut hook_table = HookTable::default();
+    let ptr_size = if image.is_64() { 8 } else { 4 };
 
-    if elf.is_64 {
-        unsafe {
-            dump_dyn::<SecDynamic64>(offset, base, elf, &mut dyn_table)?;
-            dump_hooks::<SecHook64>(offset, base, elf, &mut hook_table)?;
+    while let Some((at, entry)) = reader.next::<T>()? {
+        let fix = |index: u64, raw: u64| image.fix(at + index * ptr_size, raw);
+        let build_id = fix(1, entry.build_id());
+
+        if reader.terminates(build_id == 0 && entry.file_size() == 0 && entry.text_hash() == 0)? {
+            break;
         }
+
+        let module = match fix(0, entry.module()) {
+            0 => String::new(),
+            va => match image.string(va) {
+                Some(s) => s.to_string(),
+                None => return Err(reader.error("could not read the module name")),
+            },
+        };
+
+        let build_id = match build_id {
+            0 => Vec::new(),
+            va => match image.string(va).and_then(crate::parse_build_id) {
+                Some(id) => id,
+                None => return Err(reader.error("invalid build-id")),
+            },
+        };
+
+        fp_table.push(FingerprintEntry {
+            module,
+            build_id,
+            file_size: entry.file_size(),
+            text_hash: entry.text_hash(),
+        });
+    }
+
+    Ok(())
+}
+
+// ELF
+
+fn dump_image<I: Image>(image: &I) -> error::Result<MLMetadata> {
+    let mut metadata = MLMetadata::default();
+
+    if image.is_64() {
+        dump_dyn::<SecDynamic64, I>(image, &mut metadata.dynamic)?;
+        dump_hooks::<SecHook64, SecExpect64, SecTarget64, SecRecord64, I>(
+            image,
+            &mut metadata.hooks,
+        )?;
+        dump_fingerprints::<SecFingerprint64, I>(image, &mut metadata.fingerprints)?;
     } else {
+        dump_dyn::<SecDynamic32, I>(image, &mut metadata.dynamic)?;
+        dump_hooks::<SecHook32, SecExpect32, SecTarget32, SecRecord32, I>(
+            image,
+            &mut metadata.hooks,
+        )?;
+        dump_fingerprints::<SecFingerprint32, I>(image, &mut metadata.fingerprints)?;
+    }
+
+    Ok(metadata)
+}
+
+pub fn dump_elf(offset: usize, buffer: &[u8], elf: &Elf) -> error::Result<MLMetadata> {
+    dump_image(&FileImage {
+        elf,
+        buffer,
+        relocs: get_relocations(elf),
+        lib_offset: offset,
+    })
+}
+
+pub unsafe fn dump_elf_image(
+    bias: usize,
+    segments: &[ImageSegment],
+) -> error::Result<Option<MLMetadata>> {
+    let segments = segments
+        .iter()
+        .filter(|s| s.readable && s.size != 0)
+        .map(|s| {
+            (
+                s,
+                std::slice::from_raw_parts(s.address as *const u8, s.size),
+            )
+        })
+        .collect::<Vec<_>>();
+
+    // The segment at offset 0 holds the headers, anything it doesn't cover isn't an image.
+    let page = match segments.iter().find(|(s, _)| s.offset == 0) {
+        Some((_, data)) if data.get(..header::SELFMAG) == Some(header::ELFMAG) => *data,
+        _ => return Ok(None),
+    };
+
+    let header = Elf::parse_header(page)?;
+    let ctx = container::Ctx::new(header.container()?, header.endianness()?);
+    let phdr_end = (header.e_phnum as u64)
+        .checked_mul(header.e_phentsize as u64)
+        .and_then(|size| size.checked_add(header.e_phoff));
+
+    if phdr_end.is_none_or(|end| end > page.len() as u64) {
+        return Ok(None);
+    }
+
+    let phdrs = ProgramHeader::parse(page, header.e_phoff as _, header.e_phnum as _, ctx)?;
+
+    let mut mapped = MappedImage {
+        bias: bias as u64,
+        is_64: ctx.container == container::Container::Big,
+        segments: segments
+            .iter()
+            .map(|(s, data)| MappedSegment {
+                vaddr: s.address.wrapping_sub(bias) as u64,
+                data,
+            })
+            .collect(),
+        sections: Vec::new(),
+    };
+
+    for ph in phdrs
+        .iter()
+        .filter(|ph| ph.p_type == program_header::PT_NOTE)
+    {
+        if let Some(data) = mapped
+            .find(ph.p_vaddr)
+            .and_then(|d| d.get(..ph.p_memsz as usize))
+        {
+            mapped.sections.extend(find_locators(data, ph.p_vaddr));
+        }
+    }
+
+    // Binaries without a locator note are parsed from their file.
+    match mapped.sections.is_empty() {
+        true => Ok(None),
+        false => dump_image(&mapped).map(Some),
+    }
+}
+
+pub fn find_symbol(elf: &Elf, name: &str) -> Option<u64> {
+    elf.syms
+        .iter()
+        .find(|sym| sym.st_value != 0 && elf.strtab.get_at(sym.st_name) == Some(name))
+        .map(|sym| sym.st_value)
+}
+
+pub fn fingerprint_elf(buffer: &[u8], elf: &Elf) -> error::Result<Fingerprint> {
+    let mut fingerprint = Fingerprint {
+        file_size: buffer.len() as u64,
+        ..Default::default()
+    };
+
+    if let Some(notes) = elf.iter_note_sections(buffer, Some(BUILD_ID_NOTE)) {
+        for note in notes {
+            let note = note?;
+
+            if note.n_type == note::NT_GNU_BUILD_ID {
+                fingerprint.build_id = note.desc.to_vec();
+                break;
+            }
+        }
+    }
+
+    if let Some(sec) = find_section(elf, TEXT_SECTION) {
+        let start = sec.sh_offset as usize;
+        let end = start.checked_add(sec.sh_size as usize);
+
+        match end.and_then(|end| buffer.get(start..end)) {
+            Some(text) => fingerprint.text_hash = crate::hash_text(text),
+            None => return Err(error::Error::Malformed(String::from("Truncated .text!"))),
+        }
+    }
+
+    Ok(fingerprint)
+}
+
+// Tests
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BASE: u64 = 0x1000;
+    const CALLBACK: u64 = 0x2000;
+
+    fn raw<T: Copy>(value: T) -> Vec<u8> {
+        let size = std::mem::size_of::<T>();
+        unsafe { std::slice::from_raw_parts(&value as *const T as *const u8, size).to_vec() }
+    }
+
+    fn header(version: u32, count: u64) -> Vec<u8> {
+        raw(SecHeader {
+            magic: SECTION_MAGIC,
+            version,
+            count,
+        })
+    }
+
+    fn hook(target: u64, flags: u64) -> Vec<u8> {
+        raw(SecHook64 {
+            target,
+            callback: CALLBACK,
+            flags,
+        })
+    }
+
+    // The hook section comes first, the data it points at follows in the same segment.
+    fn parse(section: &[u8], data: &[u8]) -> error::Result<MLMetadata> {
+        let segment = [section, data].concat();
+
+        dump_image(&MappedImage {
+            bias: 0,
+            is_64: true,
+            segments: vec![MappedSegment {
+                vaddr: BASE,
+                data: &segment,
+            }],
+            sections: vec![SectionLocation {
+                kind: LOCATOR_HOOK,
+                vaddr: BASE,
+                size: section.len() as u64,
+            }],
+        })
+    }
+
+    fn is_malformed(result: error::Result<MLMetadata>) -> bool {
+        matches!(result, Err(error::Error::Malformed(_)))
+    }
+
+    #[test]
+    fn headers_bound_the_entries() {
+        let section = [header(SECTION_VERSION, 1), hook(0x3000, 0), hook(0x4000, 0)].concat();
+        let metadata = parse(&section, &[]).unwrap();
+
+        assert_eq!(metadata.hooks.hooks.len(), 1);
+        assert_eq!(metadata.hooks.hooks[0].target, HookTarget::Address(0x3000));
+        assert_eq!(metadata.hooks.hooks[0].callback, CALLBACK as usize);
+    }
+
+    #[test]
+    fn unknown_versions_are_rejected() {
+        let section = [header(SECTION_VERSION + 1, 1), hook(0x3000, 0)].concat();
+        assert!(is_malformed(parse(&section, &[])));
+    }
+
+    #[test]
+    fn legacy_sections_end_at_null() {
+        let section = [hook(0x3000, 0), hook(0, 0), hook(0x4000, 0)].concat();
+        let metadata = parse(&section, &[]).unwrap();
+
+        assert_eq!(metadata.hooks.hooks.len(), 1);
+
+        // Without a header the section may also just run out.
+        let metadata = parse(&hook(0x3000, 0), &[]).unwrap();
+        assert_eq!(metadata.hooks.hooks.len(), 1);
+    }
+
+    #[test]
+    fn counted_sections_reject_nulls_and_truncation() {
+        let section = [header(SECTION_VERSION, 2), hook(0x3000, 0), hook(0, 0)].concat();
+        assert!(is_malformed(parse(&section, &[])));
+
+        let section = [header(SECTION_VERSION, 2), hook(0x3000, 0)].concat();
+        assert!(is_malformed(parse(&section, &[])));
+    }
+
+    #[test]
+    fn out_of_range_pointers_are_rejected() {
+        let section = [header(SECTION_VERSION, 1), hook(0x8000, FLAG_DYNAMIC)].concat();
+        assert!(is_malformed(parse(&section, &[])));
+    }
+
+    #[test]
+    fn unknown_target_kinds_are_rejected() {
+        let flags = (TARGET_RVA + 1) << TARGET_SHIFT;
+        let section = [header(SECTION_VERSION, 1), hook(0x3000, flags)].concat();
+        assert!(is_malformed(parse(&section, &[])));
+    }
+
+    #[test]
+    fn extensions_follow_their_entry() {
+        let size = std::mem::size_of::<SecHeader>()
+            + std::mem::size_of::<SecHook64>() * 2
+            + std::mem::size_of::<SecExpect64>()
+            + std::mem::size_of::<SecRecord64>();
+        let bytes = BASE + size as u64;
+        let name = bytes + 2;
+
+        let section = [
+            header(SECTION_VERSION, 2),
+            hook(0x3000, FLAG_EXPECT | FLAG_RECORD),
+            raw(SecExpect64 {
+                bytes,
+                mask: 0,
+                size: 2,
+            }),
+            raw(SecRecord64 {
+                name,
+                reserved: 0,
+                reserved2: 0,
+            }),
+            hook(0x4000, 0),
+        ]
+        .concat();
+
+        let metadata = parse(&section, b"\x55\x8Brecord\0").unwrap();
+        let hooks = &metadata.hooks.hooks;
+
+        assert_eq!(hooks.len(), 2);
+        assert_eq!(hooks[0].expected, [0x55, 0x8B]);
+        assert_eq!(hooks[0].record, "record");
+        assert_eq!(hooks[1].target, HookTarget::Address(0x4000));
+    }
+
+    #[cfg(all(target_os = "linux", target_pointer_width = "64"))]
+    #[repr(C)]
+    struct PhdrInfo {
+        addr: usize,
+        name: *const u8,
+        phdr: *const goblin::elf64::program_header::ProgramHeader,
+        phnum: u16,
+    }
+
+    #[cfg(all(target_os = "linux", target_pointer_width = "64"))]
+    extern "C" {
+        fn dl_iterate_phdr(
+            callback: unsafe extern "C" fn(*mut PhdrInfo, usize, *mut u8) -> i32,
+            data: *mut u8,
+        ) -> i32;
+    }
+
+    #[cfg(all(target_os = "linux", target_pointer_width = "64"))]
+    unsafe extern "C" fn collect_segments(info: *mut PhdrInfo, _size: usize, data: *mut u8) -> i32 {
+        let (bias, segments) = &mut *(data as *mut (usize, Vec<ImageSegment>));
+        let info = &*info;
+        *bias = info.addr;
+
+        for i in 0..info.phnum as usize {
+            let phdr = &*info.phdr.add(i);
+
+            if phdr.p_type == program_header::PT_LOAD {
+                segments.push(ImageSegment {
+                    address: *bias + phdr.p_vaddr as usize,
+                    size: phdr.p_memsz as usize,
+                    offset: phdr.p_offset as usize,
+                    readable: (phdr.p_flags & program_header::PF_R) != 0,
+                });
+            }
+        }
+
+        // The test binary comes first.
+        1
+    }
+
+    #[cfg(all(target_os = "linux", target_pointer_width = "64"))]
+    #[test]
+    fn parses_own_image() {
+        let mut found: (usize, Vec<ImageSegment>) = (0, Vec::new());
+
         unsafe {
-            dump_dyn::<SecDynamic32>(offset, base, elf, &mut dyn_table)?;
-            dump_hooks::<SecHook32>(offset, base, elf, &mut hook_table)?;
+            dl_iterate_phdr(collect_segments, &mut found as *mut _ as _);
+
+            // No locator note, so the caller falls back to the file.
+            let (bias, segments) = &found;
+            assert!(segments.iter().any(|s| s.offset == 0));
+            assert!(dump_elf_image(*bias, segments).unwrap().is_none());
+
+            // Without a readable header segment there is nothing to parse.
+            let hidden = segments
+                .iter()
+                .map(|&s| ImageSegment {
+                    readable: s.offset != 0,
+                    ..s
+                })
+                .collect::<Vec<_>>();
+            assert!(dump_elf_image(*bias, &hidden).unwrap().is_none());
         }
     }
 
-    Ok((dyn_table, hook_table))
+    #[test]
+    fn truncated_extensions_are_rejected() {
+        let section = [header(SECTION_VERSION, 1), hook(0x3000, FLAG_EXPECT)].concat();
+        assert!(is_malformed(parse(&section, &[])));
+    }
 }
@@ -1,7 +1,10 @@
 mod elf;
+mod package;
 mod pe;
+mod section;
 mod types;
 
+pub use self::package::*;
 pub use self::types::*;
 
 // Includes
@@ -15,16 +18,141 @@ use std::path::Path;
 
 pub type MLError<T> = error::Result<T>;
 
+// Globals
+
+const FNV_OFFSET: u64 = 0xCBF29CE484222325;
+const FNV_PRIME: u64 = 0x100000001B3;
+
+// Helpers
+
+pub(crate) fn hash_text(text: &[u8]) -> u64 {
+    text.iter()
+        .fold(FNV_OFFSET, |h, &b| (h ^ b as u64).wrapping_mul(FNV_PRIME))
+}
+
+pub(crate) fn parse_build_id(s: &str) -> Option<Vec<u8>> {
+    if !s.len().is_multiple_of(2) {
+        return None;
+    }
+
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(s.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+pub(crate) fn parse_pattern(s: &str) -> Option<Vec<Option<u8>>> {
+    let pattern = s
+        .split_whitespace()
+        .map(|b| match b {
+            "?" | "??" => Some(None),
+            _ => u8::from_str_radix(b, 16).ok().map(Some),
+        })
+        .collect::<Option<Vec<_>>>()?;
+
+    // A pattern made only of wildcards matches anything.
+    match pattern.iter().any(Option::is_some) {
+        true => Some(pattern),
+        false => None,
+    }
+}
+
+pub(crate) fn read_target(
+    kind: u64,
+    module: u64,
+    name: u64,
+    value: u64,
+    string: impl Fn(u64) -> Option<String>,
+) -> Result<HookTarget, &'static str> {
+    let module = match module {
+        0 => String::new(),
+        va => string(va).ok_or("could not read the target module")?,
+    };
+
+    let name = match name {
+        0 => None,
+        va => string(va),
+    };
+
+    match kind {
+        TARGET_EXPORT => Ok(HookTarget::Export {
+            module,
+            symbol: name.ok_or("could not read the target symbol")?,
+        }),
+        TARGET_SYMBOL => Ok(HookTarget::Symbol {
+            module,
+            symbol: name.ok_or("could not read the target symbol")?,
+        }),
+        TARGET_PATTERN => Ok(HookTarget::Pattern {
+            module,
+            pattern: name
+                .as_deref()
+                .and_then(self::parse_pattern)
+                .ok_or("invalid target pattern")?,
+            offset: value as i64,
+        }),
+        TARGET_RVA => Ok(HookTarget::Rva {
+            module,
+            rva: value as usize,
+        }),
+        _ => Err("unknown target kind"),
+    }
+}
+
 // MLDL
 
-pub fn parse_ml_binary(offset: usize, path: &Path) -> MLError<(DynamicTable, HookTable)> {
+pub fn parse_ml_binary(offset: usize, path: &Path) -> MLError<MLMetadata> {
+    self::parse_ml_buffer(offset, &fs::read(path)?)
+}
+
+pub fn parse_ml_buffer(offset: usize, buffer: &[u8]) -> MLError<MLMetadata> {
+    match Object::parse(buffer)? {
+        Object::Elf(elf) => elf::dump_elf(offset, buffer, &elf),
+        Object::PE(pe) => pe::dump_pe(offset, buffer, &pe),
+        _ => Err(error::Error::Malformed(String::from(
+            "Invalid binary file!",
+        ))),
+    }
+}
+
+/// # Safety
+///
+/// `segments` must be the loaded segments of the module at `bias`, mapped for the whole call.
+pub unsafe fn parse_ml_image(
+    bias: usize,
+    segments: &[ImageSegment],
+) -> MLError<Option<MLMetadata>> {
+    // Only ELF images carry a locator note, other formats are parsed from their file.
+    elf::dump_elf_image(bias, segments)
+}
+
+pub fn fingerprint_binary(path: &Path) -> MLError<Fingerprint> {
     let buffer = fs::read(path)?;
 
     match Object::parse(&buffer)? {
-        Object::Elf(elf) => elf::dump_elf(offset, buffer.as_ptr() as _, &elf),
-        Object::PE(pe) => pe::dump_pe(offset, buffer.as_ptr() as _, &pe),
+        Object::Elf(elf) => elf::fingerprint_elf(&buffer, &elf),
+        Object::PE(pe) => pe::fingerprint_pe(&buffer, &pe),
         _ => Err(error::Error::Malformed(String::from(
             "Invalid binary file!",
         ))),
     }
 }
+
+pub fn find_symbol(path: &Path, name: &str) -> MLError<Option<u64>> {
+    let buffer = fs::read(path)?;
+
+    match Object::parse(&buffer)? {
+        Object::Elf(elf) => Ok(elf::find_symbol(&elf, name)),
+        _ => Ok(None),
+    }
+}
+
+pub fn find_needed(path: &Path) -> MLError<Vec<String>> {
+    let buffer = fs::read(path)?;
+
+    match Object::parse(&buffer)? {
+        Object::Elf(elf) => Ok(elf.libraries.iter().map(|s| s.to_string()).collect()),
+        Object::PE(pe) => Ok(pe.libraries.iter().map(|s| s.to_string()).collect()),
+        _ => Ok(Vec::new()),
+    }
+}
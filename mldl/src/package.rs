@@ -0,0 +1,368 @@
+// Includes
+
+use goblin::*;
+
+use crate::section::read_struct;
+use crate::types::*;
+
+use std::fmt::{Display, Formatter, Result};
+use std::mem::size_of;
+
+// Types
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct PkgHeader {
+    magic: u32,
+    version: u32,
+    count: u64,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct PkgEntry {
+    name_offset: u64,
+    name_size: u64,
+    data_offset: u64,
+    data_size: u64,
+    hash: u64,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub struct PackageVersion {
+    pub major: u32,
+    pub minor: u32,
+    pub patch: u32,
+}
+
+pub struct PackageDependency {
+    pub id: String,
+    pub version: Option<PackageVersion>,
+}
+
+pub struct PackageManifest {
+    pub id: String,
+    pub version: PackageVersion,
+    pub dependencies: Vec<PackageDependency>,
+    pub fingerprints: FingerprintTable,
+    pub binaries: Vec<(String, String)>,
+}
+
+pub struct PackageEntry {
+    pub name: String,
+    pub offset: usize,
+    pub size: usize,
+}
+
+pub struct Package {
+    pub manifest: PackageManifest,
+    pub entries: Vec<PackageEntry>,
+}
+
+// Globals
+
+pub const PACKAGE_MAGIC: u32 = 0x4B504C4D;
+pub const PACKAGE_VERSION: u32 = 1;
+pub const MANIFEST_ENTRY: &str = "manifest";
+
+pub const PACKAGE_ARCHS: &[&str] = &["x86", "x86_64", "arm", "aarch64"];
+
+// Helpers
+
+fn malformed<T>(message: String) -> error::Result<T> {
+    Err(error::Error::Malformed(message))
+}
+
+fn read_range(buffer: &[u8], offset: u64, size: u64) -> Option<(usize, usize)> {
+    let offset = usize::try_from(offset).ok()?;
+    let size = usize::try_from(size).ok()?;
+
+    buffer.get(offset..offset.checked_add(size)?)?;
+    Some((offset, size))
+}
+
+fn parse_fingerprint(value: &str) -> Option<FingerprintEntry> {
+    let mut entry = FingerprintEntry {
+        module: String::new(),
+        build_id: Vec::new(),
+        file_size: 0,
+        text_hash: 0,
+    };
+
+    // Fields left out accept any value, like in `.mlfp`.
+    for field in value.split_whitespace() {
+        match field.split_once('=')? {
+            ("module", module) => entry.module = module.to_string(),
+            ("build_id", id) => entry.build_id = crate::parse_build_id(id)?,
+            ("file_size", size) => entry.file_size = size.parse().ok()?,
+            ("text_hash", hash) => {
+                entry.text_hash = u64::from_str_radix(hash.trim_start_matches("0x"), 16).ok()?
+            }
+            _ => return None,
+        }
+    }
+
+    Some(entry)
+}
+
+fn parse_dependency(value: &str) -> Option<PackageDependency> {
+    let mut tokens = value.split_whitespace();
+    let id = tokens.next()?.to_string();
+
+    let version = match (tokens.next(), tokens.next()) {
+        (None, _) => None,
+        (Some(">="), Some(version)) => Some(version.parse().ok()?),
+        _ => return None,
+    };
+
+    match tokens.next() {
+        Some(_) => None,
+        None => Some(PackageDependency { id, version }),
+    }
+}
+
+// Version
+
+impl std::str::FromStr for PackageVersion {
+    type Err = ();
+
+    fn from_str(s: &str) -> std::result::Result<Self, ()> {
+        let mut parts = s.split('.').map(|p| p.parse::<u32>().map_err(|_| ()));
+
+        // Missing components are zero, "1.2" is "1.2.0".
+        let version = PackageVersion {
+            major: parts.next().ok_or(())??,
+            minor: parts.next().unwrap_or(Ok(0))?,
+            patch: parts.next().unwrap_or(Ok(0))?,
+        };
+
+        match parts.next() {
+            Some(_) => Err(()),
+            None => Ok(version),
+        }
+    }
+}
+
+impl Display for PackageVersion {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+        write!(f, "{}.{}.{}", self.major, self.minor, self.patch)
+    }
+}
+
+// Manifest
+
+impl PackageManifest {
+    pub fn parse(text: &str) -> error::Result<Self> {
+        let mut id = None;
+        let mut version = None;
+        let mut dependencies = Vec::new();
+        let mut fingerprints = Vec::new();
+        let mut binaries = Vec::new();
+
+        for (i, line) in text.lines().enumerate() {
+            let line = line.trim();
+
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let invalid = || malformed(format!("manifest line {}: invalid entry!", i + 1));
+
+            let (key, value) = match line.split_once('=') {
+                Some((key, value)) => (key.trim(), value.trim()),
+                None => return invalid(),
+            };
+
+            match key {
+                "id" if !value.is_empty() => id = Some(value.to_string()),
+                "version" => match value.parse() {
+                    Ok(v) => version = Some(v),
+                    Err(_) => return invalid(),
+                },
+                "depends" => match parse_dependency(value) {
+                    Some(dependency) => dependencies.push(dependency),
+                    None => return invalid(),
+                },
+                "fingerprint" => match parse_fingerprint(value) {
+                    Some(entry) => fingerprints.push(entry),
+                    None => return invalid(),
+                },
+                _ => match key.strip_prefix("binary.") {
+                    Some(arch) if PACKAGE_ARCHS.contains(&arch) && !value.is_empty() => {
+                        if binaries.iter().any(|(a, _)| a == arch) {
+                            return malformed(format!("manifest: duplicate binary for {}!", arch));
+                        }
+
+                        binaries.push((arch.to_string(), value.to_string()));
+                    }
+                    _ => return invalid(),
+                },
+            }
+        }
+
+        let id = match id {
+            Some(id) => id,
+            None => return malformed(String::from("manifest: missing id!")),
+        };
+
+        let version = match version {
+            Some(version) => version,
+            None => return malformed(String::from("manifest: missing version!")),
+        };
+
+        if binaries.is_empty() {
+            return malformed(String::from("manifest: no binary!"));
+        }
+
+        Ok(PackageManifest {
+            id,
+            version,
+            dependencies,
+            fingerprints,
+            binaries,
+        })
+    }
+}
+
+// Package
+
+impl Package {
+    pub fn entry(&self, name: &str) -> Option<&PackageEntry> {
+        self.entries.iter().find(|e| e.name == name)
+    }
+
+    pub fn binary(&self, arch: &str) -> Option<&PackageEntry> {
+        let (_, name) = self.manifest.binaries.iter().find(|(a, _)| a == arch)?;
+        self.entry(name)
+    }
+
+    pub fn assets(&self) -> impl Iterator<Item = &PackageEntry> {
+        // Binaries and the manifest are the package's own files.
+        self.entries.iter().filter(move |e| {
+            e.name != MANIFEST_ENTRY && !self.manifest.binaries.iter().any(|(_, b)| *b == e.name)
+        })
+    }
+
+    pub fn data<'a>(&self, buffer: &'a [u8], entry: &PackageEntry) -> &'a [u8] {
+        &buffer[entry.offset..entry.offset + entry.size]
+    }
+}
+
+pub fn parse_package(buffer: &[u8]) -> error::Result<Package> {
+    let header = match read_struct::<PkgHeader>(buffer, 0) {
+        Some(header) if header.magic == PACKAGE_MAGIC => header,
+        _ => return malformed(String::from("Not a package!")),
+    };
+
+    if header.version != PACKAGE_VERSION {
+        return malformed(format!(
+            "Package uses unsupported format version {}!",
+            header.version
+        ));
+    }
+
+    let mut entries: Vec<PackageEntry> = Vec::new();
+
+    for i in 0..header.count {
+        let at = usize::try_from(i)
+            .ok()
+            .and_then(|i| i.checked_mul(size_of::<PkgEntry>()))
+            .and_then(|offset| offset.checked_add(size_of::<PkgHeader>()));
+
+        let raw = match at.and_then(|at| read_struct::<PkgEntry>(buffer, at)) {
+            Some(raw) => raw,
+            None => return malformed(format!("Package entry {}: truncated entry!", i)),
+        };
+
+        let name = read_range(buffer, raw.name_offset, raw.name_size)
+            .and_then(|(offset, size)| std::str::from_utf8(&buffer[offset..offset + size]).ok());
+
+        let name = match name {
+            Some(name) if !name.is_empty() => name.to_string(),
+            _ => return malformed(format!("Package entry {}: invalid name!", i)),
+        };
+
+        let (offset, size) = match read_range(buffer, raw.data_offset, raw.data_size) {
+            Some(range) => range,
+            None => return malformed(format!("{}: data is out of bounds!", name)),
+        };
+
+        if crate::hash_text(&buffer[offset..offset + size]) != raw.hash {
+            return malformed(format!("{}: data is corrupted!", name));
+        }
+
+        if entries.iter().any(|e| e.name == name) {
+            return malformed(format!("{}: duplicate entry!", name));
+        }
+
+        entries.push(PackageEntry { name, offset, size });
+    }
+
+    let manifest = match entries.iter().find(|e| e.name == MANIFEST_ENTRY) {
+        Some(e) => match std::str::from_utf8(&buffer[e.offset..e.offset + e.size]) {
+            Ok(text) => PackageManifest::parse(text)?,
+            Err(_) => return malformed(String::from("manifest: invalid text!")),
+        },
+        None => return malformed(String::from("Package has no manifest!")),
+    };
+
+    for (arch, name) in manifest.binaries.iter() {
+        if !entries.iter().any(|e| e.name == *name) {
+            return malformed(format!(
+                "manifest: binary {} for {} is missing!",
+                name, arch
+            ));
+        }
+    }
+
+    Ok(Package { manifest, entries })
+}
+
+pub fn write_package(files: &[(String, Vec<u8>)]) -> Vec<u8> {
+    let table = size_of::<PkgHeader>() + files.len() * size_of::<PkgEntry>();
+    let mut data = Vec::new();
+    let mut entries = Vec::new();
+
+    for (name, bytes) in files {
+        let name_offset = table + data.len();
+        data.extend_from_slice(name.as_bytes());
+
+        let data_offset = table + data.len();
+        data.extend_from_slice(bytes);
+
+        entries.push(PkgEntry {
+            name_offset: name_offset as u64,
+            name_size: name.len() as u64,
+            data_offset: data_offset as u64,
+            data_size: bytes.len() as u64,
+            hash: crate::hash_text(bytes),
+        });
+    }
+
+    let header = PkgHeader {
+        magic: PACKAGE_MAGIC,
+        version: PACKAGE_VERSION,
+        count: files.len() as u64,
+    };
+
+    let mut buffer = Vec::with_capacity(table + data.len());
+
+    buffer.extend_from_slice(&header.magic.to_le_bytes());
+    buffer.extend_from_slice(&header.version.to_le_bytes());
+    buffer.extend_from_slice(&header.count.to_le_bytes());
+
+    for entry in entries {
+        for field in [
+            entry.name_offset,
+            entry.name_size,
+            entry.data_offset,
+            entry.data_size,
+            entry.hash,
+        ] {
+            buffer.extend_from_slice(&field.to_le_bytes());
+        }
+    }
+
+    buffer.extend_from_slice(&data);
+    buffer
+}
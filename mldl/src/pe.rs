@@ -3,179 +3,324 @@
 use goblin::pe::*;
 use goblin::*;
 
+use crate::section::{read_struct, SectionReader};
 use crate::types::*;
 
-use std::ffi::CStr;
+use std::mem::size_of;
+use std::ops::Range;
 
 // Globals
 
 const DYN_SECTION: &'static str = ".mldyn";
 const HOOK_SECTION: &'static str = ".mlhook";
+const FINGERPRINT_SECTION: &str = ".mlfp";
+const TEXT_SECTION: &str = ".text";
 
 // Helpers
 
-unsafe fn find_raw_addr(pe: &PE, vaddr: u64) -> Option<u64> {
+fn find_raw_range(pe: &PE, vaddr: u64) -> Option<Range<usize>> {
     let base = pe.image_base as u64;
     let sections = &pe.sections;
 
     for s in sections {
         let raw = s.pointer_to_raw_data as u64;
-        let va = base + s.virtual_address as u64;
-        let va_end = va + s.size_of_raw_data as u64;
+        let size = s.size_of_raw_data as u64;
+        let va = base.checked_add(s.virtual_address as u64)?;
 
-        if vaddr > va && vaddr < va_end {
-            return Some(vaddr - va + raw);
+        if vaddr >= va && vaddr - va < size {
+            let start = usize::try_from((vaddr - va).checked_add(raw)?).ok()?;
+            let end = usize::try_from(raw.checked_add(size)?).ok()?;
+            return Some(start..end);
         }
     }
 
     None
 }
 
-unsafe fn read_raw_string<'a>(pe: &PE, base: u64, vaddr: u64) -> Option<&'a str> {
-    match CStr::from_ptr((base + find_raw_addr(pe, vaddr)?) as _).to_str() {
-        Ok(s) => Some(s),
-        Err(_) => None,
+fn read_raw_string<'a>(pe: &PE, buffer: &'a [u8], vaddr: u64) -> Option<&'a str> {
+    crate::section::read_cstr(buffer.get(find_raw_range(pe, vaddr)?)?)
+}
+
+fn read_raw_bytes(pe: &PE, buffer: &[u8], vaddr: u64, size: usize) -> Option<Vec<u8>> {
+    buffer
+        .get(find_raw_range(pe, vaddr)?)?
+        .get(..size)
+        .map(<[u8]>::to_vec)
+}
+
+fn read_expected<E>(pe: &PE, buffer: &[u8], ext: &E) -> Option<(Vec<u8>, Vec<u8>)>
+where
+    E: SecExpect,
+{
+    let size = ext.size() as usize;
+    let bytes = read_raw_bytes(pe, buffer, ext.bytes(), size)?;
+
+    let mask = match ext.mask() {
+        0 => Vec::new(),
+        va => read_raw_bytes(pe, buffer, va, size)?,
+    };
+
+    Some((bytes, mask))
+}
+
+fn open_section<'a>(
+    pe: &PE,
+    buffer: &'a [u8],
+    name: &'a str,
+) -> error::Result<Option<SectionReader<'a>>> {
+    for sec in &pe.sections {
+        if sec.name()? == name {
+            return SectionReader::new(
+                name,
+                buffer,
+                sec.pointer_to_raw_data as _,
+                sec.size_of_raw_data as _,
+                pe.image_base as u64 + sec.virtual_address as u64,
+            )
+            .map(Some);
+        }
     }
+
+    Ok(None)
 }
 
 // Dumper
 
-unsafe fn dump_dyn<T>(
+fn dump_dyn<T>(
     mut lib_offset: usize,
-    base: u64,
+    buffer: &[u8],
     pe: &PE,
     dyn_table: &mut DynamicTable,
 ) -> error::Result<()>
 where
     T: SecDynamic,
 {
-    let mut p: *const T = std::ptr::null_mut();
-    let mut size = 0u64;
-
     if lib_offset != 0 {
         lib_offset -= pe.image_base;
     }
 
-    for sec in &pe.sections {
-        if sec.name()? == DYN_SECTION {
-            p = (base + sec.pointer_to_raw_data as u64) as _;
-            size = sec.size_of_raw_data as _;
+    let mut reader = match open_section(pe, buffer, DYN_SECTION)? {
+        Some(r) => r,
+        None => return Ok(()),
+    };
+
+    while let Some((_, entry)) = reader.next::<T>()? {
+        if reader.terminates(entry.address() == 0)? {
             break;
         }
-    }
-
-    if let Some(mut entry) = p.as_ref() {
-        let mut offset = 0u64;
-
-        while offset < size {
-            if entry.address() == 0 {
-                break;
-            }
 
-            let sym = read_raw_string(pe, base, entry.sym());
+        let sym = match read_raw_string(pe, buffer, entry.sym()) {
+            Some(s) => s.to_string(),
+            None => return Err(reader.error("could not read the symbol name")),
+        };
 
-            if sym.is_none() {
-                return Err(error::Error::Malformed(String::from(
-                    "Could not read binary metadata!",
-                )));
-            }
-
-            let sym = sym.unwrap().to_string();
-            let record = match read_raw_string(pe, base, entry.record()) {
+        let record = match entry.record() {
+            0 => String::from(""),
+            va => match read_raw_string(pe, buffer, va) {
                 Some(s) => s.to_string(),
-                None => String::from(""),
-            };
-
-            dyn_table.push(DynamicEntry {
-                address: lib_offset + (entry.address() as usize),
-                sym,
-                record,
-            });
-
-            offset += std::mem::size_of::<T>() as u64;
-            p = p.offset(1);
-            entry = &*p;
-        }
+                None => return Err(reader.error("could not read the record name")),
+            },
+        };
+
+        dyn_table.push(DynamicEntry {
+            address: lib_offset + (entry.address() as usize),
+            sym,
+            record,
+        });
     }
 
     Ok(())
 }
 
-unsafe fn dump_hooks<T>(
+#[allow(deprecated)]
+fn dump_hooks<T, E, R, N>(
     mut lib_offset: usize,
-    base: u64,
+    buffer: &[u8],
     pe: &PE,
     hook_table: &mut HookTable,
 ) -> error::Result<()>
 where
     T: SecHook,
+    E: SecExpect,
+    R: SecTarget,
+    N: SecRecord,
 {
-    let mut p: *const T = std::ptr::null_mut();
-    let mut size = 0u64;
-
     if lib_offset != 0 {
         lib_offset -= pe.image_base;
     }
 
-    for sec in &pe.sections {
-        if sec.name()? == HOOK_SECTION {
-            p = (base + sec.pointer_to_raw_data as u64) as _;
-            size = sec.size_of_raw_data as _;
+    let mut reader = match open_section(pe, buffer, HOOK_SECTION)? {
+        Some(r) => r,
+        None => return Ok(()),
+    };
+
+    while let Some((_, entry)) = reader.next::<T>()? {
+        if reader.terminates(entry.target() == 0)? {
             break;
         }
-    }
-
-    if let Some(mut entry) = p.as_ref() {
-        let mut offset = 0u64;
 
-        while offset < size {
-            if entry.target() == 0 {
-                break;
+        let flags = entry.flags();
+
+        let target = match (flags >> TARGET_SHIFT) & TARGET_MASK {
+            TARGET_ADDRESS if (flags & FLAG_DYNAMIC) != 0 => {
+                match read_raw_string(pe, buffer, entry.target()) {
+                    Some(s) => HookTarget::Export {
+                        module: String::new(),
+                        symbol: s.to_string(),
+                    },
+                    None => return Err(reader.error("could not read the target symbol")),
+                }
             }
+            TARGET_ADDRESS => HookTarget::Address(lib_offset + (entry.target() as usize)),
+            kind if kind > TARGET_RVA => return Err(reader.error("unknown target kind")),
+            kind => {
+                let record = match read_raw_bytes(pe, buffer, entry.target(), size_of::<R>())
+                    .and_then(|b| read_struct::<R>(&b, 0))
+                {
+                    Some(r) => r,
+                    None => return Err(reader.error("could not read the target record")),
+                };
+
+                crate::read_target(kind, record.module(), record.name(), record.value(), |va| {
+                    read_raw_string(pe, buffer, va).map(str::to_string)
+                })
+                .map_err(|e| reader.error(e))?
+            }
+        };
+
+        // The expected bytes record follows its entry.
+        let (expected, expected_mask) = match (flags & FLAG_EXPECT) != 0 {
+            true => {
+                let (_, ext) = reader.extension::<E>()?;
 
-            let flags = entry.flags();
-            let is_dynamic = (flags & FLAG_DYNAMIC) != 0;
-
-            if (flags & FLAG_DISPATCHER) != 0 {
-                hook_table.dispatchers.push(HookEntry {
-                    target: lib_offset + (entry.target() as usize),
-
-                    callback: lib_offset + (entry.callback() as usize),
-                    dispatcher: true,
-                    dynamic: is_dynamic,
-                    locking: false,
-                    preload: false,
-                    optional: false,
-                    priority: false,
-                });
-            } else if (flags & FLAG_LOCKING) != 0 {
-                hook_table.locking_hooks.push(HookEntry {
-                    target: lib_offset + (entry.target() as usize),
-                    callback: lib_offset + (entry.callback() as usize),
-                    dispatcher: false,
-                    dynamic: is_dynamic,
-                    locking: true,
-                    preload: (flags & FLAG_PRELOAD) != 0,
-                    optional: (flags & FLAG_OPTIONAL) != 0,
-                    priority: (flags & FLAG_PRIORITY) != 0,
-                });
-            } else {
-                hook_table.hooks.push(HookEntry {
-                    target: lib_offset + (entry.target() as usize),
-                    callback: lib_offset + (entry.callback() as usize),
-                    dispatcher: false,
-                    dynamic: is_dynamic,
-                    locking: false,
-                    preload: (flags & FLAG_PRELOAD) != 0,
-                    optional: (flags & FLAG_OPTIONAL) != 0,
-                    priority: (flags & FLAG_PRIORITY) != 0,
-                });
+                match read_expected(pe, buffer, &ext) {
+                    Some(e) => e,
+                    None => return Err(reader.error("could not read the expected bytes")),
+                }
+            }
+            false => (Vec::new(), Vec::new()),
+        };
+
+        // Record names follow the expected bytes.
+        let record = match (flags & FLAG_RECORD) != 0 {
+            true => {
+                let (_, ext) = reader.extension::<N>()?;
+
+                match ext.name() {
+                    0 => String::new(),
+                    va => match read_raw_string(pe, buffer, va) {
+                        Some(s) => s.to_string(),
+                        None => return Err(reader.error("could not read the record name")),
+                    },
+                }
             }
+            false => String::new(),
+        };
+
+        let dynamic = target.is_dynamic();
+
+        if (flags & FLAG_DISPATCHER) != 0 {
+            hook_table.dispatchers.push(HookEntry {
+                target,
+                callback: lib_offset + (entry.callback() as usize),
+                dispatcher: true,
+                dynamic,
+                locking: false,
+                preload: false,
+                optional: false,
+                priority: false,
+                guard: false,
+                target_conv: CallConv::Cdecl,
+                hook_conv: CallConv::Cdecl,
+                args: 0,
+                expected,
+                expected_mask,
+                record,
+            });
+        } else if (flags & FLAG_LOCKING) != 0 {
+            hook_table.locking_hooks.push(HookEntry {
+                target,
+                callback: lib_offset + (entry.callback() as usize),
+                dispatcher: false,
+                dynamic,
+                locking: true,
+                preload: (flags & FLAG_PRELOAD) != 0,
+                optional: (flags & FLAG_OPTIONAL) != 0,
+                priority: (flags & FLAG_PRIORITY) != 0,
+                guard: (flags & FLAG_GUARD) != 0,
+                target_conv: CallConv::from_flags(flags, CONV_TARGET_SHIFT),
+                hook_conv: CallConv::from_flags(flags, CONV_HOOK_SHIFT),
+                args: ((flags >> ARGS_SHIFT) & ARGS_MASK) as usize,
+                expected,
+                expected_mask,
+                record,
+            });
+        } else {
+            hook_table.hooks.push(HookEntry {
+                target,
+                callback: lib_offset + (entry.callback() as usize),
+                dispatcher: false,
+                dynamic,
+                locking: false,
+                preload: (flags & FLAG_PRELOAD) != 0,
+                optional: (flags & FLAG_OPTIONAL) != 0,
+                priority: (flags & FLAG_PRIORITY) != 0,
+                guard: (flags & FLAG_GUARD) != 0,
+                target_conv: CallConv::from_flags(flags, CONV_TARGET_SHIFT),
+                hook_conv: CallConv::from_flags(flags, CONV_HOOK_SHIFT),
+                args: ((flags >> ARGS_SHIFT) & ARGS_MASK) as usize,
+                expected,
+                expected_mask,
+                record,
+            });
+        }
+    }
+
+    Ok(())
+}
 
-            offset += std::mem::size_of::<T>() as u64;
-            p = p.offset(1);
-            entry = &*p;
+fn dump_fingerprints<T>(
+    buffer: &[u8],
+    pe: &PE,
+    fp_table: &mut FingerprintTable,
+) -> error::Result<()>
+where
+    T: SecFingerprint,
+{
+    let mut reader = match open_section(pe, buffer, FINGERPRINT_SECTION)? {
+        Some(r) => r,
+        None => return Ok(()),
+    };
+
+    while let Some((_, entry)) = reader.next::<T>()? {
+        if reader
+            .terminates(entry.build_id() == 0 && entry.file_size() == 0 && entry.text_hash() == 0)?
+        {
+            break;
         }
+
+        let module = match entry.module() {
+            0 => String::new(),
+            va => match read_raw_string(pe, buffer, va) {
+                Some(s) => s.to_string(),
+                None => return Err(reader.error("could not read the module name")),
+            },
+        };
+
+        let build_id = match entry.build_id() {
+            0 => Vec::new(),
+            va => match read_raw_string(pe, buffer, va).and_then(crate::parse_build_id) {
+                Some(id) => id,
+                None => return Err(reader.error("invalid build-id")),
+            },
+        };
+
+        fp_table.push(FingerprintEntry {
+            module,
+            build_id,
+            file_size: entry.file_size(),
+            text_hash: entry.text_hash(),
+        });
     }
 
     Ok(())
@@ -183,21 +328,63 @@ where
 
 // PE
 
-pub fn dump_pe(offset: usize, base: u64, pe: &PE) -> error::Result<(DynamicTable, HookTable)> {
-    let mut dyn_table = DynamicTable::new();
-    let mut hook_table = HookTable::default();
+pub fn dump_pe(offset: usize, buffer: &[u8], pe: &PE) -> error::Result<MLMetadata> {
+    let mut metadata = MLMetadata::default();
 
     if pe.is_64 {
-        unsafe {
-            dump_dyn::<SecDynamic64>(offset, base, pe, &mut dyn_table)?;
-            dump_hooks::<SecHook64>(offset, base, pe, &mut hook_table)?;
-        }
+        dump_dyn::<SecDynamic64>(offset, buffer, pe, &mut metadata.dynamic)?;
+        dump_hooks::<SecHook64, SecExpect64, SecTarget64, SecRecord64>(
+            offset,
+            buffer,
+            pe,
+            &mut metadata.hooks,
+        )?;
+        dump_fingerprints::<SecFingerprint64>(buffer, pe, &mut metadata.fingerprints)?;
     } else {
-        unsafe {
-            dump_dyn::<SecDynamic32>(offset, base, pe, &mut dyn_table)?;
-            dump_hooks::<SecHook32>(offset, base, pe, &mut hook_table)?;
+        dump_dyn::<SecDynamic32>(offset, buffer, pe, &mut metadata.dynamic)?;
+        dump_hooks::<SecHook32, SecExpect32, SecTarget32, SecRecord32>(
+            offset,
+            buffer,
+            pe,
+            &mut metadata.hooks,
+        )?;
+        dump_fingerprints::<SecFingerprint32>(buffer, pe, &mut metadata.fingerprints)?;
+    }
+
+    Ok(metadata)
+}
+
+pub fn fingerprint_pe(buffer: &[u8], pe: &PE) -> error::Result<Fingerprint> {
+    let mut fingerprint = Fingerprint {
+        file_size: buffer.len() as u64,
+        ..Default::default()
+    };
+
+    // PE images carry no build-id, the CodeView GUID and age identify the build.
+    if let Some(info) = pe
+        .debug_data
+        .as_ref()
+        .and_then(|d| d.codeview_pdb70_debug_info.as_ref())
+    {
+        fingerprint.build_id = info.signature.to_vec();
+        fingerprint
+            .build_id
+            .extend_from_slice(&info.age.to_le_bytes());
+    }
+
+    for sec in &pe.sections {
+        if sec.name()? == TEXT_SECTION {
+            let start = sec.pointer_to_raw_data as usize;
+            let end = start.checked_add(sec.size_of_raw_data as usize);
+
+            match end.and_then(|end| buffer.get(start..end)) {
+                Some(text) => fingerprint.text_hash = crate::hash_text(text),
+                None => return Err(error::Error::Malformed(String::from("Truncated .text!"))),
+            }
+
+            break;
         }
     }
 
-    Ok((dyn_table, hook_table))
+    Ok(fingerprint)
 }
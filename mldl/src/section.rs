@@ -0,0 +1,127 @@
+// Includes
+
+use goblin::*;
+
+use crate::types::*;
+
+use std::mem::size_of;
+
+// Types
+
+pub(crate) struct SectionReader<'a> {
+    name: &'a str,
+    data: &'a [u8],
+    vaddr: u64,
+    offset: usize,
+    remaining: Option<u64>,
+    entries: usize,
+}
+
+// Helpers
+
+pub(crate) fn read_cstr(data: &[u8]) -> Option<&str> {
+    let end = data.iter().position(|&b| b == 0)?;
+    std::str::from_utf8(&data[..end]).ok()
+}
+
+pub(crate) fn read_struct<T: Copy>(data: &[u8], offset: usize) -> Option<T> {
+    let bytes = data.get(offset..offset.checked_add(size_of::<T>())?)?;
+
+    // Sections are only byte aligned inside the file buffer.
+    Some(unsafe { std::ptr::read_unaligned(bytes.as_ptr() as *const T) })
+}
+
+// Reader
+
+impl<'a> SectionReader<'a> {
+    pub(crate) fn new(
+        name: &'a str,
+        buffer: &'a [u8],
+        start: u64,
+        size: u64,
+        vaddr: u64,
+    ) -> error::Result<Self> {
+        let data = usize::try_from(start)
+            .ok()
+            .zip(usize::try_from(size).ok())
+            .and_then(|(start, size)| buffer.get(start..start.checked_add(size)?))
+            .ok_or_else(|| error::Error::Malformed(format!("{} is out of bounds!", name)))?;
+
+        let mut reader = SectionReader {
+            name,
+            data,
+            vaddr,
+            offset: 0,
+            remaining: None,
+            entries: 0,
+        };
+
+        // Headerless sections are legacy, they end at the first null entry.
+        if let Some(header) = read_struct::<SecHeader>(data, 0) {
+            if header.magic == SECTION_MAGIC {
+                if header.version != SECTION_VERSION {
+                    return Err(error::Error::Malformed(format!(
+                        "{} uses unsupported format version {}!",
+                        name, header.version
+                    )));
+                }
+
+                reader.offset = size_of::<SecHeader>();
+                reader.remaining = Some(header.count);
+            }
+        }
+
+        Ok(reader)
+    }
+
+    pub(crate) fn next<T: Copy>(&mut self) -> error::Result<Option<(u64, T)>> {
+        if self.remaining == Some(0) {
+            return Ok(None);
+        }
+
+        match read_struct::<T>(self.data, self.offset) {
+            Some(entry) => {
+                let at = self.vaddr + self.offset as u64;
+
+                self.offset += size_of::<T>();
+                self.entries += 1;
+                self.remaining = self.remaining.map(|n| n - 1);
+
+                Ok(Some((at, entry)))
+            }
+            None if self.remaining.is_none() => Ok(None),
+            None => Err(error::Error::Malformed(format!(
+                "{} entry {}: truncated entry!",
+                self.name, self.entries
+            ))),
+        }
+    }
+
+    pub(crate) fn extension<E: Copy>(&mut self) -> error::Result<(u64, E)> {
+        match read_struct::<E>(self.data, self.offset) {
+            Some(record) => {
+                let at = self.vaddr + self.offset as u64;
+                self.offset += size_of::<E>();
+                Ok((at, record))
+            }
+            None => Err(self.error("truncated extension record")),
+        }
+    }
+
+    pub(crate) fn terminates(&self, null: bool) -> error::Result<bool> {
+        match (self.remaining, null) {
+            (Some(_), true) => Err(self.error("unexpected null entry")),
+            (Some(_), false) => Ok(false),
+            (None, null) => Ok(null),
+        }
+    }
+
+    pub(crate) fn error(&self, what: &str) -> error::Error {
+        error::Error::Malformed(format!(
+            "{} entry {}: {}!",
+            self.name,
+            self.entries.saturating_sub(1),
+            what
+        ))
+    }
+}
@@ -10,6 +10,33 @@ pub(crate) const FLAG_LOCKING: u64 = 0x04;
 pub(crate) const FLAG_PRELOAD: u64 = 0x08;
 pub(crate) const FLAG_OPTIONAL: u64 = 0x10;
 pub(crate) const FLAG_PRIORITY: u64 = 0x20;
+pub(crate) const FLAG_GUARD: u64 = 0x40;
+pub(crate) const FLAG_EXPECT: u64 = 0x80;
+pub(crate) const FLAG_RECORD: u64 = 0x80000;
+
+pub(crate) const CONV_TARGET_SHIFT: u64 = 8;
+pub(crate) const CONV_HOOK_SHIFT: u64 = 10;
+pub(crate) const CONV_MASK: u64 = 0x03;
+pub(crate) const ARGS_SHIFT: u64 = 12;
+pub(crate) const ARGS_MASK: u64 = 0x0F;
+pub(crate) const TARGET_SHIFT: u64 = 16;
+pub(crate) const TARGET_MASK: u64 = 0x07;
+
+pub(crate) const TARGET_ADDRESS: u64 = 0;
+pub(crate) const TARGET_EXPORT: u64 = 1;
+pub(crate) const TARGET_SYMBOL: u64 = 2;
+pub(crate) const TARGET_PATTERN: u64 = 3;
+pub(crate) const TARGET_RVA: u64 = 4;
+
+pub(crate) const SECTION_MAGIC: u32 = u32::from_le_bytes(*b"MLMD");
+pub(crate) const SECTION_VERSION: u32 = 1;
+
+pub(crate) const ML_NOTE_NAME: &[u8] = b"MLRT\0";
+pub(crate) const NT_ML_SECTIONS: u32 = 1;
+
+pub(crate) const LOCATOR_DYN: u32 = 1;
+pub(crate) const LOCATOR_HOOK: u32 = 2;
+pub(crate) const LOCATOR_FINGERPRINT: u32 = 3;
 
 // Types
 
@@ -18,21 +45,83 @@ pub enum ModuleKind {
     Binary,
 }
 
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum CallConv {
+    Cdecl,
+    Stdcall,
+    Thiscall,
+    Fastcall,
+}
+
 pub struct DynamicEntry {
     pub address: usize,
     pub sym: String,
     pub record: String,
 }
 
+// An empty module stands for the game executable.
+#[derive(Clone, PartialEq, Debug)]
+pub enum HookTarget {
+    Address(usize),
+    Export {
+        module: String,
+        symbol: String,
+    },
+    Symbol {
+        module: String,
+        symbol: String,
+    },
+    Pattern {
+        module: String,
+        pattern: Vec<Option<u8>>,
+        offset: i64,
+    },
+    Rva {
+        module: String,
+        rva: usize,
+    },
+}
+
 pub struct HookEntry {
-    pub target: usize,
+    pub target: HookTarget,
     pub callback: usize,
     pub dispatcher: bool,
+    #[deprecated(note = "dynamic targets are `HookTarget::Export` with an empty module")]
     pub dynamic: bool,
     pub locking: bool,
     pub preload: bool,
     pub optional: bool,
     pub priority: bool,
+    pub guard: bool,
+    pub target_conv: CallConv,
+    pub hook_conv: CallConv,
+    pub args: usize,
+    pub expected: Vec<u8>,
+    pub expected_mask: Vec<u8>,
+    pub record: String,
+}
+
+// A segment as the dynamic loader mapped it, with the size and access it really has.
+#[derive(Clone, Copy, Debug)]
+pub struct ImageSegment {
+    pub address: usize,
+    pub size: usize,
+    pub offset: usize,
+    pub readable: bool,
+}
+
+pub struct FingerprintEntry {
+    pub module: String,
+    pub build_id: Vec<u8>,
+    pub file_size: u64,
+    pub text_hash: u64,
+}
+
+#[derive(Clone, Default, PartialEq, Debug)]
+pub struct Fingerprint {
+    pub build_id: Vec<u8>,
+    pub file_size: u64,
+    pub text_hash: u64,
 }
 
 pub type DynamicTable = Vec<DynamicEntry>;
@@ -44,9 +133,35 @@ pub struct HookTable {
     pub locking_hooks: Vec<HookEntry>,
 }
 
+pub type FingerprintTable = Vec<FingerprintEntry>;
+
+#[derive(Default)]
+pub struct MLMetadata {
+    pub dynamic: DynamicTable,
+    pub hooks: HookTable,
+    pub fingerprints: FingerprintTable,
+}
+
 // Sections
 
 #[repr(C)]
+#[derive(Clone, Copy)]
+pub(crate) struct SecHeader {
+    pub magic: u32,
+    pub version: u32,
+    pub count: u64,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub(crate) struct SecLocator {
+    pub kind: u32,
+    pub offset: i32,
+    pub size: u32,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
 pub(crate) struct SecDynamic32 {
     pub addr: u32,
     pub sym: u32,
@@ -54,19 +169,21 @@ pub(crate) struct SecDynamic32 {
 }
 
 #[repr(C)]
+#[derive(Clone, Copy)]
 pub(crate) struct SecDynamic64 {
     pub addr: u64,
     pub sym: u64,
     pub record: u64,
 }
 
-pub(crate) trait SecDynamic {
+pub(crate) trait SecDynamic: Copy {
     fn address(&self) -> u64;
     fn sym(&self) -> u64;
     fn record(&self) -> u64;
 }
 
 #[repr(C)]
+#[derive(Clone, Copy)]
 pub(crate) struct SecHook32 {
     pub target: u32,
     pub callback: u32,
@@ -74,20 +191,121 @@ pub(crate) struct SecHook32 {
 }
 
 #[repr(C)]
+#[derive(Clone, Copy)]
 pub(crate) struct SecHook64 {
     pub target: u64,
     pub callback: u64,
     pub flags: u64,
 }
 
-pub(crate) trait SecHook {
+pub(crate) trait SecHook: Copy {
     fn target(&self) -> u64;
     fn callback(&self) -> u64;
     fn flags(&self) -> u64;
 }
 
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub(crate) struct SecExpect32 {
+    pub bytes: u32,
+    pub mask: u32,
+    pub size: u64,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub(crate) struct SecExpect64 {
+    pub bytes: u64,
+    pub mask: u64,
+    pub size: u64,
+}
+
+pub(crate) trait SecExpect: Copy {
+    fn bytes(&self) -> u64;
+    fn mask(&self) -> u64;
+    fn size(&self) -> u64;
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub(crate) struct SecRecord32 {
+    pub name: u32,
+    pub reserved: u32,
+    pub reserved2: u64,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub(crate) struct SecRecord64 {
+    pub name: u64,
+    pub reserved: u64,
+    pub reserved2: u64,
+}
+
+pub(crate) trait SecRecord: Copy {
+    fn name(&self) -> u64;
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub(crate) struct SecTarget32 {
+    pub module: u32,
+    pub name: u32,
+    pub value: u64,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub(crate) struct SecTarget64 {
+    pub module: u64,
+    pub name: u64,
+    pub value: u64,
+}
+
+pub(crate) trait SecTarget: Copy {
+    fn module(&self) -> u64;
+    fn name(&self) -> u64;
+    fn value(&self) -> u64;
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub(crate) struct SecFingerprint32 {
+    pub module: u32,
+    pub build_id: u32,
+    pub file_size: u64,
+    pub text_hash: u64,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub(crate) struct SecFingerprint64 {
+    pub module: u64,
+    pub build_id: u64,
+    pub file_size: u64,
+    pub text_hash: u64,
+}
+
+pub(crate) trait SecFingerprint: Copy {
+    fn module(&self) -> u64;
+    fn build_id(&self) -> u64;
+    fn file_size(&self) -> u64;
+    fn text_hash(&self) -> u64;
+}
+
 // Impl
 
+impl CallConv {
+    pub(crate) fn from_flags(flags: u64, shift: u64) -> Self {
+        match (flags >> shift) & CONV_MASK {
+            1 => CallConv::Stdcall,
+            2 => CallConv::Thiscall,
+            3 => CallConv::Fastcall,
+            _ => CallConv::Cdecl,
+        }
+    }
+}
+
 impl SecDynamic for SecDynamic32 {
     fn address(&self) -> u64 {
         self.addr as _
@@ -144,6 +362,147 @@ impl SecHook for SecHook64 {
     }
 }
 
+impl SecExpect for SecExpect32 {
+    fn bytes(&self) -> u64 {
+        self.bytes as _
+    }
+
+    fn mask(&self) -> u64 {
+        self.mask as _
+    }
+
+    fn size(&self) -> u64 {
+        self.size
+    }
+}
+
+impl SecExpect for SecExpect64 {
+    fn bytes(&self) -> u64 {
+        self.bytes
+    }
+
+    fn mask(&self) -> u64 {
+        self.mask
+    }
+
+    fn size(&self) -> u64 {
+        self.size
+    }
+}
+
+impl SecRecord for SecRecord32 {
+    fn name(&self) -> u64 {
+        self.name as _
+    }
+}
+
+impl SecRecord for SecRecord64 {
+    fn name(&self) -> u64 {
+        self.name
+    }
+}
+
+impl SecTarget for SecTarget32 {
+    fn module(&self) -> u64 {
+        self.module as _
+    }
+
+    fn name(&self) -> u64 {
+        self.name as _
+    }
+
+    fn value(&self) -> u64 {
+        self.value
+    }
+}
+
+impl SecTarget for SecTarget64 {
+    fn module(&self) -> u64 {
+        self.module
+    }
+
+    fn name(&self) -> u64 {
+        self.name
+    }
+
+    fn value(&self) -> u64 {
+        self.value
+    }
+}
+
+impl SecFingerprint for SecFingerprint32 {
+    fn module(&self) -> u64 {
+        self.module as _
+    }
+
+    fn build_id(&self) -> u64 {
+        self.build_id as _
+    }
+
+    fn file_size(&self) -> u64 {
+        self.file_size
+    }
+
+    fn text_hash(&self) -> u64 {
+        self.text_hash
+    }
+}
+
+impl SecFingerprint for SecFingerprint64 {
+    fn module(&self) -> u64 {
+        self.module
+    }
+
+    fn build_id(&self) -> u64 {
+        self.build_id
+    }
+
+    fn file_size(&self) -> u64 {
+        self.file_size
+    }
+
+    fn text_hash(&self) -> u64 {
+        self.text_hash
+    }
+}
+
+impl HookTarget {
+    // Symbols looked up in the game executable, which `HookEntry::dynamic` used to flag.
+    pub(crate) fn is_dynamic(&self) -> bool {
+        matches!(self, HookTarget::Export { module, .. } if module.is_empty())
+    }
+}
+
+impl FingerprintEntry {
+    pub fn check(&self, actual: &Fingerprint) -> std::result::Result<(), String> {
+        let hex = |id: &[u8]| id.iter().map(|b| format!("{:02x}", b)).collect::<String>();
+
+        if !self.build_id.is_empty() && self.build_id != actual.build_id {
+            return Err(format!(
+                "build-id is {}, expected {}",
+                hex(&actual.build_id),
+                hex(&self.build_id)
+            ));
+        }
+
+        if self.file_size != 0 && self.file_size != actual.file_size {
+            return Err(format!(
+                "file size is {}, expected {}",
+                actual.file_size, self.file_size
+            ));
+        }
+
+        if self.text_hash != 0 && self.text_hash != actual.text_hash {
+            return Err(format!(
+                ".text hash is 0x{:016X}, expected 0x{:016X}",
+                actual.text_hash, self.text_hash
+            ));
+        }
+
+        Ok(())
+    }
+}
+
 impl Display for DynamicEntry {
     fn fmt(&self, f: &mut Formatter<'_>) -> Result {
         writeln!(f, "Address: 0x{:X}", self.address)?;
@@ -160,20 +519,112 @@ impl Display for DynamicEntry {
     }
 }
 
+impl Display for HookTarget {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+        let scope = |module: &str| match module.is_empty() {
+            true => String::new(),
+            false => format!("{}!", module),
+        };
+
+        match self {
+            HookTarget::Address(address) => write!(f, "0x{:X}", address),
+            HookTarget::Export { module, symbol } => write!(f, "{}{}", scope(module), symbol),
+            HookTarget::Symbol { module, symbol } => {
+                write!(f, "{}{} (own)", scope(module), symbol)
+            }
+            HookTarget::Pattern {
+                module,
+                pattern,
+                offset,
+            } => {
+                let bytes = pattern
+                    .iter()
+                    .map(|b| match b {
+                        Some(b) => format!("{:02X}", b),
+                        None => String::from("??"),
+                    })
+                    .collect::<Vec<_>>()
+                    .join(" ");
+
+                write!(f, "{}[{}]{:+}", scope(module), bytes, offset)
+            }
+            HookTarget::Rva { module, rva } => write!(f, "{}+0x{:X}", scope(module), rva),
+        }
+    }
+}
+
 impl Display for HookEntry {
+    #[allow(deprecated)]
     fn fmt(&self, f: &mut Formatter<'_>) -> Result {
         let read_opt = |b: bool| match b {
             true => "Yes",
             false => "No",
         };
 
-        writeln!(f, "Target: 0x{:X}", self.target)?;
+        writeln!(f, "Target: {}", self.target)?;
         writeln!(f, "Callback: 0x{:X}", self.callback)?;
         writeln!(f, "Is dispatcher? {}", read_opt(self.dispatcher))?;
         writeln!(f, "Is dynamic? {}", read_opt(self.dynamic))?;
         writeln!(f, "Is locking? {}", read_opt(self.locking))?;
         writeln!(f, "Is preload? {}", read_opt(self.preload))?;
         writeln!(f, "Is optional? {}", read_opt(self.optional))?;
-        writeln!(f, "Is priority? {}", read_opt(self.priority))
+        writeln!(f, "Is priority? {}", read_opt(self.priority))?;
+        writeln!(f, "Is guarded? {}", read_opt(self.guard))?;
+        writeln!(f, "Target convention: {:?}", self.target_conv)?;
+        writeln!(f, "Hook convention: {:?}", self.hook_conv)?;
+        writeln!(f, "Arguments: {}", self.args)?;
+        writeln!(
+            f,
+            "Record: {}",
+            if self.record.is_empty() {
+                "(none)"
+            } else {
+                &self.record
+            }
+        )?;
+        writeln!(
+            f,
+            "Expected bytes: {}",
+            if self.expected.is_empty() {
+                String::from("(any)")
+            } else {
+                self.expected
+                    .iter()
+                    .enumerate()
+                    .map(|(i, b)| match self.expected_mask.get(i) {
+                        Some(0) => String::from("??"),
+                        _ => format!("{:02X}", b),
+                    })
+                    .collect::<Vec<_>>()
+                    .join(" ")
+            }
+        )
+    }
+}
+
+impl Display for FingerprintEntry {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+        let hex = self
+            .build_id
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect::<String>();
+
+        writeln!(
+            f,
+            "Module: {}",
+            if self.module.is_empty() {
+                "(main)"
+            } else {
+                &self.module
+            }
+        )?;
+        writeln!(
+            f,
+            "Build-id: {}",
+            if hex.is_empty() { "(any)" } else { &hex }
+        )?;
+        writeln!(f, "File size: {}", self.file_size)?;
+        writeln!(f, "Text hash: 0x{:016X}", self.text_hash)
     }
 }
@@ -1,33 +1,1415 @@
 // Includes
 
+use super::{Arch, ArchKind};
 use crate::core::*;
 
+// Globals
+
+const ARM_INSN_SIZE: usize = 4;
+const THUMB_INSN_SIZE: usize = 2;
+
+const ARM_NOP: u32 = 0xE320F000;
+const ARM_MOV_R0_R0: u32 = 0xE1A00000;
+const ARM_UDF: u32 = 0xE7F000F0;
+const ARM_LDR_PC_PC_M4: u32 = 0xE51FF004;
+const ARM_ADD_LR_PC_4: u32 = 0xE28FE004;
+const ARM_PUSH: u32 = 0xE52D0004;
+const ARM_POP: u32 = 0xE49D0004;
+
+const THUMB_NOP: u16 = 0xBF00;
+const THUMB_MOV_R8_R8: u16 = 0x46C0;
+const THUMB_UDF: u16 = 0xDE00;
+const THUMB_LDR_PC_PC: (u16, u16) = (0xF8DF, 0xF000);
+const THUMB_PUSH: u16 = 0xB400;
+const THUMB_POP: u16 = 0xBC00;
+
+const COND_AL: u32 = 0xE;
+const REG_SP: u32 = 13;
+const REG_LR: u32 = 14;
+const REG_PC: u32 = 15;
+
+// Types
+
+pub struct A32;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum Kind {
+    B,
+    Bl,
+    Blx,
+    BCond,
+    Cbz,
+    Adr,
+    LdrLiteral,
+    It,
+    Other,
+}
+
+struct Insn {
+    kind: Kind,
+    size: usize,
+    hw1: u32,
+    hw2: u32,
+}
+
+// Helpers
+
+fn is_thumb(address: u64) -> bool {
+    address & 1 != 0
+}
+
+fn align4(address: u64) -> u64 {
+    address & !3
+}
+
+fn read_half(buffer: &[u8], offset: usize) -> u32 {
+    u16::from_le_bytes([buffer[offset], buffer[offset + 1]]) as u32
+}
+
+fn read_word(buffer: &[u8], offset: usize) -> u32 {
+    read_half(buffer, offset) | (read_half(buffer, offset + 2) << 16)
+}
+
+fn push_half(buffer: &mut Vec<u8>, half: u32) {
+    buffer.extend_from_slice(&(half as u16).to_le_bytes());
+}
+
+fn push_word(buffer: &mut Vec<u8>, word: u32) {
+    buffer.extend_from_slice(&word.to_le_bytes());
+}
+
+fn push_thumb32(buffer: &mut Vec<u8>, hw1: u32, hw2: u32) {
+    push_half(buffer, hw1);
+    push_half(buffer, hw2);
+}
+
+fn sign_extend(value: u32, bits: u32) -> i64 {
+    let shift = 64 - bits;
+    ((value as i64) << shift) >> shift
+}
+
+fn fits(offset: i64, bits: u32, scale: u32) -> bool {
+    let limit = 1i64 << (bits - 1);
+    offset % (scale as i64) == 0 && (-limit..limit).contains(&(offset / scale as i64))
+}
+
+fn invert_cond(cond: u32) -> u32 {
+    cond ^ 1
+}
+
+fn arm_expand_imm(imm12: u32) -> u32 {
+    (imm12 & 0xFF).rotate_right((imm12 >> 8) * 2)
+}
+
+// ARM
+
+fn decode_arm(insn: u32) -> Kind {
+    if insn & 0xFE000000 == 0xFA000000 {
+        Kind::Blx
+    } else if insn >> 28 == 0xF {
+        Kind::Other
+    } else if insn & 0x0F000000 == 0x0A000000 {
+        Kind::B
+    } else if insn & 0x0F000000 == 0x0B000000 {
+        Kind::Bl
+    } else if insn & 0x0F3F0000 == 0x051F0000 {
+        Kind::LdrLiteral
+    } else if insn & 0x0FFF0000 == 0x028F0000 || insn & 0x0FFF0000 == 0x024F0000 {
+        Kind::Adr
+    } else {
+        Kind::Other
+    }
+}
+
+fn arm_target(insn: u32, pc: u64) -> Option<u64> {
+    let base = pc.wrapping_add(8);
+
+    let target = match decode_arm(insn) {
+        Kind::B | Kind::Bl => base.wrapping_add((sign_extend(insn & 0xFFFFFF, 24) << 2) as u64),
+        Kind::Blx => {
+            let offset = (sign_extend(insn & 0xFFFFFF, 24) << 2) | (((insn >> 24) & 1) << 1) as i64;
+            base.wrapping_add(offset as u64) | 1
+        }
+        Kind::LdrLiteral => match insn & (1 << 23) != 0 {
+            true => base.wrapping_add((insn & 0xFFF) as u64),
+            false => base.wrapping_sub((insn & 0xFFF) as u64),
+        },
+        Kind::Adr => match insn & 0x00F00000 == 0x00800000 {
+            true => base.wrapping_add(arm_expand_imm(insn & 0xFFF) as u64),
+            false => base.wrapping_sub(arm_expand_imm(insn & 0xFFF) as u64),
+        },
+        _ => return None,
+    };
+
+    Some(target)
+}
+
+fn encode_arm_b(cond: u32, link: bool, from: u64, to: u64) -> Option<u32> {
+    let offset = to.wrapping_sub(from.wrapping_add(8)) as i64;
+
+    match fits(offset, 26, 4) {
+        true => Some(
+            (cond << 28)
+                | if link { 0x0B000000 } else { 0x0A000000 }
+                | (((offset >> 2) as u32) & 0xFFFFFF),
+        ),
+        false => None,
+    }
+}
+
+fn encode_arm_blx(from: u64, to: u64) -> Option<u32> {
+    let offset = (to & !1).wrapping_sub(from.wrapping_add(8)) as i64;
+
+    match fits(offset, 26, 2) {
+        true => Some(
+            0xFA000000 | ((((offset >> 1) & 1) as u32) << 24) | (((offset >> 2) as u32) & 0xFFFFFF),
+        ),
+        false => None,
+    }
+}
+
+fn push_arm_mov32(buffer: &mut Vec<u8>, cond: u32, rd: u32, value: u32) {
+    for (op, imm) in [(0x03000000, value & 0xFFFF), (0x03400000, value >> 16)] {
+        push_word(
+            buffer,
+            (cond << 28) | op | ((imm >> 12) << 16) | (rd << 12) | (imm & 0xFFF),
+        );
+    }
+}
+
+fn push_arm_jump(buffer: &mut Vec<u8>, target: u64) {
+    push_word(buffer, ARM_LDR_PC_PC_M4);
+    push_word(buffer, target as u32);
+}
+
+/// Register fields read and written by an instruction without a kind, as bit offsets.
+/// Classes where PC is unpredictable or not a register report none.
+fn arm_operands(insn: u32) -> Result<(Vec<u32>, Vec<u32>)> {
+    let op = (insn >> 21) & 0xF;
+    let load = insn & (1 << 20) != 0;
+    let indexed = insn & (1 << 24) == 0 || insn & (1 << 21) != 0;
+
+    // Stores read Rt and loads write it, indexed forms write back the base.
+    let transfer = |mut reads: Vec<u32>, loads: bool| {
+        let mut writes = Vec::new();
+
+        match loads {
+            true => writes.push(12),
+            false => reads.push(12),
+        }
+
+        if indexed {
+            writes.push(16);
+        }
+
+        (reads, writes)
+    };
+
+    let operands = match (insn >> 25) & 7 {
+        _ if insn >> 28 == 0xF => (vec![], vec![]),
+        // LDRH, LDRSB, LDRSH, LDRD and their stores
+        0 if insn & 0x90 == 0x90 && insn & 0x60 != 0 => {
+            let loads = load || insn & 0x60 == 0x40;
+
+            match insn & (1 << 22) {
+                0 => transfer(vec![16, 0], loads),
+                _ => transfer(vec![16], loads),
+            }
+        }
+        // Multiplies
+        0 if insn & 0xF0 == 0x90 => (vec![], vec![]),
+        // MOVW, MOVT, MSR and miscellaneous instructions
+        0 | 1 if insn & 0x01900000 == 0x01000000 => match (insn >> 25) & 1 {
+            0 => (vec![0], vec![12]),
+            _ => (vec![], vec![]),
+        },
+        // Data processing, MOV and MVN have no Rn, tests no Rd.
+        0 | 1 => {
+            let mut reads = match op {
+                0b1101 | 0b1111 => vec![],
+                _ => vec![16],
+            };
+
+            if insn & (1 << 25) == 0 {
+                reads.push(0);
+
+                if insn & 0x10 != 0 {
+                    reads.push(8);
+                }
+            }
+
+            match op {
+                0b1000..=0b1011 => (reads, vec![]),
+                _ => (reads, vec![12]),
+            }
+        }
+        // Media instructions
+        3 if insn & 0x10 != 0 => (vec![], vec![]),
+        // LDR, LDRB, STR and STRB
+        2 => transfer(vec![16], load),
+        3 => transfer(vec![16, 0], load),
+        // STM stores PC itself.
+        4 if !load && insn & 0x8000 != 0 => return Err(Error::InvalidData),
+        // LDC, STC, VLDR, VSTR, VLDM and VSTM, without MCRR and MRRC
+        6 if insn & 0x0FE00000 != 0x0C400000 => match insn & (1 << 21) {
+            0 => (vec![16], vec![]),
+            _ => (vec![16], vec![16]),
+        },
+        _ => (vec![], vec![]),
+    };
+
+    Ok(operands)
+}
+
+fn arm_is_preload(insn: u32) -> bool {
+    insn & 0xFD30F000 == 0xF510F000 // PLD, PLDW
+        || insn & 0xFD70F000 == 0xF450F000 // PLI
+}
+
+/// STR Rs, [SP, #-4]!; MOVW/MOVT Rs, #pc; <insn reading Rs>; LDR Rs, [SP], #4
+fn relocate_arm_pc(buffer: &mut Vec<u8>, insn: u32, pc: u64) -> Result<()> {
+    let field = |shift: u32| (insn >> shift) & 0xF;
+
+    if arm_is_preload(insn) {
+        let reads_pc = field(16) == REG_PC || (insn & (1 << 25) != 0 && field(0) == REG_PC);
+        push_word(buffer, if reads_pc { ARM_NOP } else { insn });
+        return Ok(());
+    }
+
+    let (reads, writes) = arm_operands(insn)?;
+
+    if !reads.iter().any(|&s| field(s) == REG_PC) {
+        push_word(buffer, insn);
+        return Ok(());
+    }
+
+    let used = reads
+        .iter()
+        .chain(writes.iter())
+        .map(|&s| field(s))
+        .collect::<Vec<_>>();
+
+    // Writing PC branches, reading SP would see the spill.
+    if writes.iter().any(|&s| field(s) == REG_PC) || used.contains(&REG_SP) {
+        return Err(Error::InvalidData);
+    }
+
+    // LDRD and STRD also use the register after Rt.
+    let scratch = (0..8)
+        .find(|r| !used.contains(r) && *r != field(12) + 1)
+        .ok_or(Error::InvalidData)?;
+
+    let rewritten = reads
+        .iter()
+        .filter(|&&s| field(s) == REG_PC)
+        .fold(insn, |insn, &s| (insn & !(0xF << s)) | (scratch << s));
+
+    push_word(buffer, ARM_PUSH | (scratch << 12));
+    push_arm_mov32(buffer, COND_AL, scratch, pc.wrapping_add(8) as u32);
+    push_word(buffer, rewritten);
+    push_word(buffer, ARM_POP | (scratch << 12));
+    Ok(())
+}
+
+fn relocate_arm(buffer: &mut Vec<u8>, insn: u32, target: u64, pc: u64) -> Result<()> {
+    let cond = insn >> 28;
+    let rd = (insn >> 12) & 0xF;
+
+    match decode_arm(insn) {
+        Kind::B | Kind::Bl => {
+            let link = decode_arm(insn) == Kind::Bl;
+
+            if let Some(b) = encode_arm_b(cond, link, pc, target) {
+                push_word(buffer, b);
+                return Ok(());
+            }
+
+            // B<!cond> over the absolute jump.
+            if cond != COND_AL {
+                let skip = if link { 16 } else { 12 };
+                push_word(
+                    buffer,
+                    encode_arm_b(invert_cond(cond), false, pc, pc + skip).unwrap(),
+                );
+            }
+
+            if link {
+                push_word(buffer, ARM_ADD_LR_PC_4);
+            }
+
+            push_arm_jump(buffer, target);
+        }
+        Kind::Blx => match encode_arm_blx(pc, target) {
+            Some(blx) => push_word(buffer, blx),
+            None => {
+                push_word(buffer, ARM_ADD_LR_PC_4);
+                push_arm_jump(buffer, target);
+            }
+        },
+        Kind::LdrLiteral => {
+            let offset = target.wrapping_sub(pc.wrapping_add(8)) as i64;
+
+            if rd == REG_PC {
+                return Err(Error::InvalidData);
+            }
+
+            if (-0xFFF..=0xFFF).contains(&offset) {
+                let u = if offset >= 0 { 1 << 23 } else { 0 };
+                push_word(
+                    buffer,
+                    (insn & !(0x00800FFF)) | u | (offset.unsigned_abs() as u32),
+                );
+            } else {
+                // MOVW/MOVT Rt, #target; LDR Rt, [Rt]
+                push_arm_mov32(buffer, cond, rd, target as u32);
+                push_word(
+                    buffer,
+                    (insn & 0xF0400000) | 0x05900000 | (rd << 16) | (rd << 12),
+                );
+            }
+        }
+        Kind::Adr => {
+            if rd == REG_PC {
+                return Err(Error::InvalidData);
+            }
+
+            push_arm_mov32(buffer, cond, rd, target as u32);
+        }
+        _ => relocate_arm_pc(buffer, insn, target)?,
+    }
+
+    Ok(())
+}
+
+fn arm_redirects_flow(insn: u32) -> bool {
+    insn & 0xFF000000 == 0xEA000000 // B
+        || insn & 0xFFFFFFF0 == 0xE12FFF10 // BX
+        || insn == 0xE1A0F00E // MOV PC, LR
+        || insn & 0xFFFF8000 == 0xE8BD8000 // POP {..., PC}
+        || insn & 0xFC10F000 == 0xE410F000 // LDR PC, [...]
+}
+
+fn arm_is_padding(insn: u32) -> bool {
+    insn == ARM_NOP
+        || insn == ARM_MOV_R0_R0
+        || insn == ARM_UDF
+        || insn & 0xFFF000F0 == 0xE1200070 // BKPT
+        || insn == 0
+}
+
+// Thumb
+
+fn is_thumb32(hw1: u32) -> bool {
+    hw1 >> 11 >= 0b11101
+}
+
+fn decode_thumb(buffer: &[u8], offset: usize) -> Option<Insn> {
+    let hw1 = read_half(buffer, offset);
+
+    if !is_thumb32(hw1) {
+        let kind = if hw1 & 0xF000 == 0xD000 && (hw1 >> 8) & 0xE != 0xE {
+            Kind::BCond
+        } else if hw1 & 0xF800 == 0xE000 {
+            Kind::B
+        } else if hw1 & 0xF500 == 0xB100 {
+            Kind::Cbz
+        } else if hw1 & 0xF800 == 0x4800 {
+            Kind::LdrLiteral
+        } else if hw1 & 0xF800 == 0xA000 {
+            Kind::Adr
+        } else if hw1 & 0xFF00 == 0xBF00 && hw1 & 0xF != 0 {
+            Kind::It
+        } else {
+            Kind::Other
+        };
+
+        return Some(Insn {
+            kind,
+            size: THUMB_INSN_SIZE,
+            hw1,
+            hw2: 0,
+        });
+    }
+
+    if offset + 4 > buffer.len() {
+        return None;
+    }
+
+    let hw2 = read_half(buffer, offset + 2);
+
+    let kind = if hw1 & 0xF800 == 0xF000 && hw2 & 0xD000 == 0x9000 {
+        Kind::B
+    } else if hw1 & 0xF800 == 0xF000 && hw2 & 0xD000 == 0xD000 {
+        Kind::Bl
+    } else if hw1 & 0xF800 == 0xF000 && hw2 & 0xD001 == 0xC000 {
+        Kind::Blx
+    } else if hw1 & 0xF800 == 0xF000 && hw2 & 0xD000 == 0x8000 && (hw1 >> 6) & 0xE != 0xE {
+        Kind::BCond
+    } else if matches!(hw1 & 0xFF7F, 0xF81F | 0xF83F | 0xF85F | 0xF91F | 0xF93F) {
+        Kind::LdrLiteral
+    } else if hw1 & 0xFBFF == 0xF20F || hw1 & 0xFBFF == 0xF2AF {
+        Kind::Adr
+    } else {
+        Kind::Other
+    };
+
+    Some(Insn {
+        kind,
+        size: THUMB_INSN_SIZE * 2,
+        hw1,
+        hw2,
+    })
+}
+
+fn thumb_target(insn: &Insn, pc: u64) -> Option<u64> {
+    let base = pc.wrapping_add(4);
+    let (hw1, hw2) = (insn.hw1, insn.hw2);
+
+    let target = match (insn.kind, insn.size) {
+        (Kind::B, THUMB_INSN_SIZE) => base.wrapping_add(sign_extend((hw1 & 0x7FF) << 1, 12) as u64),
+        (Kind::BCond, THUMB_INSN_SIZE) => {
+            base.wrapping_add(sign_extend((hw1 & 0xFF) << 1, 9) as u64)
+        }
+        (Kind::Cbz, _) => base + (((hw1 >> 9) & 1) << 6 | ((hw1 >> 3) & 0x1F) << 1) as u64,
+        (Kind::LdrLiteral, THUMB_INSN_SIZE) => align4(base) + ((hw1 & 0xFF) << 2) as u64,
+        (Kind::Adr, THUMB_INSN_SIZE) => align4(base) + ((hw1 & 0xFF) << 2) as u64,
+        (Kind::B | Kind::Bl | Kind::Blx, _) => {
+            let s = (hw1 >> 10) & 1;
+            let i1 = !((hw2 >> 13) ^ s) & 1;
+            let i2 = !((hw2 >> 11) ^ s) & 1;
+            let imm =
+                (s << 24) | (i1 << 23) | (i2 << 22) | ((hw1 & 0x3FF) << 12) | ((hw2 & 0x7FF) << 1);
+            let offset = sign_extend(imm, 25);
+
+            match insn.kind {
+                Kind::Blx => align4(base).wrapping_add(offset as u64),
+                _ => base.wrapping_add(offset as u64) | 1,
+            }
+        }
+        (Kind::BCond, _) => {
+            let imm = (((hw1 >> 10) & 1) << 20)
+                | (((hw2 >> 11) & 1) << 19)
+                | (((hw2 >> 13) & 1) << 18)
+                | ((hw1 & 0x3F) << 12)
+                | ((hw2 & 0x7FF) << 1);
+            base.wrapping_add(sign_extend(imm, 21) as u64) | 1
+        }
+        (Kind::LdrLiteral, _) => match hw1 & 0x80 != 0 {
+            true => align4(base) + (hw2 & 0xFFF) as u64,
+            false => align4(base) - (hw2 & 0xFFF) as u64,
+        },
+        (Kind::Adr, _) => {
+            let imm = (((hw1 >> 10) & 1) << 11) | (((hw2 >> 12) & 7) << 8) | (hw2 & 0xFF);
+            match hw1 & 0x00A0 == 0 {
+                true => align4(base) + imm as u64,
+                false => align4(base) - imm as u64,
+            }
+        }
+        _ => return None,
+    };
+
+    // Short branches stay in Thumb state.
+    match insn.kind {
+        Kind::B | Kind::BCond | Kind::Cbz => Some(target | 1),
+        _ => Some(target),
+    }
+}
+
+fn thumb_cond(insn: &Insn) -> u32 {
+    match insn.size {
+        THUMB_INSN_SIZE => (insn.hw1 >> 8) & 0xF,
+        _ => (insn.hw1 >> 6) & 0xF,
+    }
+}
+
+fn encode_thumb_b(base: u32, from: u64, to: u64) -> Option<(u32, u32)> {
+    let offset = match base {
+        0xC000 => (to & !3).wrapping_sub(align4(from.wrapping_add(4))) as i64,
+        _ => (to & !1).wrapping_sub(from.wrapping_add(4)) as i64,
+    };
+
+    if !fits(offset, 25, 2) {
+        return None;
+    }
+
+    let imm = offset as u32;
+    let s = (imm >> 24) & 1;
+    let j1 = (!(imm >> 23) ^ s) & 1;
+    let j2 = (!(imm >> 22) ^ s) & 1;
+
+    Some((
+        0xF000 | (s << 10) | ((imm >> 12) & 0x3FF),
+        base | (j1 << 13) | (j2 << 11) | ((imm >> 1) & 0x7FF),
+    ))
+}
+
+fn encode_thumb_bcond(cond: u32, from: u64, to: u64) -> Option<(u32, u32)> {
+    let offset = (to & !1).wrapping_sub(from.wrapping_add(4)) as i64;
+
+    if !fits(offset, 21, 2) {
+        return None;
+    }
+
+    let imm = offset as u32;
+
+    Some((
+        0xF000 | (((imm >> 20) & 1) << 10) | (cond << 6) | ((imm >> 12) & 0x3F),
+        0x8000 | (((imm >> 18) & 1) << 13) | (((imm >> 19) & 1) << 11) | ((imm >> 1) & 0x7FF),
+    ))
+}
+
+fn push_thumb_mov32(buffer: &mut Vec<u8>, rd: u32, value: u32) {
+    for (op, imm) in [(0xF240, value & 0xFFFF), (0xF2C0, value >> 16)] {
+        push_thumb32(
+            buffer,
+            op | (((imm >> 11) & 1) << 10) | (imm >> 12),
+            (((imm >> 8) & 7) << 12) | (rd << 8) | (imm & 0xFF),
+        );
+    }
+}
+
+fn thumb_jump_size(pc: u64) -> u64 {
+    match pc % 4 {
+        0 => 8,
+        _ => 10,
+    }
+}
+
+/// LDR.W PC, [PC, #0]; .word target, padded so the literal is word aligned.
+fn push_thumb_jump(buffer: &mut Vec<u8>, pc: u64, target: u64) {
+    if !pc.is_multiple_of(4) {
+        push_half(buffer, THUMB_NOP as u32);
+    }
+
+    push_thumb32(buffer, THUMB_LDR_PC_PC.0 as u32, THUMB_LDR_PC_PC.1 as u32);
+    push_word(buffer, target as u32);
+}
+
+/// PUSH {Rs}; MOVW/MOVT Rs, #pc; <insn reading Rs>; POP {Rs}
+fn relocate_thumb_pc(buffer: &mut Vec<u8>, insn: &Insn, pc: u64) -> Result<()> {
+    let (hw1, hw2) = (insn.hw1, insn.hw2);
+
+    // PC value read, registers in use and the masks of the fields reading PC.
+    let (value, used, fields) = if insn.size == THUMB_INSN_SIZE && hw1 & 0xFC00 == 0x4400 {
+        // ADD, CMP, MOV and BX/BLX on high registers
+        let op = (hw1 >> 8) & 3;
+        let rdn = ((hw1 >> 4) & 8) | (hw1 & 7);
+        let rm = (hw1 >> 3) & 0xF;
+        let reads_rdn = rdn == REG_PC && op < 2;
+
+        if rm != REG_PC && !reads_rdn {
+            push_half(buffer, hw1);
+            return Ok(());
+        }
+
+        // BX PC switches to ARM, writing PC branches.
+        if op == 3 || (rdn == REG_PC && op != 1) {
+            return Err(Error::InvalidData);
+        }
+
+        let mut fields = Vec::new();
+
+        if rm == REG_PC {
+            fields.push(0x78);
+        }
+
+        if reads_rdn {
+            fields.push(0x87);
+        }
+
+        (pc + 4, vec![rdn, rm], fields)
+    } else if insn.size > THUMB_INSN_SIZE
+        && (hw1 & 0xFF5F == 0xE95F || (hw1 & 0xEE1F == 0xEC1F && hw1 & 0x01A0 != 0))
+    {
+        // LDRD and LDC/VLDR literals, writing back PC is unpredictable.
+        if hw1 & 0x20 != 0 {
+            return Err(Error::InvalidData);
+        }
+
+        let used = match hw1 & 0xFF5F == 0xE95F {
+            true => vec![hw2 >> 12, (hw2 >> 8) & 0xF],
+            false => vec![],
+        };
+
+        (align4(pc + 4), used, vec![0xF])
+    } else if insn.size > THUMB_INSN_SIZE && hw1 == 0xE8DF && hw2 & 0xFFE0 == 0xF000 {
+        // TBB/TBH [PC, Rm] index a table placed after the instruction.
+        return Err(Error::InvalidData);
+    } else {
+        push_half(buffer, hw1);
+
+        if insn.size > THUMB_INSN_SIZE {
+            push_half(buffer, hw2);
+        }
+
+        return Ok(());
+    };
+
+    // Reading SP would see the spill.
+    if used.contains(&REG_SP) {
+        return Err(Error::InvalidData);
+    }
+
+    let scratch = (0..8)
+        .find(|r| !used.contains(r))
+        .ok_or(Error::InvalidData)?;
+
+    let word = fields.iter().fold(hw1 | (hw2 << 16), |word, &mask: &u32| {
+        (word & !mask) | (scratch << mask.trailing_zeros())
+    });
+
+    push_half(buffer, THUMB_PUSH as u32 | (1 << scratch));
+    push_thumb_mov32(buffer, scratch, value as u32);
+    push_half(buffer, word & 0xFFFF);
+
+    if insn.size > THUMB_INSN_SIZE {
+        push_half(buffer, word >> 16);
+    }
+
+    push_half(buffer, THUMB_POP as u32 | (1 << scratch));
+    Ok(())
+}
+
+fn relocate_thumb(buffer: &mut Vec<u8>, insn: &Insn, target: u64, pc: u64) -> Result<()> {
+    match insn.kind {
+        Kind::B => match encode_thumb_b(0x9000, pc, target) {
+            Some((hw1, hw2)) => push_thumb32(buffer, hw1, hw2),
+            None => push_thumb_jump(buffer, pc, target),
+        },
+        Kind::Bl | Kind::Blx => {
+            let base = if insn.kind == Kind::Bl {
+                0xD000
+            } else {
+                0xC000
+            };
+
+            match encode_thumb_b(base, pc, target) {
+                Some((hw1, hw2)) => push_thumb32(buffer, hw1, hw2),
+                None => {
+                    // ADR.W LR, return | 1 followed by an absolute jump.
+                    let ret = pc + 4 + thumb_jump_size(pc + 4);
+                    let imm = (ret | 1) - align4(pc + 4);
+                    push_thumb32(
+                        buffer,
+                        0xF20F | (((imm >> 11) & 1) << 10) as u32,
+                        ((((imm >> 8) & 7) << 12) | ((REG_LR as u64) << 8) | (imm & 0xFF)) as u32,
+                    );
+                    push_thumb_jump(buffer, pc + 4, target);
+                }
+            }
+        }
+        Kind::BCond => {
+            let cond = thumb_cond(insn);
+
+            match encode_thumb_bcond(cond, pc, target) {
+                Some((hw1, hw2)) => push_thumb32(buffer, hw1, hw2),
+                None => {
+                    // B<!cond> over the absolute jump.
+                    let skip = 2 + thumb_jump_size(pc + 2);
+                    push_half(
+                        buffer,
+                        0xD000 | (invert_cond(cond) << 8) | (((skip - 4) >> 1) as u32),
+                    );
+                    push_thumb_jump(buffer, pc + 2, target);
+                }
+            }
+        }
+        Kind::Cbz => {
+            let offset = (target & !1).wrapping_sub(pc + 4) as i64;
+
+            if (0..=126).contains(&offset) {
+                let imm = offset as u32;
+                push_half(
+                    buffer,
+                    (insn.hw1 & 0xFD07) | (((imm >> 6) & 1) << 9) | (((imm >> 1) & 0x1F) << 3),
+                );
+            } else {
+                // CB{N}Z with the inverted test over the absolute jump.
+                let skip = (2 + thumb_jump_size(pc + 2) - 4) as u32;
+                push_half(
+                    buffer,
+                    ((insn.hw1 ^ 0x0800) & 0xFD07)
+                        | (((skip >> 6) & 1) << 9)
+                        | (((skip >> 1) & 0x1F) << 3),
+                );
+                push_thumb_jump(buffer, pc + 2, target);
+            }
+        }
+        Kind::LdrLiteral => {
+            let (hw1, rt) = match insn.size {
+                THUMB_INSN_SIZE => (0xF85F, (insn.hw1 >> 8) & 7),
+                _ => (insn.hw1 & 0xFF7F, insn.hw2 >> 12),
+            };
+
+            if rt == REG_PC {
+                // LDR{B,H} with PC as target is a preload hint.
+                if hw1 != 0xF85F {
+                    return Ok(());
+                }
+
+                return Err(Error::InvalidData);
+            }
+
+            let offset = target.wrapping_sub(align4(pc + 4)) as i64;
+
+            if (-0xFFF..=0xFFF).contains(&offset) {
+                let u = if offset >= 0 { 0x80 } else { 0 };
+                push_thumb32(buffer, hw1 | u, (rt << 12) | offset.unsigned_abs() as u32);
+            } else {
+                // MOVW/MOVT Rt, #target; LDR Rt, [Rt]
+                push_thumb_mov32(buffer, rt, target as u32);
+                push_thumb32(buffer, (hw1 & 0xFF70) | 0x0080 | rt, rt << 12);
+            }
+        }
+        Kind::Adr => {
+            let rd = match insn.size {
+                THUMB_INSN_SIZE => (insn.hw1 >> 8) & 7,
+                _ => (insn.hw2 >> 8) & 0xF,
+            };
+
+            let offset = target.wrapping_sub(align4(pc + 4)) as i64;
+
+            if (-0xFFF..=0xFFF).contains(&offset) {
+                let imm = offset.unsigned_abs() as u32;
+                let op = if offset >= 0 { 0xF20F } else { 0xF2AF };
+                push_thumb32(
+                    buffer,
+                    op | (((imm >> 11) & 1) << 10),
+                    (((imm >> 8) & 7) << 12) | (rd << 8) | (imm & 0xFF),
+                );
+            } else {
+                push_thumb_mov32(buffer, rd, target as u32);
+            }
+        }
+        Kind::It => return Err(Error::InvalidData),
+        Kind::Other => relocate_thumb_pc(buffer, insn, target)?,
+    }
+
+    Ok(())
+}
+
+fn thumb_redirects_flow(insn: &Insn) -> bool {
+    let (hw1, hw2) = (insn.hw1, insn.hw2);
+
+    match insn.size {
+        THUMB_INSN_SIZE => {
+            insn.kind == Kind::B
+                || hw1 & 0xFF87 == 0x4700 // BX
+                || hw1 & 0xFF00 == 0xBD00 // POP {..., PC}
+        }
+        _ => {
+            insn.kind == Kind::B
+                || (hw1 == 0xE8BD && hw2 & 0x8000 != 0) // POP.W {..., PC}
+                || (hw1 & 0xFFF0 == 0xF8D0 && hw2 >> 12 == REG_PC) // LDR.W PC, [Rn, #imm]
+                || (hw1 == 0xF8DF && hw2 >> 12 == REG_PC) // LDR.W PC, [PC, #imm]
+        }
+    }
+}
+
+fn thumb_is_padding(insn: &Insn) -> bool {
+    insn.size == THUMB_INSN_SIZE
+        && (insn.hw1 == THUMB_NOP as u32
+            || insn.hw1 == THUMB_MOV_R8_R8 as u32
+            || insn.hw1 == THUMB_UDF as u32
+            || insn.hw1 & 0xFF00 == 0xBE00 // BKPT
+            || insn.hw1 == 0)
+}
+
+// Relocation
+
+fn relocate_pass(
+    buffer: &[u8],
+    from: u64,
+    to: u64,
+    offsets: &[Option<usize>],
+) -> Result<(Vec<u8>, Vec<Option<usize>>)> {
+    let thumb = is_thumb(from);
+    let (from, to) = (from & !1, to & !1);
+    let end = from + buffer.len() as u64;
+    let mut code = Vec::new();
+    let mut new_offsets = vec![None; offsets.len()];
+    let mut i = 0usize;
+
+    // Branches into the relocated block land on the relocated copy.
+    let remap = |target: u64, new_pc: u64| -> u64 {
+        let address = target & !1;
+
+        match address >= from && address < end {
+            true => match offsets[((address - from) as usize) / THUMB_INSN_SIZE] {
+                Some(o) => (to + o as u64) | (target & 1),
+                None => new_pc | (target & 1),
+            },
+            false => target,
+        }
+    };
+
+    while i < buffer.len() {
+        let pc = from + i as u64;
+        let new_pc = to + code.len() as u64;
+        new_offsets[i / THUMB_INSN_SIZE] = Some(code.len());
+
+        if thumb {
+            let insn = decode_thumb(buffer, i).ok_or(Error::InvalidData)?;
+
+            match thumb_target(&insn, pc) {
+                Some(target) => {
+                    let target = match insn.kind {
+                        Kind::LdrLiteral | Kind::Adr => target,
+                        _ => remap(target, new_pc),
+                    };
+
+                    relocate_thumb(&mut code, &insn, target, new_pc)?;
+                }
+                // Instructions without a target read PC at their own address.
+                None => relocate_thumb(&mut code, &insn, pc, new_pc)?,
+            }
+
+            i += insn.size;
+        } else {
+            let insn = read_word(buffer, i);
+
+            match arm_target(insn, pc) {
+                Some(target) => {
+                    let target = match decode_arm(insn) {
+                        Kind::LdrLiteral | Kind::Adr => target,
+                        _ => remap(target, new_pc),
+                    };
+
+                    relocate_arm(&mut code, insn, target, new_pc)?;
+                }
+                None => relocate_arm(&mut code, insn, pc, new_pc)?,
+            }
+
+            i += ARM_INSN_SIZE;
+        }
+    }
+
+    Ok((code, new_offsets))
+}
+
 // Arch
 
 pub const fn max_insn_size() -> usize {
-    4
+    ARM_INSN_SIZE
 }
 
-pub fn get_trap_data() -> Vec<u8> {
-    panic!("Unimplemented")
+pub fn get_trap_data(from: Address) -> Vec<u8> {
+    match is_thumb(from as u64) {
+        true => THUMB_UDF.to_le_bytes().to_vec(),
+        false => ARM_UDF.to_le_bytes().to_vec(),
+    }
 }
 
 pub fn get_jump_data(from: Address, to: Address) -> Vec<u8> {
-    panic!("Unimplemented")
+    let mut buffer = Vec::new();
+
+    match is_thumb(from as u64) {
+        true => push_thumb_jump(&mut buffer, from as u64 & !1, to as u64),
+        false => push_arm_jump(&mut buffer, to as u64),
+    }
+
+    buffer
+}
+
+/// LDR PC, [PC, #+/-imm12] reading `slot`, in the instruction set of `from`.
+pub fn get_slot_jump_data(from: Address, slot: Address) -> Result<Vec<u8>> {
+    let pc = match is_thumb(from as u64) {
+        true => align4((from as u64 & !1) + 4),
+        false => from as u64 + 8,
+    };
+
+    let offset = (slot as u64).wrapping_sub(pc) as i64;
+    let (up, imm) = ((offset >= 0) as u32, offset.unsigned_abs() as u32);
+
+    if imm > 0xFFF {
+        return Err(Error::InvalidData);
+    }
+
+    let mut buffer = Vec::new();
+
+    match is_thumb(from as u64) {
+        true => push_thumb32(&mut buffer, 0xF85F | (up << 7), 0xF000 | imm),
+        false => push_word(&mut buffer, 0xE51FF000 | (up << 23) | imm),
+    }
+
+    Ok(buffer)
+}
+
+pub fn get_backjump_data(from: Address, offset: u8) -> Vec<u8> {
+    let offset = offset as i64;
+
+    match is_thumb(from as u64) {
+        true => {
+            let imm = (-offset - 4) >> 1;
+            (0xE000u16 | (imm as u16 & 0x7FF)).to_le_bytes().to_vec()
+        }
+        false => {
+            let imm = (-offset - 8) >> 2;
+            (0xEA000000u32 | (imm as u32 & 0xFFFFFF))
+                .to_le_bytes()
+                .to_vec()
+        }
+    }
+}
+
+pub fn get_overwrite_size(from: Address, buffer: &[u8]) -> usize {
+    let mut size = 0usize;
+    let mut flow_redirected = false;
+
+    if is_thumb(from as u64) {
+        while size + THUMB_INSN_SIZE <= buffer.len() {
+            let insn = match decode_thumb(buffer, size) {
+                Some(insn) => insn,
+                None => break,
+            };
+
+            if flow_redirected && !thumb_is_padding(&insn) {
+                break;
+            }
+
+            if thumb_redirects_flow(&insn) {
+                flow_redirected = true;
+            }
+
+            size += insn.size;
+        }
+    } else {
+        while size + ARM_INSN_SIZE <= buffer.len() {
+            let insn = read_word(buffer, size);
+
+            if flow_redirected && !arm_is_padding(insn) {
+                break;
+            }
+
+            if arm_redirects_flow(insn) {
+                flow_redirected = true;
+            }
+
+            size += ARM_INSN_SIZE;
+        }
+    }
+
+    size
 }
 
-pub fn get_backjump_data(offset: u8) -> Vec<u8> {
-    panic!("Unimplemented")
+pub fn get_padding_size(from: Address, buffer: &[u8]) -> usize {
+    let mut size = 0usize;
+
+    if is_thumb(from as u64) {
+        let end = buffer.len() - buffer.len() % THUMB_INSN_SIZE;
+
+        while size < end {
+            match decode_thumb(buffer, end - size - THUMB_INSN_SIZE) {
+                Some(insn) if thumb_is_padding(&insn) => size += THUMB_INSN_SIZE,
+                _ => break,
+            }
+        }
+    } else {
+        let end = buffer.len() - buffer.len() % ARM_INSN_SIZE;
+
+        while size < end && arm_is_padding(read_word(buffer, end - size - ARM_INSN_SIZE)) {
+            size += ARM_INSN_SIZE;
+        }
+    }
+
+    size
 }
 
-pub fn get_overwrite_size(buffer: &[u8]) -> usize {
-    panic!("Unimplemented")
+pub fn relocate(buffer: &[u8], from: Address, to: Address) -> Result<Vec<u8>> {
+    let align = match is_thumb(from as u64) {
+        true => THUMB_INSN_SIZE,
+        false => ARM_INSN_SIZE,
+    };
+
+    if is_thumb(from as u64) != is_thumb(to as u64)
+        || !buffer.len().is_multiple_of(align)
+        || !(from as u64 & !1).is_multiple_of(align as u64)
+        || !(to as u64 & !1).is_multiple_of(align as u64)
+    {
+        return Err(Error::InvalidData);
+    }
+
+    let count = buffer.len() / THUMB_INSN_SIZE;
+    let (_, offsets) = relocate_pass(buffer, from as _, to as _, &vec![None; count])?;
+    let (code, _) = relocate_pass(buffer, from as _, to as _, &offsets)?;
+    Ok(code)
 }
 
-pub fn get_padding_size(buffer: &[u8]) -> usize {
-    panic!("Unimplemented")
+// Impl
+
+impl Arch for A32 {
+    fn kind(&self) -> ArchKind {
+        ArchKind::A32
+    }
+
+    fn max_insn_size(&self) -> usize {
+        max_insn_size()
+    }
+
+    fn jump_data(&self, from: Address, to: Address) -> Vec<u8> {
+        get_jump_data(from, to)
+    }
+
+    fn slot_jump_data(&self, from: Address, slot: Address) -> Result<Vec<u8>> {
+        get_slot_jump_data(from, slot)
+    }
+
+    fn backjump_data(&self, from: Address, offset: u8) -> Vec<u8> {
+        get_backjump_data(from, offset)
+    }
+
+    fn trap_data(&self, from: Address) -> Vec<u8> {
+        get_trap_data(from)
+    }
+
+    fn overwrite_size(&self, from: Address, buffer: &[u8]) -> usize {
+        get_overwrite_size(from, buffer)
+    }
+
+    fn padding_size(&self, from: Address, buffer: &[u8]) -> usize {
+        get_padding_size(from, buffer)
+    }
+
+    fn relocate(&self, buffer: &[u8], from: Address, to: Address) -> Result<Vec<u8>> {
+        relocate(buffer, from, to)
+    }
+
+    fn code_address(&self, from: Address) -> Address {
+        (from as usize & !1) as Address
+    }
+
+    fn with_state(&self, from: Address, at: Address) -> Address {
+        (at as usize | (from as usize & 1)) as Address
+    }
 }
 
-pub fn relocate(buffer: &[u8], to: Address) -> Result<Vec<u8>> {
-    panic!("Unimplemented")
+// Tests
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FAR: u64 = 0x1000_0000;
+
+    fn arm(insns: &[u32]) -> Vec<u8> {
+        insns.iter().flat_map(|w| w.to_le_bytes()).collect()
+    }
+
+    fn thumb(halves: &[u32]) -> Vec<u8> {
+        halves
+            .iter()
+            .flat_map(|h| (*h as u16).to_le_bytes())
+            .collect()
+    }
+
+    fn words(buffer: &[u8]) -> Vec<u32> {
+        (0..buffer.len() / 4)
+            .map(|i| read_word(buffer, i * 4))
+            .collect()
+    }
+
+    fn halves(buffer: &[u8]) -> Vec<u32> {
+        (0..buffer.len() / 2)
+            .map(|i| read_half(buffer, i * 2))
+            .collect()
+    }
+
+    fn relocate_arm_one(insn: u32, to: u64) -> Result<Vec<u32>> {
+        relocate(&arm(&[insn]), 0x1000 as _, to as _).map(|data| words(&data))
+    }
+
+    fn relocate_thumb_one(insn: &[u32], to: u64) -> Result<Vec<u32>> {
+        relocate(&thumb(insn), 0x1001 as _, (to | 1) as _).map(|data| halves(&data))
+    }
+
+    fn thumb_mov32(rd: u32, value: u32) -> Vec<u32> {
+        let mut buffer = Vec::new();
+        push_thumb_mov32(&mut buffer, rd, value);
+        halves(&buffer)
+    }
+
+    #[test]
+    fn encodes_jumps() {
+        assert_eq!(
+            words(&get_jump_data(0x1000 as _, 0x2000 as _)),
+            [ARM_LDR_PC_PC_M4, 0x2000]
+        );
+        assert_eq!(
+            halves(&get_jump_data(0x1001 as _, 0x2001 as _)),
+            [0xF8DF, 0xF000, 0x2001, 0]
+        );
+        assert_eq!(
+            halves(&get_jump_data(0x1003 as _, 0x2001 as _)),
+            [THUMB_NOP as u32, 0xF8DF, 0xF000, 0x2001, 0]
+        );
+
+        assert_eq!(words(&get_backjump_data(0x1000 as _, 8)), [0xEAFFFFFC]);
+        assert_eq!(halves(&get_backjump_data(0x1001 as _, 8)), [0xE7FA]);
+        assert_eq!(words(&get_trap_data(0x1000 as _)), [ARM_UDF]);
+        assert_eq!(halves(&get_trap_data(0x1001 as _)), [THUMB_UDF as u32]);
+    }
+
+    #[test]
+    fn jumps_through_slot() {
+        // ldr pc, [pc, #-16]
+        let data = get_slot_jump_data(0x1008 as _, 0x1000 as _).unwrap();
+        assert_eq!(words(&data), [0xE51FF010]);
+
+        // ldr.w pc, [pc, #-8]
+        let data = get_slot_jump_data(0x1005 as _, 0x1000 as _).unwrap();
+        assert_eq!(halves(&data), [0xF85F, 0xF008]);
+
+        let data = get_slot_jump_data(0x1001 as _, 0x1100 as _).unwrap();
+        assert_eq!(halves(&data), [0xF8DF, 0xF0FC]);
+
+        assert!(get_slot_jump_data(0x1000 as _, 0x3000 as _).is_err());
+    }
+
+    #[test]
+    fn relocates_arm_branches() {
+        // b #0x1100
+        let code = relocate_arm_one(0xEA00003E, 0x2000).unwrap();
+        assert_eq!(code.len(), 1);
+        assert_eq!(arm_target(code[0], 0x2000), Some(0x1100));
+
+        assert_eq!(
+            relocate_arm_one(0xEA00003E, FAR).unwrap(),
+            [ARM_LDR_PC_PC_M4, 0x1100]
+        );
+
+        // bl #0x1100
+        assert_eq!(
+            relocate_arm_one(0xEB00003E, FAR).unwrap(),
+            [ARM_ADD_LR_PC_4, ARM_LDR_PC_PC_M4, 0x1100]
+        );
+
+        // beq #0x1100 skips the jump when not taken.
+        assert_eq!(
+            relocate_arm_one(0x0A00003E, FAR).unwrap(),
+            [0x1A000001, ARM_LDR_PC_PC_M4, 0x1100]
+        );
+
+        // blx #0x1100 switches to Thumb.
+        assert_eq!(
+            relocate_arm_one(0xFA00003E, FAR).unwrap(),
+            [ARM_ADD_LR_PC_4, ARM_LDR_PC_PC_M4, 0x1101]
+        );
+    }
+
+    #[test]
+    fn relocates_arm_branch_in_block() {
+        // b #0x1004; nop
+        let data = relocate(&arm(&[0xEAFFFFFF, ARM_NOP]), 0x1000 as _, FAR as _).unwrap();
+        assert_eq!(words(&data), [0xEAFFFFFF, ARM_NOP]);
+    }
+
+    #[test]
+    fn relocates_arm_literals() {
+        // ldr r0, [pc, #8]
+        assert_eq!(relocate_arm_one(0xE59F0008, 0x1100).unwrap(), [0xE51F00F8]);
+        assert_eq!(
+            relocate_arm_one(0xE59F0008, FAR).unwrap(),
+            [0xE3010010, 0xE3400000, 0xE5900000]
+        );
+
+        // ldr pc, [pc, #8]
+        assert!(relocate_arm_one(0xE59FF008, FAR).is_err());
+
+        // adr r0, #0x100C
+        assert_eq!(
+            relocate_arm_one(0xE28F0004, FAR).unwrap(),
+            [0xE301000C, 0xE3400000]
+        );
+    }
+
+    #[test]
+    fn relocates_arm_pc_operands() {
+        let spill = |scratch: u32, insn: u32| {
+            vec![
+                ARM_PUSH | (scratch << 12),
+                0xE3010008 | (scratch << 12),
+                0xE3400000 | (scratch << 12),
+                insn,
+                ARM_POP | (scratch << 12),
+            ]
+        };
+
+        // add r0, pc, r1
+        assert_eq!(
+            relocate_arm_one(0xE08F0001, FAR).unwrap(),
+            spill(2, 0xE0820001)
+        );
+        // adds r0, pc, #4
+        assert_eq!(
+            relocate_arm_one(0xE29F0004, FAR).unwrap(),
+            spill(2, 0xE2920004)
+        );
+        // mov r0, pc
+        assert_eq!(
+            relocate_arm_one(0xE1A0000F, FAR).unwrap(),
+            spill(2, 0xE1A00002)
+        );
+        // ldrh r0, [pc, #4]
+        assert_eq!(
+            relocate_arm_one(0xE1DF00B4, FAR).unwrap(),
+            spill(2, 0xE1D200B4)
+        );
+        // ldrd r2, r3, [pc, #-4]
+        assert_eq!(
+            relocate_arm_one(0xE14F20D4, FAR).unwrap(),
+            spill(0, 0xE14020D4)
+        );
+        // ldr r0, [pc, r1]
+        assert_eq!(
+            relocate_arm_one(0xE79F0001, FAR).unwrap(),
+            spill(2, 0xE7920001)
+        );
+        // str pc, [r0]
+        assert_eq!(
+            relocate_arm_one(0xE580F000, FAR).unwrap(),
+            spill(1, 0xE5801000)
+        );
+        // vldr d0, [pc, #8]
+        assert_eq!(
+            relocate_arm_one(0xED9F0B02, FAR).unwrap(),
+            spill(0, 0xED900B02)
+        );
+
+        // pld [pc, #8] is only a hint.
+        assert_eq!(relocate_arm_one(0xF5DFF008, FAR).unwrap(), [ARM_NOP]);
+    }
+
+    #[test]
+    fn rejects_arm_pc_operands() {
+        // bx pc; add pc, pc, r0; add r0, sp, pc; ldr r0, [pc, #4]!; stm r0, {r1, pc}
+        for insn in [0xE12FFF1F, 0xE08FF000, 0xE08D000F, 0xE5BF0004, 0xE8808002] {
+            assert!(relocate_arm_one(insn, FAR).is_err(), "{:#x}", insn);
+        }
+    }
+
+    #[test]
+    fn keeps_arm_instructions() {
+        // push {r4, lr}; mov r0, r1; ldr r0, [r1, #4]; dmb ish; mov pc, lr
+        for insn in [0xE92D4010, 0xE1A00001, 0xE5910004, 0xF57FF05B, 0xE1A0F00E] {
+            assert_eq!(relocate_arm_one(insn, FAR).unwrap(), [insn]);
+        }
+    }
+
+    #[test]
+    fn relocates_thumb_branches() {
+        // b #0x1100
+        let code = relocate_thumb_one(&[0xE07E], 0x2000).unwrap();
+        let insn = decode_thumb(&thumb(&code), 0).unwrap();
+        assert_eq!(insn.kind, Kind::B);
+        assert_eq!(thumb_target(&insn, 0x2000), Some(0x1101));
+
+        assert_eq!(
+            relocate_thumb_one(&[0xE07E], FAR).unwrap(),
+            [0xF8DF, 0xF000, 0x1101, 0]
+        );
+
+        // bl #0x1100 sets LR past the jump.
+        let (hw1, hw2) = encode_thumb_b(0xD000, 0x1000, 0x1101).unwrap();
+        assert_eq!(
+            relocate_thumb_one(&[hw1, hw2], FAR).unwrap(),
+            [0xF20F, 0x0E09, 0xF8DF, 0xF000, 0x1101, 0]
+        );
+
+        // beq #0x1100 skips the jump when not taken.
+        let code = relocate_thumb_one(&[0xD07E], 0x2000).unwrap();
+        let insn = decode_thumb(&thumb(&code), 0).unwrap();
+        assert_eq!((insn.kind, insn.size), (Kind::BCond, 4));
+        assert_eq!(thumb_cond(&insn), 0);
+        assert_eq!(thumb_target(&insn, 0x2000), Some(0x1101));
+
+        assert_eq!(
+            relocate_thumb_one(&[0xD07E], FAR).unwrap(),
+            [0xD104, THUMB_NOP as u32, 0xF8DF, 0xF000, 0x1101, 0]
+        );
+
+        // cbz r0, #0x1010 becomes cbnz over the jump.
+        assert_eq!(
+            relocate_thumb_one(&[0xB130], FAR).unwrap(),
+            [0xB920, THUMB_NOP as u32, 0xF8DF, 0xF000, 0x1011, 0]
+        );
+    }
+
+    #[test]
+    fn relocates_thumb_literals() {
+        // ldr r0, [pc, #8]
+        assert_eq!(
+            relocate_thumb_one(&[0x4802], 0x2000).unwrap(),
+            [0xF85F, 0x0FF8]
+        );
+
+        let mut expected = thumb_mov32(0, 0x100C);
+        expected.extend([0xF8D0, 0x0000]);
+        assert_eq!(relocate_thumb_one(&[0x4802], FAR).unwrap(), expected);
+
+        // adr r0, #0x100C
+        assert_eq!(
+            relocate_thumb_one(&[0xA002], FAR).unwrap(),
+            thumb_mov32(0, 0x100C)
+        );
+
+        // it eq
+        assert!(relocate_thumb_one(&[0xBF08, 0x4608], FAR).is_err());
+    }
+
+    #[test]
+    fn relocates_thumb_pc_operands() {
+        let spill = |scratch: u32, value: u32, insn: &[u32]| {
+            let mut code = vec![THUMB_PUSH as u32 | (1 << scratch)];
+            code.extend(thumb_mov32(scratch, value));
+            code.extend(insn);
+            code.push(THUMB_POP as u32 | (1 << scratch));
+            code
+        };
+
+        // add r3, pc
+        assert_eq!(
+            relocate_thumb_one(&[0x447B], FAR).unwrap(),
+            spill(0, 0x1004, &[0x4403])
+        );
+        // mov r0, pc
+        assert_eq!(
+            relocate_thumb_one(&[0x4678], FAR).unwrap(),
+            spill(1, 0x1004, &[0x4608])
+        );
+        // cmp pc, r1
+        assert_eq!(
+            relocate_thumb_one(&[0x458F], FAR).unwrap(),
+            spill(0, 0x1004, &[0x4508])
+        );
+        // ldrd r0, r1, [pc, #8]
+        assert_eq!(
+            relocate_thumb_one(&[0xE9DF, 0x0102], FAR).unwrap(),
+            spill(2, 0x1004, &[0xE9D2, 0x0102])
+        );
+        // vldr d0, [pc, #8] at a half-word address
+        let code = relocate(
+            &thumb(&[0xBF00, 0xED9F, 0x0B02]),
+            0x1001 as _,
+            (FAR | 1) as _,
+        );
+        let mut expected = vec![THUMB_NOP as u32];
+        expected.extend(spill(0, 0x1004, &[0xED90, 0x0B02]));
+        assert_eq!(halves(&code.unwrap()), expected);
+    }
+
+    #[test]
+    fn rejects_thumb_pc_operands() {
+        // bx pc; add pc, r0; add sp, pc; tbb [pc, r0]
+        for insn in [&[0x4778][..], &[0x4487], &[0x44FD], &[0xE8DF, 0xF000]] {
+            assert!(relocate_thumb_one(insn, FAR).is_err(), "{:x?}", insn);
+        }
+    }
+
+    #[test]
+    fn keeps_thumb_instructions() {
+        // push {r4, lr}; mov r0, r1; add r0, r1; ldr.w r0, [r1, #4]
+        for insn in [&[0xB510][..], &[0x4608], &[0x4408], &[0xF8D1, 0x0004]] {
+            assert_eq!(relocate_thumb_one(insn, FAR).unwrap(), insn);
+        }
+    }
 }
@@ -1,33 +1,585 @@
 // Includes
 
+use super::{Arch, ArchKind};
 use crate::core::*;
 
+// Globals
+
+const INSN_SIZE: usize = 4;
+
+const NOP: u32 = 0xD503201F;
+const BRK: u32 = 0xD4200000;
+const BR_X17: u32 = 0xD61F0220;
+const BLR_X17: u32 = 0xD63F0220;
+const LDR_X17_LITERAL_8: u32 = 0x58000051;
+
+const SCRATCH: u32 = 17;
+
+// Types
+
+pub struct A64;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum Kind {
+    B,
+    Bl,
+    BCond,
+    Cbz,
+    Tbz,
+    Adr,
+    Adrp,
+    LdrLiteral,
+    Other,
+}
+
+// Helpers
+
+fn read_word(buffer: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes([
+        buffer[offset],
+        buffer[offset + 1],
+        buffer[offset + 2],
+        buffer[offset + 3],
+    ])
+}
+
+fn push_word(buffer: &mut Vec<u8>, word: u32) {
+    buffer.extend_from_slice(&word.to_le_bytes());
+}
+
+fn push_quad(buffer: &mut Vec<u8>, quad: u64) {
+    buffer.extend_from_slice(&quad.to_le_bytes());
+}
+
+fn sign_extend(value: u32, bits: u32) -> i64 {
+    let shift = 64 - bits;
+    ((value as i64) << shift) >> shift
+}
+
+fn fits(offset: i64, bits: u32, scale: u32) -> bool {
+    let limit = 1i64 << (bits - 1);
+    offset % (scale as i64) == 0 && (-limit..limit).contains(&(offset / scale as i64))
+}
+
+fn field(offset: i64, bits: u32, scale: u32) -> u32 {
+    ((offset / scale as i64) as u32) & ((1u32 << bits) - 1)
+}
+
+fn kind_of(insn: u32) -> Kind {
+    if insn & 0xFC000000 == 0x14000000 {
+        Kind::B
+    } else if insn & 0xFC000000 == 0x94000000 {
+        Kind::Bl
+    } else if insn & 0xFF000010 == 0x54000000 {
+        Kind::BCond
+    } else if insn & 0x7E000000 == 0x34000000 {
+        Kind::Cbz
+    } else if insn & 0x7E000000 == 0x36000000 {
+        Kind::Tbz
+    } else if insn & 0x9F000000 == 0x10000000 {
+        Kind::Adr
+    } else if insn & 0x9F000000 == 0x90000000 {
+        Kind::Adrp
+    } else if insn & 0x3B000000 == 0x18000000 {
+        Kind::LdrLiteral
+    } else {
+        Kind::Other
+    }
+}
+
+fn target_of(insn: u32, pc: u64) -> Option<u64> {
+    let offset = match kind_of(insn) {
+        Kind::B | Kind::Bl => sign_extend(insn & 0x3FFFFFF, 26) << 2,
+        Kind::BCond | Kind::Cbz | Kind::LdrLiteral => sign_extend((insn >> 5) & 0x7FFFF, 19) << 2,
+        Kind::Tbz => sign_extend((insn >> 5) & 0x3FFF, 14) << 2,
+        Kind::Adr => sign_extend((((insn >> 5) & 0x7FFFF) << 2) | ((insn >> 29) & 3), 21),
+        Kind::Adrp => {
+            let imm = sign_extend((((insn >> 5) & 0x7FFFF) << 2) | ((insn >> 29) & 3), 21);
+            return Some((pc & !0xFFF).wrapping_add((imm << 12) as u64));
+        }
+        Kind::Other => return None,
+    };
+
+    Some(pc.wrapping_add(offset as u64))
+}
+
+fn redirects_flow(insn: u32) -> bool {
+    kind_of(insn) == Kind::B
+        || (kind_of(insn) == Kind::BCond && insn & 0xE == 0xE)
+        || insn & 0xFFFFFC1F == 0xD61F0000 // BR
+        || insn & 0xFFFFFC1F == 0xD65F0000 // RET
+        || insn == 0xD69F03E0 // ERET
+}
+
+fn is_padding(insn: u32) -> bool {
+    insn == NOP || insn & 0xFFE0001F == BRK || insn & 0xFFFF0000 == 0
+}
+
+fn encode_b(from: u64, to: u64, link: bool) -> Option<u32> {
+    let offset = to.wrapping_sub(from) as i64;
+
+    match fits(offset, 26, 4) {
+        true => Some(if link { 0x94000000 } else { 0x14000000 } | field(offset, 26, 4)),
+        false => None,
+    }
+}
+
+fn encode_adr(rd: u32, from: u64, to: u64) -> Option<u32> {
+    let offset = to.wrapping_sub(from) as i64;
+
+    match fits(offset, 21, 1) {
+        true => {
+            let imm = field(offset, 21, 1);
+            Some(0x10000000 | ((imm & 3) << 29) | ((imm >> 2) << 5) | rd)
+        }
+        false => None,
+    }
+}
+
+fn encode_adrp(rd: u32, from: u64, to: u64) -> Option<u32> {
+    let offset = ((to & !0xFFF).wrapping_sub(from & !0xFFF) as i64) >> 12;
+
+    match fits(offset, 21, 1) {
+        true => {
+            let imm = field(offset, 21, 1);
+            Some(0x90000000 | ((imm & 3) << 29) | ((imm >> 2) << 5) | rd)
+        }
+        false => None,
+    }
+}
+
+/// LDR Xt, #8; B #12; .quad value
+fn push_literal_load(buffer: &mut Vec<u8>, rt: u32, value: u64) {
+    push_word(buffer, 0x58000040 | rt);
+    push_word(buffer, 0x14000003);
+    push_quad(buffer, value);
+}
+
+/// LDR X17, #8; BR X17; .quad target
+fn push_absolute_jump(buffer: &mut Vec<u8>, target: u64) {
+    push_word(buffer, LDR_X17_LITERAL_8);
+    push_word(buffer, BR_X17);
+    push_quad(buffer, target);
+}
+
+/// Unsigned-offset load of `Rt` from `[Rn]`, matching the literal form being replaced.
+fn register_load(insn: u32, rn: u32) -> Option<u32> {
+    let rt = insn & 0x1F;
+    let base = match ((insn >> 30) & 3, (insn >> 26) & 1) {
+        (0, 0) => 0xB9400000, // LDR Wt
+        (1, 0) => 0xF9400000, // LDR Xt
+        (2, 0) => 0xB9800000, // LDRSW Xt
+        (0, 1) => 0xBD400000, // LDR St
+        (1, 1) => 0xFD400000, // LDR Dt
+        (2, 1) => 0x3DC00000, // LDR Qt
+        _ => return None,
+    };
+
+    Some(base | (rn << 5) | rt)
+}
+
+fn relocate_insn(buffer: &mut Vec<u8>, insn: u32, target: u64, pc: u64) -> Result<()> {
+    let offset = target.wrapping_sub(pc) as i64;
+
+    match kind_of(insn) {
+        Kind::B | Kind::Bl => {
+            let link = kind_of(insn) == Kind::Bl;
+
+            if let Some(b) = encode_b(pc, target, link) {
+                push_word(buffer, b);
+            } else if link {
+                // LDR X17, #12; BLR X17; B #12; .quad target
+                push_word(buffer, 0x58000060 | SCRATCH);
+                push_word(buffer, BLR_X17);
+                push_word(buffer, 0x14000003);
+                push_quad(buffer, target);
+            } else {
+                push_absolute_jump(buffer, target);
+            }
+        }
+        Kind::BCond | Kind::Cbz | Kind::Tbz => {
+            let (bits, shift, mask) = match kind_of(insn) {
+                Kind::Tbz => (14, 5, 0x3FFFu32 << 5),
+                _ => (19, 5, 0x7FFFFu32 << 5),
+            };
+
+            if fits(offset, bits, 4) {
+                push_word(buffer, (insn & !mask) | (field(offset, bits, 4) << shift));
+            } else if kind_of(insn) == Kind::BCond && insn & 0xE == 0xE {
+                push_absolute_jump(buffer, target);
+            } else {
+                // Invert the condition and skip over an absolute jump.
+                let inverted = match kind_of(insn) {
+                    Kind::BCond => insn ^ 1,
+                    _ => insn ^ (1 << 24),
+                };
+
+                push_word(buffer, (inverted & !mask) | (field(20, bits, 4) << shift));
+                push_absolute_jump(buffer, target);
+            }
+        }
+        Kind::Adr => {
+            let rd = insn & 0x1F;
+
+            if let Some(adr) = encode_adr(rd, pc, target) {
+                push_word(buffer, adr);
+            } else if let Some(adrp) = encode_adrp(rd, pc, target) {
+                push_word(buffer, adrp);
+                push_word(
+                    buffer,
+                    0x91000000 | (((target & 0xFFF) as u32) << 10) | (rd << 5) | rd,
+                );
+            } else {
+                push_literal_load(buffer, rd, target);
+            }
+        }
+        Kind::Adrp => {
+            let rd = insn & 0x1F;
+
+            match encode_adrp(rd, pc, target) {
+                Some(adrp) => push_word(buffer, adrp),
+                None => push_literal_load(buffer, rd, target),
+            }
+        }
+        Kind::LdrLiteral => {
+            if fits(offset, 19, 4) {
+                push_word(
+                    buffer,
+                    (insn & !(0x7FFFF << 5)) | (field(offset, 19, 4) << 5),
+                );
+            } else if (insn >> 30) & 3 == 3 && (insn >> 26) & 1 == 0 {
+                // PRFM is only a hint.
+                push_word(buffer, NOP);
+            } else {
+                let rn = match (insn >> 26) & 1 {
+                    0 => insn & 0x1F,
+                    _ => SCRATCH,
+                };
+
+                push_literal_load(buffer, rn, target);
+                push_word(buffer, register_load(insn, rn).ok_or(Error::InvalidData)?);
+            }
+        }
+        Kind::Other => push_word(buffer, insn),
+    }
+
+    Ok(())
+}
+
+fn relocate_pass(
+    buffer: &[u8],
+    from: u64,
+    to: u64,
+    offsets: &[Option<usize>],
+) -> Result<(Vec<u8>, Vec<Option<usize>>)> {
+    let mut code = Vec::new();
+    let mut new_offsets = Vec::with_capacity(offsets.len());
+    let end = from + buffer.len() as u64;
+
+    for i in (0..buffer.len()).step_by(INSN_SIZE) {
+        let insn = read_word(buffer, i);
+        let pc = from + i as u64;
+        let new_pc = to + code.len() as u64;
+        new_offsets.push(Some(code.len()));
+
+        let target = match target_of(insn, pc) {
+            Some(t) => t,
+            None => {
+                push_word(&mut code, insn);
+                continue;
+            }
+        };
+
+        // Branches into the relocated block land on the relocated copy.
+        let is_branch = !matches!(kind_of(insn), Kind::Adr | Kind::Adrp | Kind::LdrLiteral);
+        let target = match is_branch && target >= from && target < end {
+            true => match offsets[((target - from) as usize) / INSN_SIZE] {
+                Some(o) => to + o as u64,
+                None => new_pc,
+            },
+            false => target,
+        };
+
+        relocate_insn(&mut code, insn, target, new_pc)?;
+    }
+
+    Ok((code, new_offsets))
+}
+
 // Arch
 
 pub const fn max_insn_size() -> usize {
-    4
+    INSN_SIZE
 }
 
 pub fn get_trap_data() -> Vec<u8> {
-    panic!("Unimplemented")
+    BRK.to_le_bytes().to_vec()
 }
 
-pub fn get_jump_data(from: Address, to: Address) -> Vec<u8> {
-    panic!("Unimplemented")
+pub fn get_jump_data(target: Address) -> Vec<u8> {
+    let mut buffer = Vec::new();
+    push_absolute_jump(&mut buffer, target as u64);
+    buffer
+}
+
+pub fn get_near_jump_data(from: Address, to: Address) -> Option<Vec<u8>> {
+    Some(
+        encode_b(from as u64, to as u64, false)?
+            .to_le_bytes()
+            .to_vec(),
+    )
+}
+
+/// LDR X17, slot; BR X17
+pub fn get_slot_jump_data(from: Address, slot: Address) -> Result<Vec<u8>> {
+    let offset = (slot as u64).wrapping_sub(from as u64) as i64;
+
+    if !fits(offset, 19, 4) {
+        return Err(Error::InvalidData);
+    }
+
+    let mut buffer = Vec::new();
+    push_word(
+        &mut buffer,
+        0x58000000 | (field(offset, 19, 4) << 5) | SCRATCH,
+    );
+    push_word(&mut buffer, BR_X17);
+    Ok(buffer)
 }
 
 pub fn get_backjump_data(offset: u8) -> Vec<u8> {
-    panic!("Unimplemented")
+    encode_b(offset as u64, 0, false)
+        .unwrap()
+        .to_le_bytes()
+        .to_vec()
 }
 
 pub fn get_overwrite_size(buffer: &[u8]) -> usize {
-    panic!("Unimplemented")
+    let mut size = 0usize;
+    let mut flow_redirected = false;
+
+    while size + INSN_SIZE <= buffer.len() {
+        let insn = read_word(buffer, size);
+
+        if flow_redirected && !is_padding(insn) {
+            break;
+        }
+
+        if redirects_flow(insn) {
+            flow_redirected = true;
+        }
+
+        size += INSN_SIZE;
+    }
+
+    size
 }
 
 pub fn get_padding_size(buffer: &[u8]) -> usize {
-    panic!("Unimplemented")
+    let mut size = 0usize;
+    let end = buffer.len() - buffer.len() % INSN_SIZE;
+
+    while size < end && is_padding(read_word(buffer, end - size - INSN_SIZE)) {
+        size += INSN_SIZE;
+    }
+
+    size
+}
+
+pub fn relocate(buffer: &[u8], from: Address, to: Address) -> Result<Vec<u8>> {
+    if !buffer.len().is_multiple_of(INSN_SIZE) || !(from as u64).is_multiple_of(INSN_SIZE as u64) {
+        return Err(Error::InvalidData);
+    }
+
+    let count = buffer.len() / INSN_SIZE;
+    let (_, offsets) = relocate_pass(buffer, from as _, to as _, &vec![None; count])?;
+    let (code, _) = relocate_pass(buffer, from as _, to as _, &offsets)?;
+    Ok(code)
+}
+
+// Impl
+
+impl Arch for A64 {
+    fn kind(&self) -> ArchKind {
+        ArchKind::A64
+    }
+
+    fn max_insn_size(&self) -> usize {
+        max_insn_size()
+    }
+
+    fn jump_data(&self, from: Address, to: Address) -> Vec<u8> {
+        // A single B reaches +/-128MiB, anything further goes through X17.
+        get_near_jump_data(from, to).unwrap_or_else(|| get_jump_data(to))
+    }
+
+    fn slot_jump_data(&self, from: Address, slot: Address) -> Result<Vec<u8>> {
+        get_slot_jump_data(from, slot)
+    }
+
+    fn backjump_data(&self, _from: Address, offset: u8) -> Vec<u8> {
+        get_backjump_data(offset)
+    }
+
+    fn trap_data(&self, _from: Address) -> Vec<u8> {
+        get_trap_data()
+    }
+
+    fn overwrite_size(&self, _from: Address, buffer: &[u8]) -> usize {
+        get_overwrite_size(buffer)
+    }
+
+    fn padding_size(&self, _from: Address, buffer: &[u8]) -> usize {
+        get_padding_size(buffer)
+    }
+
+    fn relocate(&self, buffer: &[u8], from: Address, to: Address) -> Result<Vec<u8>> {
+        relocate(buffer, from, to)
+    }
 }
 
-pub fn relocate(buffer: &[u8], to: Address) -> Result<Vec<u8>> {
-    panic!("Unimplemented")
+// Tests
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FAR: u64 = 0x10_0000_0000;
+
+    fn words(buffer: &[u8]) -> Vec<u32> {
+        (0..buffer.len() / INSN_SIZE)
+            .map(|i| read_word(buffer, i * INSN_SIZE))
+            .collect()
+    }
+
+    fn quad(buffer: &[u8], offset: usize) -> u64 {
+        u64::from_le_bytes(buffer[offset..offset + 8].try_into().unwrap())
+    }
+
+    fn relocate_one(insn: u32, to: u64) -> Vec<u8> {
+        relocate(&insn.to_le_bytes(), 0x1000 as _, to as _).unwrap()
+    }
+
+    #[test]
+    fn encodes_branches() {
+        assert_eq!(encode_b(0x1000, 0x1008, false), Some(0x14000002));
+        assert_eq!(encode_b(0x1000, 0xFFC, true), Some(0x97FFFFFF));
+        assert_eq!(encode_b(0x1000, 0x1000 + (128 << 20), false), None);
+        assert_eq!(encode_b(0x1000, 0x1002, false), None);
+    }
+
+    #[test]
+    fn encodes_addresses() {
+        assert_eq!(encode_adr(0, 0x1000, 0x1010), Some(0x10000080));
+        assert_eq!(encode_adr(0, 0x1000, 0x1000 + (1 << 20)), None);
+        assert_eq!(encode_adrp(1, 0x1000, 0x3FFF), Some(0xD0000001));
+        assert_eq!(target_of(0xD0000001, 0x1234), Some(0x3000));
+    }
+
+    #[test]
+    fn jumps_near_and_far() {
+        assert_eq!(
+            words(&A64.jump_data(0x1000 as _, 0x2000 as _)),
+            [0x14000400]
+        );
+        assert_eq!(
+            words(&A64.jump_data(0x2000 as _, 0x1000 as _)),
+            [0x17FFFC00]
+        );
+
+        let data = A64.jump_data(0x1000 as _, FAR as _);
+        assert_eq!(data.len(), 16);
+        assert_eq!(words(&data[..8]), [LDR_X17_LITERAL_8, BR_X17]);
+        assert_eq!(quad(&data, 8), FAR);
+    }
+
+    #[test]
+    fn jumps_through_slot() {
+        let data = get_slot_jump_data(0x1008 as _, 0x1000 as _).unwrap();
+        assert_eq!(words(&data), [0x58FFFFD1, BR_X17]);
+        assert!(get_slot_jump_data(0x1000 as _, FAR as _).is_err());
+    }
+
+    #[test]
+    fn relocates_near_branch() {
+        // b #+0x100
+        let data = relocate_one(0x14000040, 0x2000);
+        assert_eq!(words(&data).len(), 1);
+        assert_eq!(kind_of(words(&data)[0]), Kind::B);
+        assert_eq!(target_of(words(&data)[0], 0x2000), Some(0x1100));
+    }
+
+    #[test]
+    fn relocates_far_branch() {
+        // b #+0x100
+        let data = relocate_one(0x14000040, FAR);
+        assert_eq!(words(&data[..8]), [LDR_X17_LITERAL_8, BR_X17]);
+        assert_eq!(quad(&data, 8), 0x1100);
+
+        // bl #+0x100
+        let data = relocate_one(0x94000040, FAR);
+        assert_eq!(words(&data[..12]), [0x58000071, BLR_X17, 0x14000003]);
+        assert_eq!(quad(&data, 12), 0x1100);
+    }
+
+    #[test]
+    fn relocates_far_conditional() {
+        // b.eq, cbz x0 and tbz w0, #0 to +0x10 become their inverse over a jump.
+        for (insn, inverted) in [
+            (0x54000080, 0x540000A1),
+            (0xB4000080, 0xB50000A0),
+            (0x36000080, 0x370000A0),
+        ] {
+            let data = relocate_one(insn, FAR);
+            assert_eq!(words(&data[..12]), [inverted, LDR_X17_LITERAL_8, BR_X17]);
+            assert_eq!(quad(&data, 12), 0x1010);
+        }
+    }
+
+    #[test]
+    fn relocates_far_adr() {
+        // adr x0, #+0x10
+        let data = relocate_one(0x10000080, 0x1000_0000);
+        assert_eq!(words(&data)[1], 0x91004000);
+        assert_eq!(target_of(words(&data)[0], 0x1000_0000), Some(0x1000));
+
+        let data = relocate_one(0x10000080, FAR);
+        assert_eq!(words(&data[..8]), [0x58000040, 0x14000003]);
+        assert_eq!(quad(&data, 8), 0x1010);
+    }
+
+    #[test]
+    fn relocates_far_literal_load() {
+        // ldr x1, #+0x10
+        let data = relocate_one(0x58000081, FAR);
+        assert_eq!(words(&data[..8]), [0x58000041, 0x14000003]);
+        assert_eq!(quad(&data, 8), 0x1010);
+        assert_eq!(words(&data[16..]), [0xF9400021]);
+
+        // ldr s0, #+0x10 loads its address into the scratch register.
+        let data = relocate_one(0x1C000080, FAR);
+        assert_eq!(words(&data[..8]), [0x58000051, 0x14000003]);
+        assert_eq!(words(&data[16..]), [0xBD400220]);
+
+        // prfm pldl1keep, #+0x10
+        assert_eq!(words(&relocate_one(0xD8000080, FAR)), [NOP]);
+    }
+
+    #[test]
+    fn relocates_branch_in_block() {
+        // b #+8; nop; nop
+        let buffer = [0x14000002u32, NOP, NOP]
+            .iter()
+            .flat_map(|w| w.to_le_bytes())
+            .collect::<Vec<_>>();
+
+        let data = relocate(&buffer, 0x1000 as _, FAR as _).unwrap();
+        assert_eq!(words(&data), [0x14000002, NOP, NOP]);
+    }
+
+    #[test]
+    fn rejects_misaligned() {
+        assert!(relocate(&[0; 6], 0x1000 as _, 0x2000 as _).is_err());
+        assert!(relocate(&[0; 4], 0x1002 as _, 0x2000 as _).is_err());
+    }
 }
@@ -1,19 +1,73 @@
 // Arch
 
-#[cfg(target_arch = "aarch64")]
-mod a64;
+pub mod a32;
+pub mod a64;
+pub mod x32_64;
 
-#[cfg(target_arch = "aarch64")]
-pub use self::a64::*;
+// Includes
 
-#[cfg(target_arch = "arm")]
-mod a32;
+use crate::core::*;
+
+// Types
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ArchKind {
+    X86,
+    X86_64,
+    A32,
+    A64,
+}
+
+/// Hooking backend for one instruction set.
+/// `from` is the address being patched; on A32 its lowest bit selects Thumb.
+pub trait Arch: Sync {
+    fn kind(&self) -> ArchKind;
+    fn max_insn_size(&self) -> usize;
+    fn jump_data(&self, from: Address, to: Address) -> Vec<u8>;
+    /// Jump through the pointer stored at `slot`, which can be swapped atomically.
+    fn slot_jump_data(&self, from: Address, slot: Address) -> Result<Vec<u8>>;
+    fn backjump_data(&self, from: Address, offset: u8) -> Vec<u8>;
+    fn trap_data(&self, from: Address) -> Vec<u8>;
+    fn overwrite_size(&self, from: Address, buffer: &[u8]) -> usize;
+    fn padding_size(&self, from: Address, buffer: &[u8]) -> usize;
+    fn relocate(&self, buffer: &[u8], from: Address, to: Address) -> Result<Vec<u8>>;
+
+    /// Address of the bytes at `from`, without the state bit.
+    fn code_address(&self, from: Address) -> Address {
+        from
+    }
+
+    /// `at` in the same instruction set as `from`.
+    fn with_state(&self, _from: Address, at: Address) -> Address {
+        at
+    }
+}
+
+// Globals
+
+#[cfg(target_arch = "x86")]
+pub const NATIVE: ArchKind = ArchKind::X86;
+
+#[cfg(target_arch = "x86_64")]
+pub const NATIVE: ArchKind = ArchKind::X86_64;
 
 #[cfg(target_arch = "arm")]
-pub use self::a32::*;
+pub const NATIVE: ArchKind = ArchKind::A32;
+
+#[cfg(target_arch = "aarch64")]
+pub const NATIVE: ArchKind = ArchKind::A64;
+
+// Arch
 
-#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
-mod x32_64;
+pub fn get(kind: ArchKind) -> &'static dyn Arch {
+    match kind {
+        ArchKind::X86 => &x32_64::X86,
+        ArchKind::X86_64 => &x32_64::X86_64,
+        ArchKind::A32 => &a32::A32,
+        ArchKind::A64 => &a64::A64,
+    }
+}
 
-#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
-pub use self::x32_64::*;
+pub fn native() -> &'static dyn Arch {
+    get(NATIVE)
+}
@@ -1,17 +1,28 @@
 // Includes
 
+use super::{Arch, ArchKind};
 use crate::core::*;
 
 use iced_x86::*;
-use lazy_static::*;
 
-// Globals
+// Types
+
+type FrameCode = std::result::Result<(Vec<Instruction>, [usize; 2]), IcedError>;
 
-#[cfg(target_pointer_width = "64")]
-const BITNESS: u32 = 64;
+pub struct X86;
 
-#[cfg(target_pointer_width = "32")]
-const BITNESS: u32 = 32;
+pub struct X86_64;
+
+#[repr(C)]
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum CallConv {
+    Cdecl,
+    Stdcall,
+    Thiscall,
+    Fastcall,
+}
+
+// Globals
 
 const REDIRECT_FLOW: [Code; 30] = [
     Code::Jmp_m1616,
@@ -56,10 +67,39 @@ const PADDINGS: [Code; 7] = [
     Code::Int3,
 ];
 
-lazy_static! {
-    static ref TRAP_DATA: Vec<u8> = single_encoder(&Instruction::with(Code::Ud2));
-    static ref MAX_JUMP_SIZE: usize = get_jump_data(NULLPTR).len();
-}
+const SCRATCH_REGISTERS: [Register; 10] = [
+    Register::RAX,
+    Register::RCX,
+    Register::RDX,
+    Register::RBX,
+    Register::RSI,
+    Register::RDI,
+    Register::R8,
+    Register::R9,
+    Register::R10,
+    Register::R11,
+];
+
+const RED_ZONE_SIZE: u64 = 0x80;
+const RELOCATION_SLACK: i64 = 0x1000;
+const LABEL_BASE_64: u64 = 0xFFFF_FFFF_FFFF_0000;
+const LABEL_BASE_32: u64 = 0xFFFF_0000;
+
+const FRAME_GPRS_64: [Register; 8] = [
+    Register::RAX,
+    Register::RCX,
+    Register::RDX,
+    Register::RSI,
+    Register::RDI,
+    Register::R8,
+    Register::R9,
+    Register::R10,
+];
+
+const FRAME_GPRS_32: [Register; 3] = [Register::EAX, Register::ECX, Register::EDX];
+
+// Room for the next hook and the runtime's own stub data.
+pub const FRAME_STUB_HEADER: usize = 24;
 
 // Helpers
 
@@ -77,30 +117,550 @@ fn is_padding(insn: &Instruction) -> bool {
         .is_some()
 }
 
-fn single_encoder(insn: &Instruction) -> Vec<u8> {
-    let mut encoder = Encoder::new(BITNESS);
+fn single_encoder(bitness: u32, insn: &Instruction) -> Vec<u8> {
+    let mut encoder = Encoder::new(bitness);
     encoder.encode(insn, 0).unwrap();
     encoder.take_buffer()
 }
 
-#[cfg(target_pointer_width = "64")]
-fn add_jump(buffer: &mut Vec<Instruction>, address: u64) {
-    buffer.push(Instruction::with1(Code::Pushq_imm32, address as u32).unwrap());
+fn is_short_only(insn: &Instruction) -> bool {
+    insn.is_loop() || insn.is_loopcc() || insn.is_jcx_short()
+}
+
+fn is_reachable(ip: u64, target: u64) -> bool {
+    let diff = target.wrapping_sub(ip) as i64;
+    diff > i32::MIN as i64 + RELOCATION_SLACK && diff < i32::MAX as i64 - RELOCATION_SLACK
+}
+
+fn uses_stack(insn: &Instruction, factory: &mut InstructionInfoFactory) -> bool {
+    insn.stack_pointer_increment() != 0
+        || factory
+            .info(insn)
+            .used_registers()
+            .iter()
+            .any(|r| r.register().full_register() == Register::RSP)
+}
+
+fn find_scratch(insn: &Instruction, factory: &mut InstructionInfoFactory) -> Option<Register> {
+    let used: Vec<Register> = factory
+        .info(insn)
+        .used_registers()
+        .iter()
+        .map(|r| r.register().full_register())
+        .collect();
+
+    SCRATCH_REGISTERS
+        .iter()
+        .find(|&r| !used.contains(r))
+        .copied()
+}
+
+fn wrap_encoding<T>(r: std::result::Result<T, IcedError>) -> Result<T> {
+    match r {
+        Ok(v) => Ok(v),
+        Err(_) => Err(Error::InvalidData),
+    }
+}
+
+/// Expand a branch that only exists in rel8 form (loop, jrcxz, ...):
+///     loop   take
+///     jmp    skip
+/// take:
+///     jmp    target
+/// skip:
+fn add_short_branch(
+    bitness: u32,
+    code: &mut Vec<Instruction>,
+    insn: &Instruction,
+    labels: &mut u64,
+) -> Result<()> {
+    let take = *labels;
+    *labels += 1;
+
+    // The operand width follows the bitness, a 64-bit label would be truncated on x86.
+    let mut branch = *insn;
+
+    match bitness {
+        64 => branch.set_near_branch64(take),
+        _ => branch.set_near_branch32(take as u32),
+    }
+
+    code.push(branch);
+
+    let mut skip = wrap_encoding(Instruction::with_branch(
+        match bitness {
+            64 => Code::Jmp_rel8_64,
+            _ => Code::Jmp_rel8_32,
+        },
+        insn.next_ip(),
+    ))?;
+    skip.set_ip(0);
+    code.push(skip);
+
+    let mut jump = wrap_encoding(Instruction::with_branch(
+        match bitness {
+            64 => Code::Jmp_rel32_64,
+            _ => Code::Jmp_rel32_32,
+        },
+        insn.near_branch_target(),
+    ))?;
+    jump.set_ip(take);
+    code.push(jump);
+
+    Ok(())
+}
+
+/// Rewrite a RIP-relative operand which cannot be reached from the new location.
+fn add_far_ip_rel(
+    code: &mut Vec<Instruction>,
+    insn: &Instruction,
+    factory: &mut InstructionInfoFactory,
+) -> Result<()> {
+    let target = insn.ip_rel_memory_address();
+    let mut sequence = Vec::new();
+
+    match insn.code() {
+        // lea reg, [rip+x] -> mov reg, imm64
+        Code::Lea_r64_m => {
+            sequence.push(wrap_encoding(Instruction::with2(
+                Code::Mov_r64_imm64,
+                insn.op0_register(),
+                target,
+            ))?);
+        }
+        // call/jmp [rip+x] -> load the slot address into r11, which is neither
+        // an argument nor preserved across calls in either ABI.
+        Code::Call_rm64 | Code::Jmp_rm64 => {
+            sequence.push(wrap_encoding(Instruction::with2(
+                Code::Mov_r64_imm64,
+                Register::R11,
+                target,
+            ))?);
+            sequence.push(wrap_encoding(Instruction::with1(
+                insn.code(),
+                MemoryOperand::with_base(Register::R11),
+            ))?);
+        }
+        // Anything else goes through a scratch register, outside the red zone.
+        _ => {
+            if insn.flow_control() != FlowControl::Next || uses_stack(insn, factory) {
+                return Err(Error::InvalidData);
+            }
+
+            let scratch = match find_scratch(insn, factory) {
+                Some(r) => r,
+                None => return Err(Error::InvalidData),
+            };
+
+            let mut rewritten = *insn;
+            rewritten.set_memory_base(scratch);
+            rewritten.set_memory_displacement64(0);
+            rewritten.set_memory_displ_size(0);
+
+            sequence.push(wrap_encoding(Instruction::with2(
+                Code::Lea_r64_m,
+                Register::RSP,
+                MemoryOperand::with_base_displ(Register::RSP, -(RED_ZONE_SIZE as i64)),
+            ))?);
+            sequence.push(wrap_encoding(Instruction::with1(Code::Push_r64, scratch))?);
+            sequence.push(wrap_encoding(Instruction::with2(
+                Code::Mov_r64_imm64,
+                scratch,
+                target,
+            ))?);
+            sequence.push(rewritten);
+            sequence.push(wrap_encoding(Instruction::with1(Code::Pop_r64, scratch))?);
+            sequence.push(wrap_encoding(Instruction::with2(
+                Code::Lea_r64_m,
+                Register::RSP,
+                MemoryOperand::with_base_displ(Register::RSP, RED_ZONE_SIZE as i64),
+            ))?);
+        }
+    }
+
+    // Keep the original address on the first instruction so in-block branches still land.
+    for (i, s) in sequence.iter_mut().enumerate() {
+        s.set_ip(if i == 0 { insn.ip() } else { 0 });
+    }
+
+    code.append(&mut sequence);
+    Ok(())
+}
+
+fn register_args(conv: CallConv) -> &'static [Register] {
+    match conv {
+        CallConv::Thiscall => &[Register::ECX],
+        CallConv::Fastcall => &[Register::ECX, Register::EDX],
+        _ => &[],
+    }
+}
+
+fn stack_slot(index: usize, pushed: usize) -> MemoryOperand {
+    // Return address, then stack arguments, shifted by what we pushed so far.
+    MemoryOperand::with_base_displ(Register::ESP, (4 + 4 * (index + pushed)) as i64)
+}
+
+fn add_jump(bitness: u32, buffer: &mut Vec<Instruction>, address: u64) {
+    if bitness == 32 {
+        buffer.push(Instruction::with1(Code::Pushd_imm32, address as u32).unwrap());
+        buffer.push(Instruction::with(Code::Retnd));
+        return;
+    }
+
+    // Jump through an inline literal, the red zone below rsp may be live.
     buffer.push(
-        Instruction::with2(
-            Code::Mov_rm32_imm32,
-            MemoryOperand::with_base_displ(Register::RSP, 0x04),
-            (address >> 32) as u32,
+        Instruction::with1(
+            Code::Jmp_rm64,
+            MemoryOperand::with_base_displ(Register::RIP, 0x06),
         )
         .unwrap(),
     );
-    buffer.push(Instruction::with(Code::Retnq));
+    buffer.push(Instruction::with_declare_qword_1(address));
 }
 
-#[cfg(target_pointer_width = "32")]
-fn add_jump(buffer: &mut Vec<Instruction>, target: u64) {
-    buffer.push(Instruction::with1(Code::Pushd_imm32, target as u32).unwrap());
-    buffer.push(Instruction::with(Code::Retnd));
+fn frame_code_64(at: u64, enter: u64, leave: u64, labels: [u64; 2]) -> FrameCode {
+    let save_size = get_frame_save_size(64) as u32;
+    let mut code = Vec::new();
+    let rsp = |displ: usize| MemoryOperand::with_base_displ(Register::RSP, displ as i64);
+
+    // Spill every argument register, so both SysV and Win64 callers are covered.
+    code.push(Instruction::with2(
+        Code::Sub_rm64_imm32,
+        Register::RSP,
+        save_size,
+    )?);
+
+    for (i, &r) in FRAME_GPRS_64.iter().enumerate() {
+        code.push(Instruction::with2(Code::Mov_rm64_r64, rsp(8 * i), r)?);
+    }
+
+    for i in 0..8 {
+        let r = Register::XMM0 + i as u32;
+        code.push(Instruction::with2(
+            Code::Movdqu_xmmm128_xmm,
+            rsp(0x40 + 0x10 * i),
+            r,
+        )?);
+    }
+
+    // enter(saved, cookie), with the arguments set for either ABI.
+    code.push(Instruction::with2(
+        Code::Mov_r64_rm64,
+        Register::RDI,
+        Register::RSP,
+    )?);
+    code.push(Instruction::with2(
+        Code::Mov_r64_rm64,
+        Register::RCX,
+        Register::RSP,
+    )?);
+    code.push(Instruction::with2(Code::Mov_r64_imm64, Register::RSI, at)?);
+    code.push(Instruction::with2(Code::Mov_r64_imm64, Register::RDX, at)?);
+    code.push(Instruction::with2(
+        Code::Sub_rm64_imm32,
+        Register::RSP,
+        0x20,
+    )?);
+    code.push(Instruction::with2(
+        Code::Mov_r64_imm64,
+        Register::RAX,
+        enter,
+    )?);
+    code.push(Instruction::with1(Code::Call_rm64, Register::RAX)?);
+    code.push(Instruction::with2(
+        Code::Add_rm64_imm32,
+        Register::RSP,
+        0x20,
+    )?);
+
+    code.push(Instruction::with2(
+        Code::Mov_r64_rm64,
+        Register::R11,
+        Register::RAX,
+    )?);
+
+    for (i, &r) in FRAME_GPRS_64.iter().enumerate() {
+        code.push(Instruction::with2(Code::Mov_r64_rm64, r, rsp(8 * i))?);
+    }
+
+    for i in 0..8 {
+        let r = Register::XMM0 + i as u32;
+        code.push(Instruction::with2(
+            Code::Movdqu_xmm_xmmm128,
+            r,
+            rsp(0x40 + 0x10 * i),
+        )?);
+    }
+
+    code.push(Instruction::with2(
+        Code::Add_rm64_imm32,
+        Register::RSP,
+        save_size,
+    )?);
+
+    // A null destination means the stub is bypassed.
+    code.push(Instruction::with2(
+        Code::Test_rm64_r64,
+        Register::R11,
+        Register::R11,
+    )?);
+    code.push(Instruction::with_branch(Code::Je_rel32_64, labels[0])?);
+
+    // Return into the stub instead of the caller, then enter the destination.
+    code.push(Instruction::with1(Code::Push_r64, Register::R11)?);
+    code.push(Instruction::with2(
+        Code::Mov_r64_imm64,
+        Register::R11,
+        labels[1],
+    )?);
+    code.push(Instruction::with2(
+        Code::Mov_rm64_r64,
+        rsp(8),
+        Register::R11,
+    )?);
+    code.push(Instruction::with(Code::Retnq));
+
+    let bypass = code.len();
+    code.push(Instruction::with1(
+        Code::Jmp_rm64,
+        MemoryOperand::with_base_displ(Register::RIP, at as i64),
+    )?);
+
+    // Spill the return registers and let leave(rets) hand back the caller.
+    let after = code.len();
+    code.push(Instruction::with2(
+        Code::Sub_rm64_imm32,
+        Register::RSP,
+        0x40,
+    )?);
+    code.push(Instruction::with2(
+        Code::Mov_rm64_r64,
+        rsp(0x00),
+        Register::RAX,
+    )?);
+    code.push(Instruction::with2(
+        Code::Mov_rm64_r64,
+        rsp(0x08),
+        Register::RDX,
+    )?);
+    code.push(Instruction::with2(
+        Code::Movdqu_xmmm128_xmm,
+        rsp(0x10),
+        Register::XMM0,
+    )?);
+    code.push(Instruction::with2(
+        Code::Movdqu_xmmm128_xmm,
+        rsp(0x20),
+        Register::XMM1,
+    )?);
+
+    // rdi is nonvolatile on Win64, the caller still expects its own.
+    code.push(Instruction::with2(
+        Code::Mov_rm64_r64,
+        rsp(0x30),
+        Register::RDI,
+    )?);
+    code.push(Instruction::with2(
+        Code::Mov_r64_rm64,
+        Register::RDI,
+        Register::RSP,
+    )?);
+    code.push(Instruction::with2(
+        Code::Mov_r64_rm64,
+        Register::RCX,
+        Register::RSP,
+    )?);
+    code.push(Instruction::with2(
+        Code::Sub_rm64_imm32,
+        Register::RSP,
+        0x20,
+    )?);
+    code.push(Instruction::with2(
+        Code::Mov_r64_imm64,
+        Register::RAX,
+        leave,
+    )?);
+    code.push(Instruction::with1(Code::Call_rm64, Register::RAX)?);
+    code.push(Instruction::with2(
+        Code::Add_rm64_imm32,
+        Register::RSP,
+        0x20,
+    )?);
+    code.push(Instruction::with2(
+        Code::Mov_r64_rm64,
+        Register::R11,
+        Register::RAX,
+    )?);
+    code.push(Instruction::with2(
+        Code::Mov_r64_rm64,
+        Register::RAX,
+        rsp(0x00),
+    )?);
+    code.push(Instruction::with2(
+        Code::Mov_r64_rm64,
+        Register::RDX,
+        rsp(0x08),
+    )?);
+    code.push(Instruction::with2(
+        Code::Movdqu_xmm_xmmm128,
+        Register::XMM0,
+        rsp(0x10),
+    )?);
+    code.push(Instruction::with2(
+        Code::Movdqu_xmm_xmmm128,
+        Register::XMM1,
+        rsp(0x20),
+    )?);
+    code.push(Instruction::with2(
+        Code::Mov_r64_rm64,
+        Register::RDI,
+        rsp(0x30),
+    )?);
+    code.push(Instruction::with2(
+        Code::Add_rm64_imm32,
+        Register::RSP,
+        0x40,
+    )?);
+    code.push(Instruction::with1(Code::Jmp_rm64, Register::R11)?);
+    Ok((code, [bypass, after]))
+}
+
+fn align_stack_32(code: &mut Vec<Instruction>, args: u32) -> std::result::Result<(), IcedError> {
+    // Calls see a 16-byte aligned stack, ebp survives them and keeps the old esp.
+    code.push(Instruction::with1(Code::Push_r32, Register::EBP)?);
+    code.push(Instruction::with2(
+        Code::Mov_r32_rm32,
+        Register::EBP,
+        Register::ESP,
+    )?);
+    code.push(Instruction::with2(Code::And_rm32_imm8, Register::ESP, -16)?);
+    code.push(Instruction::with2(
+        Code::Sub_rm32_imm8,
+        Register::ESP,
+        (16 - args % 16) % 16,
+    )?);
+    Ok(())
+}
+
+fn restore_stack_32(code: &mut Vec<Instruction>) -> std::result::Result<(), IcedError> {
+    code.push(Instruction::with2(
+        Code::Mov_r32_rm32,
+        Register::ESP,
+        Register::EBP,
+    )?);
+    code.push(Instruction::with1(Code::Pop_r32, Register::EBP)?);
+    Ok(())
+}
+
+fn frame_code_32(at: u64, enter: u64, leave: u64, labels: [u64; 2]) -> FrameCode {
+    let mut code = Vec::new();
+    let esp = |displ: usize| MemoryOperand::with_base_displ(Register::ESP, displ as i64);
+
+    // Spill eax, ecx and edx, which may carry register arguments.
+    for &r in FRAME_GPRS_32.iter().rev() {
+        code.push(Instruction::with1(Code::Push_r32, r)?);
+    }
+
+    // enter(saved, cookie)
+    code.push(Instruction::with2(
+        Code::Mov_r32_rm32,
+        Register::EAX,
+        Register::ESP,
+    )?);
+    align_stack_32(&mut code, 8)?;
+    code.push(Instruction::with1(Code::Pushd_imm32, at as u32)?);
+    code.push(Instruction::with1(Code::Push_r32, Register::EAX)?);
+    code.push(Instruction::with2(
+        Code::Mov_r32_imm32,
+        Register::EAX,
+        enter as u32,
+    )?);
+    code.push(Instruction::with1(Code::Call_rm32, Register::EAX)?);
+    restore_stack_32(&mut code)?;
+
+    // A null destination means the stub is bypassed.
+    code.push(Instruction::with2(
+        Code::Test_rm32_r32,
+        Register::EAX,
+        Register::EAX,
+    )?);
+    code.push(Instruction::with_branch(Code::Je_rel32_32, labels[0])?);
+
+    // Return into the stub instead of the caller, then enter the destination.
+    code.push(Instruction::with2(
+        Code::Mov_rm32_imm32,
+        esp(12),
+        labels[1] as u32,
+    )?);
+    code.push(Instruction::with2(
+        Code::Mov_r32_rm32,
+        Register::ECX,
+        esp(4),
+    )?);
+    code.push(Instruction::with2(
+        Code::Mov_r32_rm32,
+        Register::EDX,
+        esp(8),
+    )?);
+    code.push(Instruction::with2(
+        Code::Mov_rm32_r32,
+        esp(8),
+        Register::EAX,
+    )?);
+    code.push(Instruction::with2(
+        Code::Mov_r32_rm32,
+        Register::EAX,
+        esp(0),
+    )?);
+    code.push(Instruction::with2(
+        Code::Add_rm32_imm32,
+        Register::ESP,
+        8u32,
+    )?);
+    code.push(Instruction::with(Code::Retnd));
+
+    let bypass = code.len();
+
+    for &r in FRAME_GPRS_32.iter() {
+        code.push(Instruction::with1(Code::Pop_r32, r)?);
+    }
+
+    code.push(Instruction::with1(
+        Code::Jmp_rm32,
+        MemoryOperand::with_displ(at, 4),
+    )?);
+
+    // leave(rets) hands back the caller, eax and edx may have been overridden.
+    let after = code.len();
+    code.push(Instruction::with1(Code::Push_r32, Register::EDX)?);
+    code.push(Instruction::with1(Code::Push_r32, Register::EAX)?);
+    code.push(Instruction::with2(
+        Code::Mov_r32_rm32,
+        Register::EAX,
+        Register::ESP,
+    )?);
+    align_stack_32(&mut code, 4)?;
+    code.push(Instruction::with1(Code::Push_r32, Register::EAX)?);
+    code.push(Instruction::with2(
+        Code::Mov_r32_imm32,
+        Register::EAX,
+        leave as u32,
+    )?);
+    code.push(Instruction::with1(Code::Call_rm32, Register::EAX)?);
+    restore_stack_32(&mut code)?;
+    code.push(Instruction::with2(
+        Code::Mov_r32_rm32,
+        Register::EDX,
+        esp(4),
+    )?);
+    code.push(Instruction::with2(
+        Code::Mov_rm32_r32,
+        esp(4),
+        Register::EAX,
+    )?);
+    code.push(Instruction::with1(Code::Pop_r32, Register::EAX)?);
+    code.push(Instruction::with(Code::Retnd));
+    Ok((code, [bypass, after]))
 }
 
 // Arch
@@ -109,18 +669,18 @@ pub const fn max_insn_size() -> usize {
     15
 }
 
-pub fn max_jump_size() -> usize {
-    *MAX_JUMP_SIZE
+pub fn max_jump_size(bitness: u32) -> usize {
+    get_jump_data(bitness, NULLPTR).len()
 }
 
-pub fn get_trap_data() -> Vec<u8> {
-    TRAP_DATA.to_vec()
+pub fn get_trap_data(bitness: u32) -> Vec<u8> {
+    single_encoder(bitness, &Instruction::with(Code::Ud2))
 }
 
-pub fn get_jump_data(target: Address) -> Vec<u8> {
+pub fn get_jump_data(bitness: u32, target: Address) -> Vec<u8> {
     let mut buffer = Vec::new();
-    let mut encoder = Encoder::new(BITNESS);
-    add_jump(&mut buffer, target as _);
+    let mut encoder = Encoder::new(bitness);
+    add_jump(bitness, &mut buffer, target as _);
 
     for insn in buffer {
         encoder.encode(&insn, 0).unwrap();
@@ -129,15 +689,40 @@ pub fn get_jump_data(target: Address) -> Vec<u8> {
     encoder.take_buffer()
 }
 
-pub fn get_backjump_data(offset: u8) -> Vec<u8> {
-    let offset = -(offset as i8);
-    single_encoder(&Instruction::with1(Code::Jmp_rel8_16, offset as i32).unwrap())
+pub fn get_slot_jump_data(bitness: u32, from: Address, slot: Address) -> Result<Vec<u8>> {
+    let operand = match bitness {
+        64 => MemoryOperand::with_base_displ(Register::RIP, slot as i64),
+        _ => MemoryOperand::with_displ(slot as u64, 4),
+    };
+
+    let code = match bitness {
+        64 => Code::Jmp_rm64,
+        _ => Code::Jmp_rm32,
+    };
+
+    let insn = wrap_encoding(Instruction::with1(code, operand))?;
+    let mut encoder = Encoder::new(bitness);
+    wrap_encoding(encoder.encode(&insn, from as _))?;
+    Ok(encoder.take_buffer())
+}
+
+pub fn get_backjump_data(bitness: u32, offset: u8) -> Vec<u8> {
+    let code = match bitness {
+        64 => Code::Jmp_rel8_64,
+        _ => Code::Jmp_rel8_32,
+    };
+
+    let ip = 0x1000u64;
+    let insn = Instruction::with_branch(code, ip - offset as u64).unwrap();
+    let mut encoder = Encoder::new(bitness);
+    encoder.encode(&insn, ip).unwrap();
+    encoder.take_buffer()
 }
 
-pub fn get_overwrite_size(buffer: &[u8]) -> usize {
+pub fn get_overwrite_size(bitness: u32, buffer: &[u8]) -> usize {
     let mut size = 0usize;
     let mut insn = Instruction::new();
-    let mut decoder = Decoder::new(BITNESS, buffer, DecoderOptions::NONE);
+    let mut decoder = Decoder::new(bitness, buffer, DecoderOptions::NONE);
     let mut flow_redirected = false;
 
     while decoder.can_decode() {
@@ -189,9 +774,138 @@ pub fn get_padding_size(buffer: &[u8]) -> usize {
     size
 }
 
-pub fn relocate(buffer: &[u8], to: Address) -> Result<Vec<u8>> {
-    let mut decoder = Decoder::new(BITNESS, buffer, DecoderOptions::NONE);
-    let mut buffer = Vec::new();
+/// Adapter for 32-bit code: entered with the `source` convention, calls `callee`
+/// with the `target` convention and returns with the cleanup `source` expects.
+/// `args` is the number of dword arguments, register ones included.
+pub fn get_thunk_data(
+    source: CallConv,
+    target: CallConv,
+    args: usize,
+    callee: Address,
+) -> Result<Vec<u8>> {
+    let source_regs = register_args(source);
+    let target_regs = register_args(target);
+    let mut code = Vec::new();
+    let mut pushed = 0usize;
+
+    // Push the callee's stack arguments, right to left.
+    for i in (target_regs.len().min(args)..args).rev() {
+        code.push(wrap_encoding(match source_regs.get(i) {
+            Some(&r) => Instruction::with1(Code::Push_r32, r),
+            None => Instruction::with1(Code::Push_rm32, stack_slot(i - source_regs.len(), pushed)),
+        })?);
+
+        pushed += 1;
+    }
+
+    // Load the callee's register arguments which the caller passed on the stack.
+    for (i, &r) in target_regs.iter().enumerate().take(args) {
+        if source_regs.get(i).is_none() {
+            code.push(wrap_encoding(Instruction::with2(
+                Code::Mov_r32_rm32,
+                r,
+                stack_slot(i - source_regs.len(), pushed),
+            ))?);
+        }
+    }
+
+    code.push(wrap_encoding(Instruction::with2(
+        Code::Mov_r32_imm32,
+        Register::EAX,
+        callee as u32,
+    ))?);
+    code.push(wrap_encoding(Instruction::with1(
+        Code::Call_rm32,
+        Register::EAX,
+    ))?);
+
+    if target == CallConv::Cdecl && pushed != 0 {
+        code.push(wrap_encoding(Instruction::with2(
+            Code::Add_rm32_imm32,
+            Register::ESP,
+            (4 * pushed) as u32,
+        ))?);
+    }
+
+    let source_stack = args.saturating_sub(source_regs.len());
+
+    if source != CallConv::Cdecl && source_stack != 0 {
+        code.push(wrap_encoding(Instruction::with1(
+            Code::Retnd_imm16,
+            (4 * source_stack) as u32,
+        ))?);
+    } else {
+        code.push(Instruction::with(Code::Retnd));
+    }
+
+    let mut encoder = Encoder::new(32);
+
+    for insn in code {
+        wrap_encoding(encoder.encode(&insn, 0))?;
+    }
+
+    Ok(encoder.take_buffer())
+}
+
+pub const fn get_frame_save_size(bitness: u32) -> usize {
+    match bitness {
+        64 => 0xC8,
+        _ => 0x0C,
+    }
+}
+
+pub const fn get_frame_rets_size(bitness: u32) -> usize {
+    match bitness {
+        64 => 0x40,
+        _ => 0x08,
+    }
+}
+
+pub fn get_frame_stub_data(
+    bitness: u32,
+    at: Address,
+    enter: Address,
+    leave: Address,
+) -> Result<Vec<u8>> {
+    let build = |labels: [u64; 2]| {
+        wrap_encoding(match bitness {
+            64 => frame_code_64(at as _, enter as _, leave as _, labels),
+            _ => frame_code_32(at as _, enter as _, leave as _, labels),
+        })
+    };
+
+    let encode = |code: Vec<Instruction>| -> Result<(Vec<u8>, Vec<u64>)> {
+        let mut encoder = Encoder::new(bitness);
+        let mut ip = at as u64 + FRAME_STUB_HEADER as u64;
+        let mut ips = Vec::new();
+
+        for insn in code {
+            ips.push(ip);
+            ip += wrap_encoding(encoder.encode(&insn, ip))? as u64;
+        }
+
+        Ok((encoder.take_buffer(), ips))
+    };
+
+    // Every instruction has a fixed size, so a dry run locates the labels.
+    let (code, labels) = build([at as u64; 2])?;
+    let (_, ips) = encode(code)?;
+    let (code, _) = build(labels.map(|i| ips[i]))?;
+    let (mut code, _) = encode(code)?;
+
+    let mut data = vec![0u8; FRAME_STUB_HEADER];
+    data.append(&mut code);
+    Ok(data)
+}
+
+pub fn relocate(bitness: u32, buffer: &[u8], from: Address, to: Address) -> Result<Vec<u8>> {
+    let mut decoder = Decoder::with_ip(bitness, buffer, from as _, DecoderOptions::NONE);
+    let mut factory = InstructionInfoFactory::new();
+    let mut labels = match bitness {
+        64 => LABEL_BASE_64,
+        _ => LABEL_BASE_32,
+    };
+    let mut code = Vec::new();
 
     while decoder.can_decode() {
         let insn = decoder.decode();
@@ -200,13 +914,280 @@ pub fn relocate(buffer: &[u8], to: Address) -> Result<Vec<u8>> {
             return Err(Error::InvalidData);
         }
 
-        buffer.push(insn);
+        if is_short_only(&insn) {
+            add_short_branch(bitness, &mut code, &insn, &mut labels)?;
+        } else if insn.is_ip_rel_memory_operand()
+            && !is_reachable(to as u64, insn.ip_rel_memory_address())
+        {
+            add_far_ip_rel(&mut code, &insn, &mut factory)?;
+        } else {
+            code.push(insn);
+        }
+    }
+
+    let block = InstructionBlock::new(&code, to as _);
+
+    match BlockEncoder::encode(bitness, block, BlockEncoderOptions::NONE) {
+        Ok(result) => Ok(result.code_buffer),
+        Err(_) => Err(Error::InvalidData),
+    }
+}
+
+// Impl
+
+impl Arch for X86 {
+    fn kind(&self) -> ArchKind {
+        ArchKind::X86
+    }
+
+    fn max_insn_size(&self) -> usize {
+        max_insn_size()
+    }
+
+    fn jump_data(&self, _from: Address, to: Address) -> Vec<u8> {
+        get_jump_data(32, to)
+    }
+
+    fn slot_jump_data(&self, from: Address, slot: Address) -> Result<Vec<u8>> {
+        get_slot_jump_data(32, from, slot)
+    }
+
+    fn backjump_data(&self, _from: Address, offset: u8) -> Vec<u8> {
+        get_backjump_data(32, offset)
+    }
+
+    fn trap_data(&self, _from: Address) -> Vec<u8> {
+        get_trap_data(32)
+    }
+
+    fn overwrite_size(&self, _from: Address, buffer: &[u8]) -> usize {
+        get_overwrite_size(32, buffer)
+    }
+
+    fn padding_size(&self, _from: Address, buffer: &[u8]) -> usize {
+        get_padding_size(buffer)
+    }
+
+    fn relocate(&self, buffer: &[u8], from: Address, to: Address) -> Result<Vec<u8>> {
+        relocate(32, buffer, from, to)
+    }
+}
+
+impl Arch for X86_64 {
+    fn kind(&self) -> ArchKind {
+        ArchKind::X86_64
+    }
+
+    fn max_insn_size(&self) -> usize {
+        max_insn_size()
+    }
+
+    fn jump_data(&self, _from: Address, to: Address) -> Vec<u8> {
+        get_jump_data(64, to)
+    }
+
+    fn slot_jump_data(&self, from: Address, slot: Address) -> Result<Vec<u8>> {
+        get_slot_jump_data(64, from, slot)
+    }
+
+    fn backjump_data(&self, _from: Address, offset: u8) -> Vec<u8> {
+        get_backjump_data(64, offset)
+    }
+
+    fn trap_data(&self, _from: Address) -> Vec<u8> {
+        get_trap_data(64)
+    }
+
+    fn overwrite_size(&self, _from: Address, buffer: &[u8]) -> usize {
+        get_overwrite_size(64, buffer)
+    }
+
+    fn padding_size(&self, _from: Address, buffer: &[u8]) -> usize {
+        get_padding_size(buffer)
+    }
+
+    fn relocate(&self, buffer: &[u8], from: Address, to: Address) -> Result<Vec<u8>> {
+        relocate(64, buffer, from, to)
+    }
+}
+
+// Tests
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FAR: u64 = 0x7FFF_0000_0000;
+
+    fn decode(bitness: u32, buffer: &[u8], ip: u64) -> Vec<Instruction> {
+        Decoder::with_ip(bitness, buffer, ip, DecoderOptions::NONE)
+            .into_iter()
+            .collect()
+    }
+
+    fn at(code: &[Instruction], ip: u64) -> &Instruction {
+        code.iter().find(|i| i.ip() == ip).unwrap()
+    }
+
+    fn check_short_branch(bitness: u32, buffer: &[u8], from: u64, to: u64) {
+        let data = relocate(bitness, buffer, from as _, to as _).unwrap();
+        let code = decode(bitness, &data, to);
+
+        // The short branch takes a local jump to its target, else falls through.
+        let branch = &code[0];
+        assert_eq!(branch.code(), decode(bitness, buffer, from)[0].code());
+        assert_eq!(code[1].near_branch_target(), from + 2);
+
+        let take = at(&code, branch.near_branch_target());
+        assert_eq!(take.flow_control(), FlowControl::UnconditionalBranch);
+        assert_eq!(take.near_branch_target(), from + 2 + 0x10);
+    }
+
+    #[test]
+    fn relocates_loop_32() {
+        check_short_branch(32, &[0xE2, 0x10], 0x1000, 0x8000);
+    }
+
+    #[test]
+    fn relocates_short_branch_in_block() {
+        // loop +0x10; nop
+        let data = relocate(64, &[0xE2, 0x10, 0x90], 0x1000 as _, FAR as _).unwrap();
+        let code = decode(64, &data, FAR);
+
+        // Falling through lands on the relocated nop.
+        assert_eq!(at(&code, code[1].near_branch_target()).code(), Code::Nopd);
+    }
+
+    #[test]
+    fn relocates_jcxz_32() {
+        check_short_branch(32, &[0xE3, 0x10], 0x0040_1000, 0x1000_0000);
+    }
+
+    #[test]
+    fn relocates_loop_64() {
+        check_short_branch(64, &[0xE2, 0x10], 0x1000, 0x10_0000);
+    }
+
+    #[test]
+    fn relocates_jrcxz_64() {
+        check_short_branch(64, &[0xE3, 0x10], 0x1000, 0x10_0000);
     }
 
-    let block = InstructionBlock::new(&buffer, to as _);
-    Ok(
-        BlockEncoder::encode(BITNESS, block, BlockEncoderOptions::NONE)
-            .unwrap()
-            .code_buffer,
-    )
+    #[test]
+    fn relocates_near_ip_rel() {
+        // mov rax, [rip+0x1000]
+        let buffer = [0x48, 0x8B, 0x05, 0x00, 0x10, 0x00, 0x00];
+        let data = relocate(64, &buffer, 0x1000 as _, 0x10_0000 as _).unwrap();
+        let code = decode(64, &data, 0x10_0000);
+
+        assert_eq!(code.len(), 1);
+        assert!(code[0].is_ip_rel_memory_operand());
+        assert_eq!(code[0].ip_rel_memory_address(), 0x2007);
+    }
+
+    #[test]
+    fn relocates_far_lea() {
+        // lea rax, [rip+0x1000]
+        let buffer = [0x48, 0x8D, 0x05, 0x00, 0x10, 0x00, 0x00];
+        let data = relocate(64, &buffer, 0x1000 as _, FAR as _).unwrap();
+        let code = decode(64, &data, FAR);
+
+        assert_eq!(code.len(), 1);
+        assert_eq!(code[0].code(), Code::Mov_r64_imm64);
+        assert_eq!(code[0].op0_register(), Register::RAX);
+        assert_eq!(code[0].immediate64(), 0x2007);
+    }
+
+    fn check_far_branch(buffer: &[u8], kind: Code) {
+        let data = relocate(64, buffer, 0x1000 as _, FAR as _).unwrap();
+        let code = decode(64, &data, FAR);
+
+        assert_eq!(code.len(), 2);
+        assert_eq!(code[0].code(), Code::Mov_r64_imm64);
+        assert_eq!(code[0].op0_register(), Register::R11);
+        assert_eq!(code[0].immediate64(), 0x2006);
+        assert_eq!(code[1].code(), kind);
+        assert_eq!(code[1].memory_base(), Register::R11);
+        assert_eq!(code[1].memory_displacement64(), 0);
+    }
+
+    #[test]
+    fn relocates_far_call() {
+        // call [rip+0x1000]
+        check_far_branch(&[0xFF, 0x15, 0x00, 0x10, 0x00, 0x00], Code::Call_rm64);
+    }
+
+    #[test]
+    fn relocates_far_jmp() {
+        // jmp [rip+0x1000]
+        check_far_branch(&[0xFF, 0x25, 0x00, 0x10, 0x00, 0x00], Code::Jmp_rm64);
+    }
+
+    #[test]
+    fn relocates_far_load() {
+        // mov rax, [rip+0x1000]
+        let buffer = [0x48, 0x8B, 0x05, 0x00, 0x10, 0x00, 0x00];
+        let data = relocate(64, &buffer, 0x1000 as _, FAR as _).unwrap();
+        let code = decode(64, &data, FAR);
+
+        let load = code
+            .iter()
+            .find(|i| i.code() == Code::Mov_r64_rm64)
+            .unwrap();
+        let scratch = load.memory_base();
+        assert_ne!(scratch, Register::RAX);
+        assert_eq!(load.op0_register(), Register::RAX);
+
+        // The scratch register is saved below the red zone and restored.
+        assert!(code.iter().any(|i| i.code() == Code::Mov_r64_imm64
+            && i.op0_register() == scratch
+            && i.immediate64() == 0x2007));
+        assert_eq!(code.first().unwrap().code(), Code::Lea_r64_m);
+        assert_eq!(code.last().unwrap().code(), Code::Lea_r64_m);
+    }
+
+    #[test]
+    fn rejects_far_stack_ip_rel() {
+        // push qword [rip+0x1000]
+        let buffer = [0xFF, 0x35, 0x00, 0x10, 0x00, 0x00];
+        assert!(relocate(64, &buffer, 0x1000 as _, FAR as _).is_err());
+    }
+
+    #[test]
+    fn jumps_through_slot() {
+        let data = get_slot_jump_data(64, 0x2008 as _, 0x2000 as _).unwrap();
+        let code = decode(64, &data, 0x2008);
+        assert_eq!(code[0].code(), Code::Jmp_rm64);
+        assert_eq!(code[0].memory_displacement64(), 0x2000);
+
+        let data = get_slot_jump_data(32, 0x2004 as _, 0x2000 as _).unwrap();
+        let code = decode(32, &data, 0x2004);
+        assert_eq!(code[0].code(), Code::Jmp_rm32);
+        assert_eq!(code[0].memory_displacement64(), 0x2000);
+
+        assert!(get_slot_jump_data(64, 0x2000 as _, FAR as _).is_err());
+    }
+
+    #[test]
+    fn frame_stubs_keep_abi() {
+        let data = get_frame_stub_data(32, 0x1000 as _, 0x2000 as _, 0x3000 as _).unwrap();
+        let code = decode(32, &data[FRAME_STUB_HEADER..], 0x1000);
+
+        // Both calls into the runtime run on an aligned stack.
+        let count = |f: &dyn Fn(&Instruction) -> bool| code.iter().filter(|i| f(i)).count();
+        assert_eq!(count(&|i| i.code() == Code::Call_rm32), 2);
+        assert_eq!(
+            count(&|i| i.code() == Code::And_rm32_imm8 && i.immediate8to32() == -16),
+            2
+        );
+
+        let data = get_frame_stub_data(64, 0x1000 as _, 0x2000 as _, 0x3000 as _).unwrap();
+        let code = decode(64, &data[FRAME_STUB_HEADER..], 0x1000);
+
+        // The return path hands rdi back as it found it.
+        let tail = &code[code.len() - 4..];
+        assert!(tail
+            .iter()
+            .any(|i| i.code() == Code::Mov_r64_rm64 && i.op0_register() == Register::RDI));
+    }
 }
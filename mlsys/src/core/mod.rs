@@ -13,6 +13,11 @@ pub enum Error {
     InvalidArgument,
     InvalidData,
     NoMemory,
+    Conflict,
+    Incompatible,
+    Mismatch,
+    Busy,
+    Unsupported,
 }
 
 #[repr(C)]
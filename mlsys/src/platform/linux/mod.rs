@@ -7,15 +7,21 @@ pub mod mappings;
 use crate::core::*;
 
 pub use nix::errno::*;
+pub use nix::fcntl::{fcntl, FcntlArg, SealFlag};
+pub use nix::sys::inotify::*;
+pub use nix::sys::memfd::*;
 pub use nix::sys::mman::*;
 pub use nix::sys::signal::*;
 pub use nix::unistd::*;
 
 pub use nix::libc::{
-    c_int, c_void, dladdr, dlclose, dlerror, dlopen, dlsym, getpid, readlink, siginfo_t,
-    ucontext_t, Dl_info, PATH_MAX, RTLD_GLOBAL, RTLD_LAZY, RTLD_NOLOAD,
+    c_int, c_void, dl_iterate_phdr, dl_phdr_info, dladdr, dlclose, dlerror, dlopen, dlsym, getpid,
+    readlink, siginfo_t, size_t, ucontext_t, Dl_info, PATH_MAX, PT_DYNAMIC, PT_LOAD, PT_NOTE,
+    RTLD_DEFAULT, RTLD_GLOBAL, RTLD_LAZY, RTLD_NOLOAD,
 };
 
+use nix::libc::{syscall, SYS_gettid, SYS_tgkill};
+
 #[cfg(not(target_os = "android"))]
 pub use nix::libc::{dlinfo, RTLD_DI_LINKMAP};
 
@@ -31,6 +37,18 @@ pub use nix::libc::REG_EIP;
 pub struct link_map {
     pub l_addr: *const nix::libc::c_void,
     pub l_name: *const u8,
+    pub l_ld: *const nix::libc::c_void,
+    pub l_next: *mut link_map,
+    pub l_prev: *mut link_map,
+}
+
+#[repr(C)]
+pub struct r_debug {
+    pub r_version: c_int,
+    pub r_map: *mut link_map,
+    pub r_brk: usize,
+    pub r_state: c_int,
+    pub r_ldbase: usize,
 }
 
 pub struct LinuxSigHandler {
@@ -41,6 +59,61 @@ pub struct LinuxSigHandler {
 
 extern "C" {
     fn cxx_flush_cache(beg: *mut nix::libc::c_void, end: *mut nix::libc::c_void);
+    fn _Unwind_Backtrace(
+        trace: unsafe extern "C" fn(*mut c_void, *mut c_void) -> c_int,
+        data: *mut c_void,
+    ) -> c_int;
+    fn _Unwind_GetIP(context: *mut c_void) -> usize;
+}
+
+pub const RT_CONSISTENT: c_int = 0;
+pub const RT_ADD: c_int = 1;
+pub const RT_DELETE: c_int = 2;
+
+const DT_NULL: usize = 0;
+const DT_DEBUG: usize = 21;
+
+const URC_NO_REASON: c_int = 0;
+const URC_NORMAL_STOP: c_int = 4;
+
+unsafe extern "C" fn trace_frame(context: *mut c_void, data: *mut c_void) -> c_int {
+    let f = &mut *(data as *mut &mut dyn FnMut(Address) -> bool);
+
+    match f(_Unwind_GetIP(context) as Address) {
+        true => URC_NO_REASON,
+        false => URC_NORMAL_STOP,
+    }
+}
+
+unsafe extern "C" fn find_dt_debug(
+    info: *mut dl_phdr_info,
+    _size: size_t,
+    data: *mut c_void,
+) -> c_int {
+    let found = &mut *(data as *mut *mut r_debug);
+    let info = &*info;
+
+    for i in 0..info.dlpi_phnum as usize {
+        let phdr = &*info.dlpi_phdr.add(i);
+
+        if phdr.p_type != PT_DYNAMIC {
+            continue;
+        }
+
+        // Entries are a tag and a value, both word sized.
+        let mut entry = (info.dlpi_addr as usize + phdr.p_vaddr as usize) as *const [usize; 2];
+
+        while (*entry)[0] != DT_NULL {
+            if (*entry)[0] == DT_DEBUG {
+                *found = (*entry)[1] as *mut r_debug;
+            }
+
+            entry = entry.add(1);
+        }
+    }
+
+    // The main program comes first, it alone carries the loader's entry.
+    1
 }
 
 // LinuxSigHandler
@@ -77,10 +150,13 @@ pub fn get_empty_dlinfo() -> Dl_info {
     }
 }
 
-// TODO: complete.
 pub unsafe fn wrap_system_error(error: Errno) -> Error {
     match error {
-        _ => panic!("System call failed with error: {:?}", error),
+        Errno::ENOENT | Errno::ESRCH => Error::ItemNotFound,
+        Errno::EACCES | Errno::EPERM | Errno::EFAULT => Error::InvalidAccess,
+        Errno::EINVAL | Errno::EBADF => Error::InvalidArgument,
+        Errno::ENOMEM => Error::NoMemory,
+        _ => Error::InvalidData,
     }
 }
 
@@ -99,3 +175,138 @@ pub unsafe fn get_system_error_wrapped() -> Error {
 pub unsafe fn cacheflush(address: Address, size: usize) {
     cxx_flush_cache(address as _, address.add(size) as _)
 }
+
+/// Locates the loader's debug interface, through its export or the main program's `DT_DEBUG`.
+///
+/// # Safety
+///
+/// The returned structure belongs to the loader, read it only while it reports `RT_CONSISTENT`.
+pub unsafe fn find_debug_state() -> Option<*mut r_debug> {
+    let exported = dlsym(RTLD_DEFAULT, "_r_debug\0".as_ptr() as _) as *mut r_debug;
+
+    if !exported.is_null() {
+        return Some(exported);
+    }
+
+    let mut found: *mut r_debug = std::ptr::null_mut();
+    dl_iterate_phdr(Some(find_dt_debug), &mut found as *mut _ as _);
+
+    match found.is_null() {
+        true => None,
+        false => Some(found),
+    }
+}
+
+/// # Safety
+///
+/// Always safe to call, signal handlers included.
+pub unsafe fn get_thread_id() -> u32 {
+    syscall(SYS_gettid) as u32
+}
+
+/// # Safety
+///
+/// `tid` must be a thread of this process with a handler installed for `signal`, or one that
+/// ignores it. The default action of most signals ends the process.
+pub unsafe fn signal_thread(tid: u32, signal: Signal) -> Result<()> {
+    match syscall(SYS_tgkill, getpid(), tid as c_int, signal as c_int) {
+        0 => Ok(()),
+        _ => Err(get_system_error_wrapped()),
+    }
+}
+
+/// Frames are reported from the innermost one, walking stops when `f` returns false.
+///
+/// # Safety
+///
+/// The unwinder locks and allocates, never call this from a signal handler.
+pub unsafe fn walk_stack<F: FnMut(Address) -> bool>(mut f: F) {
+    let mut f: &mut dyn FnMut(Address) -> bool = &mut f;
+    _Unwind_Backtrace(trace_frame, &mut f as *mut _ as _);
+}
+
+// Yields the pc, link register, frame pointer and stack pointer. x86 keeps no link register.
+#[cfg(target_arch = "x86_64")]
+unsafe fn get_context_frame(context: &ucontext_t) -> (usize, usize, usize, usize) {
+    use nix::libc::{REG_RBP, REG_RSP};
+    let regs = &context.uc_mcontext.gregs;
+
+    (
+        regs[REG_RIP as usize] as usize,
+        0,
+        regs[REG_RBP as usize] as usize,
+        regs[REG_RSP as usize] as usize,
+    )
+}
+
+#[cfg(target_arch = "x86")]
+unsafe fn get_context_frame(context: &ucontext_t) -> (usize, usize, usize, usize) {
+    use nix::libc::{REG_EBP, REG_ESP};
+    let regs = &context.uc_mcontext.gregs;
+
+    (
+        regs[REG_EIP as usize] as usize,
+        0,
+        regs[REG_EBP as usize] as usize,
+        regs[REG_ESP as usize] as usize,
+    )
+}
+
+#[cfg(target_arch = "aarch64")]
+unsafe fn get_context_frame(context: &ucontext_t) -> (usize, usize, usize, usize) {
+    let mcontext = &context.uc_mcontext;
+    (
+        mcontext.pc as _,
+        mcontext.regs[30] as _,
+        mcontext.regs[29] as _,
+        mcontext.sp as _,
+    )
+}
+
+#[cfg(target_arch = "arm")]
+unsafe fn get_context_frame(context: &ucontext_t) -> (usize, usize, usize, usize) {
+    let mcontext = &context.uc_mcontext;
+    (
+        mcontext.arm_pc as _,
+        mcontext.arm_lr as _,
+        mcontext.arm_fp as _,
+        mcontext.arm_sp as _,
+    )
+}
+
+/// Reports the interrupted pc and link register, then follows frame pointers inside the range
+/// of `stacks` holding the interrupted stack pointer. It neither locks nor allocates.
+///
+/// # Safety
+///
+/// `context` must be the `ucontext_t` handed to a signal handler, and every range in `stacks`
+/// must stay mapped and readable while the walk runs.
+pub unsafe fn walk_context<F: FnMut(Address) -> bool>(
+    context: *const c_void,
+    stacks: &[(usize, usize)],
+    mut f: F,
+) {
+    let (pc, lr, mut fp, sp) = self::get_context_frame(&*(context as *const ucontext_t));
+    let word = std::mem::size_of::<usize>();
+
+    if !f(pc as Address) || (lr != 0 && !f(lr as Address)) {
+        return;
+    }
+
+    // Optimized code reuses the frame pointer, only the real stack bounds make it safe to follow.
+    let end = match stacks.iter().find(|&&(begin, end)| sp >= begin && sp < end) {
+        Some(&(_, end)) => end,
+        None => return,
+    };
+
+    while fp >= sp && fp < end && fp % word == 0 && end - fp >= 2 * word {
+        let next = *(fp as *const usize);
+        let ret = *((fp + word) as *const usize);
+
+        if ret == 0 || !f(ret as Address) || next <= fp {
+            return;
+        }
+
+        fp = next;
+    }
+}
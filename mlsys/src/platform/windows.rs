@@ -109,6 +109,10 @@ pub unsafe fn get_system_error_wrapped() -> Error {
     wrap_system_error(get_system_error())
 }
 
+pub unsafe fn get_thread_id() -> u32 {
+    GetCurrentThreadId()
+}
+
 pub unsafe fn get_winapi_hinstance(val: isize) -> HINSTANCE {
     let mut data = HINSTANCE::default();
     data.0 = val;
@@ -0,0 +1,278 @@
+// Includes
+
+use super::hooks::{self, HookInfo, HookKind, HookOptions};
+use crate::process::owners::{self, Resource};
+use crate::types::*;
+use lazy_static::*;
+use mlsys::*;
+
+use std::ffi::CStr;
+use std::os::raw::c_char;
+
+// Types
+
+#[repr(C)]
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub(crate) enum DeferredStatus {
+    Pending,
+    Installed,
+    Failed,
+}
+
+enum DeferredTarget {
+    Offset(usize),
+    Symbol(String),
+}
+
+struct DeferredHook {
+    module: String,
+    target: DeferredTarget,
+    callback: Address,
+    options: HookOptions,
+    status: DeferredStatus,
+    handle: Option<Handle>,
+    address: Option<Address>,
+}
+
+#[derive(Default)]
+struct DeferredQueue {
+    serial: usize,
+    hooks: NoHashMap<usize, DeferredHook>,
+}
+
+// Globals
+
+lazy_static! {
+    static ref QUEUE: Mutex<DeferredQueue> = Mutex::new(DeferredQueue::default());
+}
+
+// Helpers
+
+unsafe impl Send for DeferredQueue {}
+
+unsafe fn find_module(name: &str) -> Option<Handle> {
+    let h = crate::process::get_module(name).ok()?;
+
+    // Lookups take a reference, don't keep the module alive.
+    let _ = crate::process::free_module_internal(h);
+    Some(h)
+}
+
+unsafe fn install(hook: &DeferredHook, h: Handle) -> Result<Address> {
+    let target = match &hook.target {
+        DeferredTarget::Offset(offset) => crate::process::get_module_base(h)?.add(*offset),
+        DeferredTarget::Symbol(sym) => crate::process::get_module_symbol_address(h, sym)?,
+    };
+
+    hooks::enable_hook(target, hook.callback, &hook.options)?;
+    Ok(target)
+}
+
+unsafe fn refresh_hook(hook: &mut DeferredHook) {
+    let module = self::find_module(&hook.module);
+
+    // Our module went away, its code is no longer mapped.
+    if hook.handle.is_some() && hook.handle != module {
+        if let Some(target) = hook.address.take() {
+            hooks::forget_hook(target, hook.callback);
+        }
+
+        hook.handle = None;
+        hook.status = DeferredStatus::Pending;
+    }
+
+    if let (None, Some(h)) = (hook.handle, module) {
+        hook.handle = Some(h);
+
+        match self::install(hook, h) {
+            Ok(target) => {
+                hook.address = Some(target);
+                hook.status = DeferredStatus::Installed;
+            }
+            Err(e) => {
+                crate::log::write(&format!(
+                    "Deferred hook on {} could not be installed: {:?}.",
+                    hook.module, e
+                ));
+
+                hook.status = DeferredStatus::Failed;
+            }
+        }
+    }
+}
+
+unsafe fn queue_hook(
+    module: *const c_char,
+    target: DeferredTarget,
+    callback: Address,
+    options: *const HookOptions,
+) -> Result<usize> {
+    if module.is_null() || callback.is_null() {
+        return Err(Error::InvalidArgument);
+    }
+
+    let module = match CStr::from_ptr(module).to_str() {
+        Ok(s) => s.to_string(),
+        Err(_) => return Err(Error::InvalidArgument),
+    };
+
+    let options = match options.as_ref() {
+        Some(&options) => options,
+        None => HookOptions::new(HookKind::Before),
+    };
+
+    let mut hook = DeferredHook {
+        module,
+        target,
+        callback,
+        options,
+        status: DeferredStatus::Pending,
+        handle: None,
+        address: None,
+    };
+
+    // Pick up the module as soon as the loader maps it.
+    if let Err(e) = crate::process::watch_modules() {
+        crate::log::write(&format!("Module notifications are unavailable: {:?}.", e));
+    }
+
+    self::refresh_hook(&mut hook);
+
+    let queue = &mut *QUEUE.lock();
+    let id = queue.serial;
+    queue.serial += 1;
+    queue.hooks.insert(id, hook);
+    Ok(id)
+}
+
+// Deferred
+
+pub(crate) unsafe fn refresh() {
+    let ids = QUEUE.lock().hooks.keys().copied().collect::<Vec<_>>();
+
+    // Resolving may reenter the loader and us, so the queue is never held meanwhile.
+    for id in ids {
+        let hook = QUEUE.lock().hooks.remove(&id);
+
+        if let Some(mut hook) = hook {
+            self::refresh_hook(&mut hook);
+            QUEUE.lock().hooks.insert(id, hook);
+        }
+    }
+}
+
+pub(crate) unsafe fn cancel(id: usize) -> Result<()> {
+    let hook = QUEUE.lock().hooks.remove(&id).ok_or(Error::ItemNotFound)?;
+
+    if hook.address.is_some() {
+        hooks::disable_hook(hook.callback)?;
+    }
+
+    Ok(())
+}
+
+pub(crate) fn pending_hooks() -> Vec<HookInfo> {
+    QUEUE
+        .lock()
+        .hooks
+        .values()
+        .filter(|hook| hook.address.is_none())
+        .map(|hook| HookInfo::pending(hook.callback, &hook.options))
+        .collect()
+}
+
+pub(crate) fn get_status(id: usize) -> Result<DeferredStatus> {
+    match QUEUE.lock().hooks.get(&id) {
+        Some(hook) => Ok(hook.status),
+        None => Err(Error::ItemNotFound),
+    }
+}
+
+fn get_owner(id: usize) -> Handle {
+    match QUEUE.lock().hooks.get(&id) {
+        Some(hook) => hook.options.owner,
+        None => NULLPTR,
+    }
+}
+
+// Bindings
+
+#[no_mangle]
+unsafe extern "C" fn MLDeferHook(
+    module: *const c_char,
+    offset: usize,
+    hook: Address,
+    options: *const HookOptions,
+    out: *mut usize,
+) -> Error {
+    if out.is_null() {
+        return Error::InvalidArgument;
+    }
+
+    match self::queue_hook(module, DeferredTarget::Offset(offset), hook, options) {
+        Ok(id) => {
+            owners::track(self::get_owner(id), Resource::Deferred(id));
+            *out = id;
+            Error::Success
+        }
+        Err(e) => e,
+    }
+}
+
+#[no_mangle]
+unsafe extern "C" fn MLDeferHookSymbol(
+    module: *const c_char,
+    symbol: *const c_char,
+    hook: Address,
+    options: *const HookOptions,
+    out: *mut usize,
+) -> Error {
+    if symbol.is_null() || out.is_null() {
+        return Error::InvalidArgument;
+    }
+
+    let symbol = match CStr::from_ptr(symbol).to_str() {
+        Ok(s) => s.to_string(),
+        Err(_) => return Error::InvalidArgument,
+    };
+
+    match self::queue_hook(module, DeferredTarget::Symbol(symbol), hook, options) {
+        Ok(id) => {
+            owners::track(self::get_owner(id), Resource::Deferred(id));
+            *out = id;
+            Error::Success
+        }
+        Err(e) => e,
+    }
+}
+
+#[no_mangle]
+unsafe extern "C" fn MLCancelDeferredHook(id: usize) -> Error {
+    match self::cancel(id) {
+        Ok(()) => {
+            owners::untrack(|r| matches!(r, Resource::Deferred(d) if *d == id));
+            Error::Success
+        }
+        Err(e) => e,
+    }
+}
+
+#[no_mangle]
+unsafe extern "C" fn MLGetDeferredHookStatus(id: usize, out: *mut DeferredStatus) -> Error {
+    if out.is_null() {
+        return Error::InvalidArgument;
+    }
+
+    match self::get_status(id) {
+        Ok(status) => {
+            *out = status;
+            Error::Success
+        }
+        Err(e) => e,
+    }
+}
+
+#[no_mangle]
+unsafe extern "C" fn MLRefreshDeferredHooks() {
+    self::refresh()
+}
@@ -1,5 +1,6 @@
 // Includes
 
+use crate::process::owners::{self, Resource};
 use crate::types::*;
 use lazy_static::*;
 use mlsys::*;
@@ -10,11 +11,34 @@ lazy_static! {
     static ref EXTENSIONS: Mutex<NoHashMap<FNV, SyncAddress>> = Mutex::new(NoHashMap::default());
 }
 
+// Extensions
+
+pub(crate) fn get(id: FNV) -> Option<Address> {
+    EXTENSIONS.lock().get(&id).map(|ext| ext.extract())
+}
+
+pub(crate) fn replace(id: FNV, ext: Address) {
+    EXTENSIONS.lock().insert(id, SyncAddress::from(ext));
+}
+
+pub(crate) fn remove(id: FNV) -> Result<()> {
+    match EXTENSIONS.lock().remove(&id) {
+        Some(_) => Ok(()),
+        None => Err(Error::ItemNotFound),
+    }
+}
+
 // Bindings
 
 #[no_mangle]
 unsafe extern "C" fn MLInsertExt(ext: SyncAddress, id: FNV) -> Bool {
-    match EXTENSIONS.lock().insert(id, ext).is_none() {
+    let inserted = EXTENSIONS.lock().insert(id, ext).is_none();
+
+    // The last module to insert the extension owns it.
+    owners::untrack(|r| matches!(r, Resource::Extension(e) if *e == id));
+    owners::track(NULLPTR, Resource::Extension(id));
+
+    match inserted {
         true => Bool::True,
         false => Bool::False,
     }
@@ -22,9 +46,12 @@ unsafe extern "C" fn MLInsertExt(ext: SyncAddress, id: FNV) -> Bool {
 
 #[no_mangle]
 unsafe extern "C" fn MLRemoveExt(id: FNV) -> Bool {
-    match EXTENSIONS.lock().remove(&id).is_some() {
-        true => Bool::True,
-        false => Bool::False,
+    match self::remove(id) {
+        Ok(()) => {
+            owners::untrack(|r| matches!(r, Resource::Extension(e) if *e == id));
+            Bool::True
+        }
+        Err(_) => Bool::False,
     }
 }
 
@@ -0,0 +1,444 @@
+// Includes
+
+use mlsys::*;
+
+use std::cell::{Cell, RefCell};
+use std::sync::atomic::{AtomicBool, AtomicPtr, AtomicU32, Ordering};
+
+// Types
+
+/// `stack` points at `args`, a copy of the first stack words taken on entry. The original
+/// may have popped or reused its arguments by the time the callback runs.
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct PostContext {
+    pub regs: [usize; 8],
+    pub vregs: [[u64; 2]; 8],
+    pub stack: Address,
+    pub ret: [usize; 2],
+    pub vret: [[u64; 2]; 2],
+    pub args: [usize; POST_STACK_WORDS],
+}
+
+pub type PostCallback = unsafe extern "C" fn(*mut PostContext);
+
+#[repr(u8)]
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub(crate) enum StubKind {
+    Guard,
+    Post,
+}
+
+// Lives at the start of the stub, so the dispatcher never takes a lock.
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct StubHeader {
+    next: Address,
+    callback: Address,
+    kind: StubKind,
+    guard: bool,
+}
+
+#[allow(clippy::large_enum_variant)]
+#[derive(Clone, Copy)]
+enum FrameKind {
+    Guard,
+    Post(PostContext),
+}
+
+#[derive(Clone, Copy)]
+struct Frame {
+    sp: usize,
+    ret: usize,
+    stub: Address,
+    callback: Address,
+    kind: FrameKind,
+}
+
+struct ShadowStack {
+    frames: [Frame; MAX_FRAMES],
+    len: usize,
+}
+
+struct Publication {
+    tid: AtomicU32,
+    stack: AtomicPtr<RefCell<ShadowStack>>,
+}
+
+// Slot index plus one, the slot is released when the thread exits.
+struct PublishedSlot(Cell<usize>);
+
+// Globals
+
+pub const POST_STACK_WORDS: usize = 16;
+
+#[cfg(target_pointer_width = "64")]
+const BITNESS: u32 = 64;
+
+#[cfg(target_pointer_width = "32")]
+const BITNESS: u32 = 32;
+
+/// Nested post or guarded hook calls tracked per thread, deeper calls bypass their hook.
+pub const MAX_FRAMES: usize = 64;
+
+const EMPTY_FRAME: Frame = Frame {
+    sp: 0,
+    ret: 0,
+    stub: NULLPTR,
+    callback: NULLPTR,
+    kind: FrameKind::Guard,
+};
+
+/// Live threads that can run post or guarded hooks, threads beyond it bypass them.
+pub const MAX_THREADS: usize = 1024;
+
+const EMPTY_PUBLICATION: Publication = Publication {
+    tid: AtomicU32::new(0),
+    stack: AtomicPtr::new(std::ptr::null_mut()),
+};
+
+const _: () = assert!(std::mem::size_of::<StubHeader>() <= arch::x32_64::FRAME_STUB_HEADER);
+
+static FRAMES_EXHAUSTED: AtomicBool = AtomicBool::new(false);
+static THREADS_EXHAUSTED: AtomicBool = AtomicBool::new(false);
+
+// Signal handlers find shadow stacks here, thread locals may allocate on first access.
+static PUBLISHED: [Publication; MAX_THREADS] = [EMPTY_PUBLICATION; MAX_THREADS];
+
+// Fixed storage, so hooks on the allocator never re-enter a push and the stack is never torn down.
+thread_local! {
+    static SHADOW_STACK: RefCell<ShadowStack> = const {
+        RefCell::new(ShadowStack {
+            frames: [EMPTY_FRAME; MAX_FRAMES],
+            len: 0,
+        })
+    };
+
+    static PUBLISHED_SLOT: PublishedSlot = const { PublishedSlot(Cell::new(0)) };
+}
+
+// Helpers
+
+impl ShadowStack {
+    fn frames(&self) -> &[Frame] {
+        &self.frames[..self.len]
+    }
+
+    fn last(&self) -> Option<&Frame> {
+        self.frames().last()
+    }
+
+    fn pop(&mut self) -> Option<Frame> {
+        let frame = self.last().copied()?;
+        self.len -= 1;
+        Some(frame)
+    }
+
+    fn push(&mut self, frame: Frame) -> bool {
+        if self.len == MAX_FRAMES {
+            return false;
+        }
+
+        self.frames[self.len] = frame;
+        self.len += 1;
+        true
+    }
+}
+
+unsafe fn get_stub_data(stub: Address) -> Option<StubHeader> {
+    let header = std::ptr::read_unaligned(stub as *const StubHeader);
+
+    // A removed stub keeps its code but forwards straight to the next hook.
+    match header.callback.is_null() {
+        true => None,
+        false => Some(header),
+    }
+}
+
+impl Drop for PublishedSlot {
+    fn drop(&mut self) {
+        if let Some(index) = self.0.get().checked_sub(1) {
+            PUBLISHED[index]
+                .stack
+                .store(std::ptr::null_mut(), Ordering::Release);
+            PUBLISHED[index].tid.store(0, Ordering::Release);
+        }
+    }
+}
+
+fn report_once(flag: &AtomicBool, message: impl FnOnce() -> String) {
+    if !flag.swap(true, Ordering::Relaxed) {
+        crate::log::write(&message());
+    }
+}
+
+unsafe fn publish(stack: &RefCell<ShadowStack>) -> bool {
+    let published = PUBLISHED_SLOT.try_with(|slot| {
+        if slot.0.get() != 0 {
+            return true;
+        }
+
+        let tid = platform::get_thread_id();
+
+        for (index, entry) in PUBLISHED.iter().enumerate() {
+            if entry
+                .tid
+                .compare_exchange(0, tid, Ordering::AcqRel, Ordering::Relaxed)
+                .is_ok()
+            {
+                entry
+                    .stack
+                    .store(stack as *const _ as *mut _, Ordering::Release);
+                slot.0.set(index + 1);
+                return true;
+            }
+        }
+
+        false
+    });
+
+    // An exiting thread already gave up its slot.
+    published.unwrap_or(false)
+}
+
+fn find_published(tid: u32) -> Option<&'static RefCell<ShadowStack>> {
+    PUBLISHED
+        .iter()
+        .find(|entry| entry.tid.load(Ordering::Acquire) == tid)
+        .and_then(|entry| unsafe { entry.stack.load(Ordering::Acquire).as_ref() })
+}
+
+fn is_guarded(stack: &[Frame], stub: Address) -> bool {
+    stack
+        .iter()
+        .any(|f| f.stub == stub && matches!(f.kind, FrameKind::Guard))
+}
+
+unsafe fn build_context(saved: *const usize, sp: usize) -> PostContext {
+    let mut context = PostContext {
+        regs: [0; 8],
+        vregs: [[0; 2]; 8],
+        stack: NULLPTR,
+        ret: [0; 2],
+        vret: [[0; 2]; 2],
+        args: [0; POST_STACK_WORDS],
+    };
+
+    // Stack arguments start past the return address.
+    let args = (sp as *const usize).add(1);
+    std::ptr::copy_nonoverlapping(args, context.args.as_mut_ptr(), POST_STACK_WORDS);
+
+    let regs = if BITNESS == 64 { 8 } else { 3 };
+    std::ptr::copy_nonoverlapping(saved, context.regs.as_mut_ptr(), regs);
+
+    if BITNESS == 64 {
+        std::ptr::copy_nonoverlapping(
+            saved.add(8) as *const [u64; 2],
+            context.vregs.as_mut_ptr(),
+            8,
+        );
+    }
+
+    context
+}
+
+unsafe extern "C" fn frame_enter(saved: *const usize, stub: Address) -> Address {
+    let sp = saved as usize + arch::x32_64::get_frame_save_size(BITNESS);
+
+    let data = match self::get_stub_data(stub) {
+        Some(d) => d,
+        None => return NULLPTR,
+    };
+
+    // A busy stack means we interrupted our own bookkeeping, call the original instead.
+    let dest = SHADOW_STACK.try_with(|cell| {
+        let mut stack = cell.try_borrow_mut().ok()?;
+
+        if !self::publish(cell) {
+            self::report_once(&THREADS_EXHAUSTED, || {
+                format!(
+                    "More than {} threads run hooks, the rest skip them.",
+                    MAX_THREADS
+                )
+            });
+            return None;
+        }
+
+        // Frames below us were abandoned through longjmp, chained stubs share our slot.
+        while stack.last().is_some_and(|f| f.sp < sp) {
+            stack.pop();
+        }
+
+        // Re-entered from our own callback, skip the stub.
+        if data.guard && self::is_guarded(stack.frames(), stub) {
+            return None;
+        }
+
+        let (kind, dest) = match data.kind {
+            StubKind::Guard => (FrameKind::Guard, data.callback),
+            StubKind::Post => (FrameKind::Post(self::build_context(saved, sp)), data.next),
+        };
+
+        let frame = Frame {
+            sp,
+            ret: *(sp as *const usize),
+            stub,
+            callback: data.callback,
+            kind,
+        };
+
+        if !stack.push(frame) {
+            self::report_once(&FRAMES_EXHAUSTED, || {
+                format!(
+                    "Hook calls nested deeper than {} frames skip their hook.",
+                    MAX_FRAMES
+                )
+            });
+            return None;
+        }
+
+        Some(dest)
+    });
+
+    dest.ok().flatten().unwrap_or(NULLPTR)
+}
+
+unsafe extern "C" fn frame_leave(rets: *mut usize) -> usize {
+    let sp = rets as usize + arch::x32_64::get_frame_rets_size(BITNESS);
+
+    let frame = SHADOW_STACK.try_with(|stack| {
+        let mut stack = stack.try_borrow_mut().ok()?;
+
+        // Skip deeper frames which never returned, our frame is the outermost one below us.
+        while let [.., outer, inner] = stack.frames() {
+            if outer.sp >= sp || inner.sp >= outer.sp {
+                break;
+            }
+
+            stack.pop();
+        }
+
+        stack.pop()
+    });
+
+    // Only a pushed frame returns here, losing it leaves no caller to return to.
+    let frame = match frame {
+        Ok(Some(f)) => f,
+        _ => std::process::abort(),
+    };
+
+    let mut context = match frame.kind {
+        FrameKind::Post(context) => context,
+        FrameKind::Guard => return frame.ret,
+    };
+
+    // Hand the return value to the callback, it may override it.
+    std::ptr::copy_nonoverlapping(rets, context.ret.as_mut_ptr(), 2);
+    context.stack = context.args.as_mut_ptr() as _;
+
+    if BITNESS == 64 {
+        std::ptr::copy_nonoverlapping(rets.add(2) as *const [u64; 2], context.vret.as_mut_ptr(), 2);
+    }
+
+    if let Some(data) = self::get_stub_data(frame.stub) {
+        // Guard the callback itself, the original already returned.
+        if data.guard {
+            let _ = SHADOW_STACK.try_with(|stack| {
+                if let Ok(mut stack) = stack.try_borrow_mut() {
+                    stack.push(Frame {
+                        sp: rets as usize,
+                        ret: frame.ret,
+                        stub: frame.stub,
+                        callback: data.callback,
+                        kind: FrameKind::Guard,
+                    });
+                }
+            });
+        }
+
+        let callback: PostCallback = std::mem::transmute(data.callback);
+        callback(&mut context);
+
+        if data.guard {
+            let _ = SHADOW_STACK.try_with(|stack| {
+                if let Ok(mut stack) = stack.try_borrow_mut() {
+                    while stack.last().is_some_and(|f| f.sp <= rets as usize) {
+                        stack.pop();
+                    }
+                }
+            });
+        }
+    }
+
+    std::ptr::copy_nonoverlapping(context.ret.as_ptr(), rets, 2);
+
+    if BITNESS == 64 {
+        std::ptr::copy_nonoverlapping(context.vret.as_ptr(), rets.add(2) as *mut [u64; 2], 2);
+    }
+
+    frame.ret
+}
+
+// Frames
+
+pub(crate) unsafe fn create_stub(
+    callback: Address,
+    kind: StubKind,
+    guard: bool,
+) -> Result<Address> {
+    // The stubs spill registers by hand, only x86 is covered for now.
+    if arch::NATIVE != arch::ArchKind::X86 && arch::NATIVE != arch::ArchKind::X86_64 {
+        return Err(Error::InvalidArgument);
+    }
+
+    let header = StubHeader {
+        next: NULLPTR,
+        callback,
+        kind,
+        guard,
+    };
+
+    crate::buffer::trampolines::insert_with(|at| {
+        let mut data =
+            arch::x32_64::get_frame_stub_data(BITNESS, at, frame_enter as _, frame_leave as _)?;
+        std::ptr::write_unaligned(data.as_mut_ptr() as *mut StubHeader, header);
+        Ok(data)
+    })
+}
+
+pub(crate) unsafe fn remove_stub(stub: Address) {
+    // Stubs stay mapped, frames still in flight return through them.
+    let callback = std::ptr::addr_of_mut!((*(stub as *mut StubHeader)).callback);
+    std::ptr::write_unaligned(callback, NULLPTR);
+}
+
+/// Safe to call from a signal handler, it neither locks, allocates nor touches thread locals.
+pub(crate) unsafe fn in_flight<F: FnMut(Address)>(mut f: F) -> bool {
+    // Threads without a published stack never had a frame.
+    let stack = match self::find_published(platform::get_thread_id()) {
+        Some(stack) => stack,
+        None => return true,
+    };
+
+    // Stubs replace the return address, the unwinder can't see past them.
+    match stack.try_borrow() {
+        Ok(stack) => {
+            for frame in stack.frames() {
+                f(frame.ret as Address);
+                f(frame.callback);
+            }
+
+            true
+        }
+        Err(_) => false,
+    }
+}
+
+pub(crate) fn get_entry(stub: Address) -> Address {
+    unsafe { stub.add(arch::x32_64::FRAME_STUB_HEADER) }
+}
+
+pub(crate) unsafe fn set_next(stub: Address, next: Address) {
+    let slot = std::ptr::addr_of_mut!((*(stub as *mut StubHeader)).next);
+    std::ptr::write_unaligned(slot, next);
+}
@@ -1,58 +1,746 @@
 // Includes
 
+use crate::buffer::frames::{self, StubKind};
+use crate::hook::*;
+use crate::process::owners::{self, Resource};
+use crate::types::*;
+use lazy_static::*;
+use mlsys::arch::x32_64::CallConv;
 use mlsys::*;
 
-use crate::types::*;
+use std::sync::atomic::{AtomicUsize, Ordering};
 
 // Types
 
+/// `After` and guarded hooks keep a frame per call, a thread nested deeper than
+/// [`frames::MAX_FRAMES`] of them, or past the first [`frames::MAX_THREADS`] live threads,
+/// calls the original instead.
+#[repr(C)]
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum HookKind {
+    Before,
+    After,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub(crate) struct HookOptions {
+    pub(crate) owner: Handle,
+    pub(crate) kind: HookKind,
+    pub(crate) guard: bool,
+    pub(crate) priority: bool,
+    pub(crate) locking: bool,
+    pub(crate) before: Handle,
+    pub(crate) after: Handle,
+    pub(crate) expected: *const u8,
+    pub(crate) expected_mask: *const u8,
+    pub(crate) expected_size: usize,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+#[allow(unused)]
+pub enum HookMethod {
+    Inline,
+    Backjump,
+    Trap,
+    Mid,
+    Import,
+    Vtable,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct HookInfo {
+    pub target: Address,
+    pub callback: Address,
+    pub owner: Handle,
+    pub method: HookMethod,
+    pub kind: HookKind,
+    pub enabled: bool,
+    pub priority: bool,
+    pub locking: bool,
+    pub trampoline: Address,
+    pub patch_size: usize,
+}
+
+#[derive(Clone, Copy)]
+pub(crate) struct HookConv {
+    pub(crate) target: CallConv,
+    pub(crate) hook: CallConv,
+    pub(crate) args: usize,
+}
+
+#[derive(Clone, Copy)]
+struct HookAdapter {
+    thunk: Address,
+    next: Address,
+    block: Address,
+    slot: Address,
+}
+
+struct ChainHook {
+    callback: Address,
+    entry: Address,
+    stub: Address,
+    adapter: Option<HookAdapter>,
+    serial: usize,
+    options: HookOptions,
+}
+
 struct ChainData {
     trampoline: Address,
+    slot: Address,
     dispatchers: Vec<Address>,
-    hooks: Vec<Address>,
+    hooks: Vec<ChainHook>,
     lock: bool,
+    serial: usize,
+    patch: HookData,
+}
+
+// Globals
+
+lazy_static! {
+    static ref CHAINS: Mutex<NoHashMap<SyncAddress, ChainData>> = Mutex::new(NoHashMap::default());
 }
 
 // Helpers
 
+unsafe impl Send for ChainData {}
+
+impl HookOptions {
+    pub(crate) fn new(kind: HookKind) -> Self {
+        HookOptions {
+            owner: NULLPTR,
+            kind,
+            guard: false,
+            priority: false,
+            locking: false,
+            before: NULLPTR,
+            after: NULLPTR,
+            expected: std::ptr::null(),
+            expected_mask: std::ptr::null(),
+            expected_size: 0,
+        }
+    }
+
+    unsafe fn pattern(&self) -> Option<Pattern<'_>> {
+        if self.expected.is_null() || self.expected_size == 0 {
+            return None;
+        }
+
+        Some(Pattern {
+            bytes: std::slice::from_raw_parts(self.expected, self.expected_size),
+            mask: match self.expected_mask.is_null() {
+                true => None,
+                false => Some(std::slice::from_raw_parts(
+                    self.expected_mask,
+                    self.expected_size,
+                )),
+            },
+        })
+    }
+}
+
+impl ChainData {
+    fn head(&self) -> Address {
+        self.hooks
+            .first()
+            .map_or(self.trampoline, |hook| hook.entry)
+    }
+
+    fn next(&self, index: usize) -> Address {
+        self.hooks
+            .get(index + 1)
+            .map_or(self.trampoline, |hook| hook.entry)
+    }
+
+    fn position(&self, current: Address) -> Option<usize> {
+        self.hooks
+            .iter()
+            .position(|hook| hook.callback == current || hook.entry == current)
+    }
+}
+
+impl HookAdapter {
+    fn blocks(&self) -> Vec<Address> {
+        vec![self.thunk, self.next, self.block]
+    }
+}
+
+impl HookInfo {
+    pub(crate) fn pending(callback: Address, options: &HookOptions) -> Self {
+        HookInfo {
+            target: NULLPTR,
+            callback,
+            owner: options.owner,
+            method: HookMethod::Inline,
+            kind: options.kind,
+            enabled: false,
+            priority: options.priority,
+            locking: options.locking,
+            trampoline: NULLPTR,
+            patch_size: 0,
+        }
+    }
+}
+
+fn to_hook_info(target: Address, chain: &ChainData, hook: &ChainHook) -> HookInfo {
+    HookInfo {
+        target,
+        callback: hook.callback,
+        owner: hook.options.owner,
+        method: match chain.patch.hook_type {
+            HookType::Inline => HookMethod::Inline,
+            HookType::Backjump => HookMethod::Backjump,
+            HookType::Trap => HookMethod::Trap,
+        },
+        kind: hook.options.kind,
+        enabled: true,
+        priority: hook.options.priority,
+        locking: hook.options.locking,
+        trampoline: chain.trampoline,
+        patch_size: chain.patch.original.len(),
+    }
+}
+
+unsafe fn make_hook(
+    callback: Address,
+    adapter: Option<HookAdapter>,
+    options: &HookOptions,
+) -> Result<ChainHook> {
+    // The chain enters adapted hooks through their thunk, but knows them by their callback.
+    let code = adapter.map_or(callback, |adapter| adapter.thunk);
+    let guard = options.guard;
+    let stub = match (options.kind, guard) {
+        (HookKind::Before, false) => NULLPTR,
+        (HookKind::Before, true) => frames::create_stub(code, StubKind::Guard, true)?,
+        (HookKind::After, _) => frames::create_stub(code, StubKind::Post, guard)?,
+    };
+
+    Ok(ChainHook {
+        callback,
+        entry: match stub.is_null() {
+            true => code,
+            false => frames::get_entry(stub),
+        },
+        stub,
+        adapter,
+        serial: 0,
+        options: *options,
+    })
+}
+
+unsafe fn make_adapter(target: Address, callback: Address, conv: &HookConv) -> Result<HookAdapter> {
+    let thunk = crate::hook::create_thunk(callback, conv.target, conv.hook, conv.args)?;
+
+    // The hook calls the rest of the chain in its own convention, through a slot kept linked.
+    let (block, next, slot) = match self::create_entry(target, NULLPTR) {
+        Ok(entry) => entry,
+        Err(e) => {
+            let _ = crate::buffer::trampolines::remove(thunk);
+            return Err(e);
+        }
+    };
+
+    match crate::hook::create_thunk(next, conv.hook, conv.target, conv.args) {
+        Ok(next) => Ok(HookAdapter {
+            thunk,
+            next,
+            block,
+            slot,
+        }),
+        Err(e) => {
+            let _ = crate::buffer::trampolines::remove(thunk);
+            let _ = crate::buffer::trampolines::remove(block);
+            Err(e)
+        }
+    }
+}
+
+unsafe fn drop_hook(hook: &ChainHook) {
+    if !hook.stub.is_null() {
+        frames::remove_stub(hook.stub);
+    }
+}
+
+fn owner_name(owner: Handle) -> String {
+    if owner.is_null() {
+        return String::from("<anonymous>");
+    }
+
+    match unsafe { crate::process::get_module_path(owner) } {
+        Ok(p) => p.display().to_string(),
+        Err(_) => format!("{:p}", owner),
+    }
+}
+
+fn report_conflict(target: Address, first: Handle, second: Handle, reason: &str) -> Error {
+    crate::log::write(&format!(
+        "Hook conflict on {:p}: {}, between {} and {}.",
+        target,
+        reason,
+        self::owner_name(first),
+        self::owner_name(second)
+    ));
+
+    Error::Conflict
+}
+
+unsafe fn verify_chain(target: Address, chain: &ChainData, expected: &Pattern) -> Result<()> {
+    let mut buffer = vec![0u8; expected.bytes.len()];
+    let code = arch::native().code_address(target);
+    crate::memory::copy(buffer.as_mut_ptr() as _, code, buffer.len())?;
+
+    // Compare against the bytes we replaced, not our own patch.
+    for (i, b) in buffer.iter_mut().enumerate() {
+        if let Some(&original) = chain.patch.original.get(chain.patch.offset + i) {
+            *b = original;
+        }
+    }
+
+    match expected.matches(&buffer) {
+        true => Ok(()),
+        false => Err(Error::Mismatch),
+    }
+}
+
+fn find_lock_owner(chain: &ChainData, options: &HookOptions) -> Option<Handle> {
+    // Locking hooks only share a target with hooks of their own module.
+    chain
+        .hooks
+        .iter()
+        .find(|hook| {
+            hook.options.owner != options.owner && (hook.options.locking || options.locking)
+        })
+        .map(|hook| hook.options.owner)
+}
+
+fn precedes(a: &ChainHook, b: &ChainHook) -> bool {
+    (a.options.priority && !b.options.priority)
+        || (!a.options.before.is_null() && a.options.before == b.options.owner)
+        || (!b.options.after.is_null() && b.options.after == a.options.owner)
+}
+
+fn sort_hooks(hooks: &mut Vec<ChainHook>) -> std::result::Result<(), (Handle, Handle)> {
+    let mut pending = std::mem::take(hooks);
+    pending.sort_by_key(|hook| hook.serial);
+
+    while !pending.is_empty() {
+        // Take the oldest hook which no other pending hook has to precede.
+        let ready = (0..pending.len()).find(|&i| {
+            (0..pending.len()).all(|j| i == j || !self::precedes(&pending[j], &pending[i]))
+        });
+
+        match ready {
+            Some(i) => hooks.push(pending.remove(i)),
+            None => {
+                let owners = (1..pending.len())
+                    .find(|&j| self::precedes(&pending[j], &pending[0]))
+                    .map_or((NULLPTR as Handle, NULLPTR as Handle), |j| {
+                        (pending[j].options.owner, pending[0].options.owner)
+                    });
+
+                hooks.append(&mut pending);
+                return Err(owners);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+unsafe fn remove_hook(chain: &mut ChainData, index: usize) -> ChainHook {
+    let hook = chain.hooks.remove(index);
+
+    // Dropping a hook never introduces a cycle.
+    let _ = self::sort_hooks(&mut chain.hooks);
+    chain.lock = chain.hooks.iter().any(|hook| hook.options.locking);
+    hook
+}
+
+unsafe fn link_stubs(chain: &ChainData) {
+    // Stubs continue through their own slot, guards bail out to the original.
+    for (i, hook) in chain.hooks.iter().enumerate() {
+        if !hook.stub.is_null() {
+            let next = match hook.options.kind {
+                HookKind::Before => chain.trampoline,
+                HookKind::After => chain.next(i),
+            };
+
+            frames::set_next(hook.stub, next);
+        }
+
+        // Adapted hooks reach the next hook through their reverse thunk.
+        if let Some(adapter) = &hook.adapter {
+            let slot = &*(adapter.slot as *const AtomicUsize);
+            slot.store(chain.next(i) as usize, Ordering::Release);
+        }
+    }
+}
+
+unsafe fn link_chain(chain: &ChainData) {
+    self::link_stubs(chain);
+
+    // Threads inside the patch only ever see the old or the new head.
+    let slot = &*(chain.slot as *const AtomicUsize);
+    slot.store(chain.head() as usize, Ordering::Release);
+}
+
+/// Aligned slot holding `head`, followed by a jump through it.
+unsafe fn create_entry(target: Address, head: Address) -> Result<(Address, Address, Address)> {
+    let arch = arch::native();
+    let align = std::mem::size_of::<usize>();
+    let mut slot = NULLPTR;
+
+    let block = crate::buffer::trampolines::insert_with(|at| {
+        let padding = (align - at as usize % align) % align;
+        slot = at.add(padding);
+
+        let mut data = vec![0u8; padding];
+        data.extend_from_slice(&(head as usize).to_ne_bytes());
+        data.append(&mut arch.slot_jump_data(arch.with_state(target, slot.add(align)), slot)?);
+        Ok(data)
+    })?;
+
+    Ok((block, arch.with_state(target, slot.add(align)), slot))
+}
+
+unsafe fn create_chain(
+    target: Address,
+    hook: ChainHook,
+    expected: Option<&Pattern>,
+) -> Result<ChainData> {
+    let arch = arch::native();
+
+    // The patch jumps to a fixed entry, relinking only swaps the slot behind it.
+    let (_, entry, slot) = self::create_entry(target, hook.entry)?;
+    let (patch, payload) = prepare_internal(target, entry, expected)?;
+
+    // Traps would need a handler to enter the chain.
+    let covered = match patch.hook_type {
+        HookType::Inline => payload.len(),
+        HookType::Backjump => payload.len() - patch.offset,
+        HookType::Trap => return Err(Error::NoMemory),
+    };
+
+    // Relocate the prolog we are about to overwrite, then jump back.
+    let size = get_prolog_size(target, covered)?;
+    let mut prolog = vec![0u8; size];
+    crate::memory::copy(prolog.as_mut_ptr() as _, arch.code_address(target), size)?;
+
+    // The trampoline runs in the same instruction set as the target.
+    let trampoline = crate::buffer::trampolines::insert_with(|at| {
+        let at = arch.with_state(target, at);
+        let mut data = arch.relocate(&prolog, target, at)?;
+        let mut jump = arch.jump_data(at.add(data.len()), target.add(size));
+        data.append(&mut jump);
+        Ok(data)
+    })?;
+    let trampoline = arch.with_state(target, trampoline);
+
+    let chain = ChainData {
+        trampoline,
+        slot,
+        dispatchers: Vec::new(),
+        lock: hook.options.locking,
+        hooks: vec![hook],
+        serial: 1,
+        patch,
+    };
+
+    // Link the stubs before the patch goes live.
+    self::link_stubs(&chain);
+
+    let at = arch.code_address(target).sub(chain.patch.offset);
+    crate::memory::copy(at, payload.as_ptr() as _, payload.len())?;
+    Ok(chain)
+}
+
+unsafe fn insert_hook(
+    target: Address,
+    callback: Address,
+    adapter: Option<HookAdapter>,
+    options: &HookOptions,
+) -> Result<()> {
+    let chains = &mut *CHAINS.lock();
+    let key = SyncAddress::from(target);
+
+    let expected = options.pattern();
+
+    if let Some(chain) = chains.get(&key) {
+        if chain.position(callback).is_some() {
+            return Err(Error::InvalidArgument);
+        }
+
+        if let Some(expected) = &expected {
+            self::verify_chain(target, chain, expected)?;
+        }
+
+        if let Some(owner) = self::find_lock_owner(chain, options) {
+            let reason = match options.locking {
+                true => "a locking hook needs exclusive ownership",
+                false => "the target is locked",
+            };
+
+            return Err(self::report_conflict(target, owner, options.owner, reason));
+        }
+    }
+
+    let mut hook = self::make_hook(callback, adapter, options)?;
+
+    let chain = match chains.get_mut(&key) {
+        Some(chain) => chain,
+        None => {
+            chains.insert(key, self::create_chain(target, hook, expected.as_ref())?);
+            return Ok(());
+        }
+    };
+
+    hook.serial = chain.serial;
+    chain.serial += 1;
+    chain.hooks.push(hook);
+
+    if let Err((first, second)) = self::sort_hooks(&mut chain.hooks) {
+        let index = chain.position(callback).unwrap();
+        self::drop_hook(&self::remove_hook(chain, index));
+
+        let reason = "ordering constraints form a cycle";
+        return Err(self::report_conflict(target, first, second, reason));
+    }
+
+    chain.lock = chain.hooks.iter().any(|hook| hook.options.locking);
+
+    self::link_chain(chain);
+    Ok(())
+}
+
+// Chains
+
+pub(crate) unsafe fn enable_hook(
+    target: Address,
+    callback: Address,
+    options: &HookOptions,
+) -> Result<()> {
+    self::insert_hook(target, callback, None, options)
+}
+
+/// Returns the adapter blocks, which outlive the hook until its callers are gone.
+pub(crate) unsafe fn enable_hook_with_conv(
+    target: Address,
+    callback: Address,
+    conv: &HookConv,
+    options: &HookOptions,
+) -> Result<Vec<Address>> {
+    if conv.target == conv.hook {
+        return self::enable_hook(target, callback, options).map(|_| Vec::new());
+    }
+
+    let adapter = self::make_adapter(target, callback, conv)?;
+
+    match self::insert_hook(target, callback, Some(adapter), options) {
+        Ok(_) => Ok(adapter.blocks()),
+        Err(e) => {
+            for block in adapter.blocks() {
+                let _ = crate::buffer::trampolines::remove(block);
+            }
+
+            Err(e)
+        }
+    }
+}
+
+unsafe fn enable_tracked_hook(
+    target: Address,
+    callback: Address,
+    options: &HookOptions,
+) -> Result<()> {
+    let mut options = *options;
+
+    // Anonymous hooks belong to their caller, so locking still tells modules apart.
+    if options.owner.is_null() {
+        if let Ok(h) = crate::process::get_caller_base()
+            .and_then(|base| crate::process::get_module_from_address(base))
+        {
+            // Lookups take a reference, don't keep the module alive.
+            let _ = crate::process::free_module_internal(h);
+            options.owner = h;
+        }
+    }
+
+    self::enable_hook(target, callback, &options)?;
+
+    // Hooks installed by the loader are released with their binary instead.
+    owners::track(options.owner, Resource::Hook(callback));
+    Ok(())
+}
+
+pub(crate) unsafe fn disable_hook(callback: Address) -> Result<()> {
+    let chains = &mut *CHAINS.lock();
+
+    let (key, index) = chains
+        .iter()
+        .find_map(|(key, chain)| chain.position(callback).map(|i| (*key, i)))
+        .ok_or(Error::InvalidArgument)?;
+
+    let target = key.extract();
+    let chain = chains.get_mut(&key).unwrap();
+    let hook = self::remove_hook(chain, index);
+
+    if chain.hooks.is_empty() {
+        restore_internal(target, &chain.patch)?;
+        chains.remove(&key);
+    } else {
+        self::link_chain(chain);
+    }
+
+    self::drop_hook(&hook);
+    Ok(())
+}
+
+pub(crate) unsafe fn forget_hook(target: Address, callback: Address) {
+    let chains = &mut *CHAINS.lock();
+    let key = SyncAddress::from(target);
+
+    let chain = match chains.get_mut(&key) {
+        Some(chain) => chain,
+        None => return,
+    };
+
+    // The target is gone, only relink what is still mapped.
+    if let Some(index) = chain.position(callback) {
+        let hook = self::remove_hook(chain, index);
+
+        if chain.hooks.is_empty() {
+            chains.remove(&key);
+        } else {
+            self::link_chain(chain);
+        }
+
+        self::drop_hook(&hook);
+    }
+}
+
+pub(crate) fn get_next_hook(target: Address, current: Address) -> Address {
+    match CHAINS.lock().get(&SyncAddress::from(target)) {
+        Some(chain) => match chain.position(current) {
+            Some(i) => match &chain.hooks[i].adapter {
+                Some(adapter) => adapter.next,
+                None => chain.next(i),
+            },
+            None => NULLPTR,
+        },
+        None => NULLPTR,
+    }
+}
+
+pub(crate) fn enumerate_hooks(owner: Handle, target: Address) -> Vec<HookInfo> {
+    let mut infos = CHAINS
+        .lock()
+        .iter()
+        .filter(|(key, _)| target.is_null() || key.extract() == target)
+        .flat_map(|(key, chain)| {
+            chain
+                .hooks
+                .iter()
+                .map(move |hook| self::to_hook_info(key.extract(), chain, hook))
+        })
+        .collect::<Vec<_>>();
+
+    // Deferred hooks waiting for their module have no target yet.
+    if target.is_null() {
+        infos.extend(crate::buffer::deferred::pending_hooks());
+    }
+
+    infos.retain(|info| owner.is_null() || info.owner == owner);
+    infos
+}
+
 // Bindings
 
 #[no_mangle]
 unsafe extern "C" fn MLEnableHook(target: Address, hook: Address) -> Bool {
-    todo!("Unimplemented!")
+    match self::enable_tracked_hook(target, hook, &HookOptions::new(HookKind::Before)) {
+        Ok(_) => Bool::True,
+        Err(_) => Bool::False,
+    }
 }
 
 #[no_mangle]
-unsafe extern "C" fn MLDisableHook(hook: Address) -> Bool {
-    todo!("Unimplemented!")
+unsafe extern "C" fn MLEnablePostHook(target: Address, hook: Address) -> Bool {
+    match self::enable_tracked_hook(target, hook, &HookOptions::new(HookKind::After)) {
+        Ok(_) => Bool::True,
+        Err(_) => Bool::False,
+    }
 }
 
 #[no_mangle]
-unsafe extern "C" fn MLEnumerateHooks(owner: Handle) -> Address {
-    todo!("UN")
+unsafe extern "C" fn MLEnableHookEx(
+    target: Address,
+    hook: Address,
+    options: *const HookOptions,
+) -> Error {
+    if let Some(options) = options.as_ref() {
+        return match self::enable_tracked_hook(target, hook, options) {
+            Ok(_) => Error::Success,
+            Err(e) => e,
+        };
+    }
+
+    Error::InvalidArgument
 }
 
 #[no_mangle]
-unsafe extern "C" fn MLInitRecord(handle: Handle, id: RawString) -> Bool {
-    todo!("Unimplemented!")
+unsafe extern "C" fn MLDisableHook(hook: Address) -> Bool {
+    match self::disable_hook(hook) {
+        Ok(_) => {
+            owners::untrack(|r| matches!(r, Resource::Hook(h) if *h == hook));
+            Bool::True
+        }
+        Err(_) => Bool::False,
+    }
 }
 
 #[no_mangle]
-unsafe extern "C" fn MLCleanupRecord(handle: Handle, id: RawString) -> Bool {
-    todo!("Unimplemented!")
+unsafe extern "C" fn MLEnumerateHooks(
+    owner: Handle,
+    target: Address,
+    out: *mut HookInfo,
+    capacity: usize,
+    count: *mut usize,
+) -> Error {
+    if count.is_null() || (out.is_null() && capacity != 0) {
+        return Error::InvalidArgument;
+    }
+
+    // A null owner or target matches every hook.
+    let infos = self::enumerate_hooks(owner, target);
+    *count = infos.len();
+
+    for (i, info) in infos.into_iter().take(capacity).enumerate() {
+        out.add(i).write(info);
+    }
+
+    Error::Success
 }
 
 #[no_mangle]
 unsafe extern "C" fn MLGetHookSize(target: Address) -> usize {
-    todo!("Unimplemented!")
+    match CHAINS.lock().get(&SyncAddress::from(target)) {
+        Some(chain) => chain.patch.original.len(),
+        None => 0,
+    }
 }
 
 #[no_mangle]
-unsafe extern "C" fn MLGetFirstChainHook(_base: Address) -> Address {
-    todo!("Unimplemented!")
+unsafe extern "C" fn MLGetFirstChainHook(base: Address) -> Address {
+    match CHAINS.lock().get(&SyncAddress::from(base)) {
+        Some(chain) => chain.head(),
+        None => NULLPTR,
+    }
 }
 
 #[no_mangle]
-unsafe extern "C" fn MLGetNextChainHook(_base: Address, _current: Address) -> Address {
-    todo!("Unimplemented!")
+unsafe extern "C" fn MLGetNextChainHook(base: Address, current: Address) -> Address {
+    self::get_next_hook(base, current)
 }
@@ -1,3 +1,5 @@
-mod ext_data;
-mod hooks;
-mod trampolines;
+pub(crate) mod deferred;
+pub(crate) mod ext_data;
+pub(crate) mod frames;
+pub(crate) mod hooks;
+pub(crate) mod trampolines;
@@ -9,6 +9,8 @@ use mlsys::*;
 struct Buffer {
     base: SyncAddress,
     size: usize,
+    blocks: NoHashMap<SyncAddress, usize>,
+    free: Vec<(Address, usize)>,
 }
 
 // Globals
@@ -20,10 +22,16 @@ lazy_static! {
         base: unsafe {
             SyncAddress::from(crate::memory::allocate(BUFFER_SIZE, MEM_XRW, ALLOC_NO_HINT).unwrap())
         },
-        size: 0
+        size: 0,
+        blocks: NoHashMap::default(),
+        free: Vec::new(),
     });
 }
 
+// Helpers
+
+unsafe impl Send for Buffer {}
+
 // Trampoline
 
 #[allow(unused)]
@@ -32,16 +40,63 @@ pub(crate) unsafe fn get_pointer() -> Result<Address> {
     Ok(buffer.base.extract().add(buffer.size))
 }
 
-#[allow(unused)]
-pub(crate) unsafe fn insert_data(data: &[u8]) -> Result<Address> {
-    let mut buffer = BUFFER.lock();
+pub(crate) unsafe fn insert_with<F>(f: F) -> Result<Address>
+where
+    F: FnOnce(Address) -> Result<Vec<u8>>,
+{
+    let buffer = &mut *BUFFER.lock();
     let addr = buffer.base.extract().add(buffer.size);
+    let data = f(addr)?;
 
-    if buffer.size >= data.len() {
-        crate::memory::copy_unchecked(Address::from(addr), data.as_ptr() as _, data.len());
+    if BUFFER_SIZE - buffer.size >= data.len() {
+        crate::memory::copy_unchecked(addr, data.as_ptr() as _, data.len());
         buffer.size += data.len();
-        return Ok(Address::from(addr));
+        buffer.blocks.insert(SyncAddress::from(addr), data.len());
+        return Ok(addr);
     }
 
     Err(Error::NoMemory)
 }
+
+#[allow(unused)]
+pub(crate) unsafe fn insert_data(data: &[u8]) -> Result<Address> {
+    let mut buffer = BUFFER.lock();
+
+    // Reuse a removed block before growing the buffer.
+    let addr = match buffer.free.iter().position(|&(_, size)| size >= data.len()) {
+        Some(i) => buffer.free.remove(i).0,
+        None if BUFFER_SIZE - buffer.size >= data.len() => {
+            let addr = buffer.base.extract().add(buffer.size);
+            buffer.size += data.len();
+            addr
+        }
+        None => return Err(Error::NoMemory),
+    };
+
+    crate::memory::copy_unchecked(addr, data.as_ptr() as _, data.len());
+    buffer.blocks.insert(SyncAddress::from(addr), data.len());
+    Ok(addr)
+}
+
+pub(crate) unsafe fn remove(address: Address) -> Result<()> {
+    let buffer = &mut *BUFFER.lock();
+
+    let size = buffer
+        .blocks
+        .remove(&SyncAddress::from(address))
+        .ok_or(Error::ItemNotFound)?;
+
+    // Stale callers trap instead of jumping to unmapped code.
+    let trap = arch::native().trap_data(address);
+    crate::memory::copy_unchecked(address, trap.as_ptr() as _, trap.len().min(size));
+    buffer.free.push((address, size));
+    Ok(())
+}
+
+pub(crate) fn get_block_size(address: Address) -> Option<usize> {
+    BUFFER
+        .lock()
+        .blocks
+        .get(&SyncAddress::from(address))
+        .copied()
+}
@@ -1,5 +1,7 @@
 // Includes
 
+use crate::process::owners::{self, Resource};
+use mlsys::arch::x32_64::CallConv;
 use mlsys::*;
 
 // Types
@@ -10,11 +12,16 @@ pub(crate) enum HookType {
     Trap,
 }
 
-#[allow(unused)]
 pub(crate) struct HookData {
-    hook_type: HookType,
-    offset: usize,
-    original: Vec<u8>,
+    pub(crate) hook_type: HookType,
+    pub(crate) offset: usize,
+    pub(crate) original: Vec<u8>,
+}
+
+#[derive(Clone, Copy)]
+pub(crate) struct Pattern<'a> {
+    pub(crate) bytes: &'a [u8],
+    pub(crate) mask: Option<&'a [u8]>,
 }
 
 // Helpers
@@ -27,33 +34,90 @@ fn build_hook_data(hook_type: HookType, offset: usize, original: &[u8]) -> HookD
     }
 }
 
+impl Pattern<'_> {
+    pub(crate) fn matches(&self, live: &[u8]) -> bool {
+        live.len() >= self.bytes.len()
+            && self.bytes.iter().enumerate().all(|(i, &b)| {
+                let m = self
+                    .mask
+                    .map_or(0xFF, |mask| mask.get(i).copied().unwrap_or(0xFF));
+                live[i] & m == b & m
+            })
+    }
+}
+
 // Hook
 
-#[allow(unused)]
-pub(crate) unsafe fn place_internal(from: Address, to: Address) -> Result<HookData> {
+pub(crate) unsafe fn verify_internal(from: Address, expected: &Pattern) -> Result<()> {
+    let mut buffer = vec![0u8; expected.bytes.len()];
+    let code = arch::native().code_address(from);
+    crate::memory::copy(buffer.as_mut_ptr() as _, code, buffer.len())?;
+
+    // Someone else already patched the target.
+    match expected.matches(&buffer) {
+        true => Ok(()),
+        false => Err(Error::Mismatch),
+    }
+}
+
+pub(crate) unsafe fn get_prolog_size(from: Address, min: usize) -> Result<usize> {
+    let arch = arch::native();
+    let mut buffer = vec![0u8; min + arch.max_insn_size()];
+    let code = arch.code_address(from);
+    crate::memory::copy(buffer.as_mut_ptr() as _, code, buffer.len())?;
+
+    // Find the first instruction boundary covering the requested bytes.
+    for size in min..=buffer.len() {
+        if arch.overwrite_size(from, &buffer[..size]) == size {
+            return Ok(size);
+        }
+    }
+
+    Err(Error::InvalidData)
+}
+
+pub(crate) unsafe fn restore_internal(from: Address, data: &HookData) -> Result<()> {
+    crate::memory::copy(
+        arch::native().code_address(from).sub(data.offset),
+        data.original.as_ptr() as _,
+        data.original.len(),
+    )
+}
+
+pub(crate) unsafe fn prepare_internal(
+    from: Address,
+    to: Address,
+    expected: Option<&Pattern>,
+) -> Result<(HookData, Vec<u8>)> {
+    let arch = arch::native();
+
+    // The state bit only selects the encoding, memory is accessed at the code address.
+    let code = arch.code_address(from);
+
+    if let Some(expected) = expected {
+        self::verify_internal(from, expected)?;
+    }
+
     // Get inline hook data.
-    let mut inline_data = arch::get_jump_data(to);
+    let mut inline_data = arch.jump_data(from, to);
 
     // Read prolog data.
-    let mut buffer = vec![0u8; inline_data.len() + arch::max_insn_size()];
-    crate::memory::copy(buffer.as_mut_ptr() as _, from, buffer.len())?;
+    let mut buffer = vec![0u8; inline_data.len() + arch.max_insn_size()];
+    crate::memory::copy(buffer.as_mut_ptr() as _, code, buffer.len())?;
 
     // Get max bytes we can overwrite.
-    let prolog_max = arch::get_overwrite_size(&buffer);
+    let prolog_max = arch.overwrite_size(from, &buffer);
 
     // Do we have enough space for the inline hook?
     if inline_data.len() <= prolog_max {
         // Save original bytes.
         buffer.resize(inline_data.len(), 0u8);
-        crate::memory::copy(buffer.as_mut_ptr() as _, from, buffer.len())?;
-
-        // Overwrite the prolog.
-        crate::memory::copy(from, inline_data.as_ptr() as _, inline_data.len())?;
-        return Ok(build_hook_data(HookType::Inline, 0, &buffer));
+        let data = build_hook_data(HookType::Inline, 0, &buffer);
+        return Ok((data, inline_data));
     }
 
     // Attempt backjumping.
-    let mut backjump_data = arch::get_backjump_data(inline_data.len() as u8);
+    let mut backjump_data = arch.backjump_data(from, inline_data.len() as u8);
 
     // Do we have enough space for backjumping?
     if backjump_data.len() <= prolog_max {
@@ -61,68 +125,175 @@ pub(crate) unsafe fn place_internal(from: Address, to: Address) -> Result<HookDa
         buffer.resize(buffer.len(), 0);
         crate::memory::copy(
             buffer.as_mut_ptr() as _,
-            from.sub(inline_data.len()),
+            code.sub(inline_data.len()),
             buffer.len(),
         )?;
 
         // Get max upper bytes we can overwrite.
-        let padding_max = arch::get_padding_size(&buffer);
+        let padding_max = arch.padding_size(from.sub(inline_data.len()), &buffer);
+
+        // Relative jumps are encoded for the address they are placed at.
+        let upper_data = arch.jump_data(from.sub(inline_data.len()), to);
 
         // Can we abuse upper paddings?
-        if inline_data.len() <= padding_max {
+        if inline_data.len() <= padding_max && upper_data.len() == inline_data.len() {
             // Prepare payload.
             let backsize = inline_data.len();
+            inline_data = upper_data;
             inline_data.append(&mut backjump_data);
 
             // Save original bytes.
             buffer.resize(inline_data.len(), 0u8);
-            crate::memory::copy(buffer.as_mut_ptr() as _, from.sub(backsize), buffer.len())?;
-
-            // Overwrite the paddings and the prolog.
-            crate::memory::copy(
-                from.sub(backsize),
-                inline_data.as_ptr() as _,
-                inline_data.len(),
-            )?;
+            crate::memory::copy(buffer.as_mut_ptr() as _, code.sub(backsize), buffer.len())?;
 
-            return Ok(build_hook_data(HookType::Backjump, backsize, &buffer));
+            let data = build_hook_data(HookType::Backjump, backsize, &buffer);
+            return Ok((data, inline_data));
         }
     }
 
     // We have to rely on a trap.
-    let trap_data = arch::get_trap_data();
+    let trap_data = arch.trap_data(from);
 
     if trap_data.len() <= prolog_max {
         // Save original bytes.
         buffer.resize(trap_data.len(), 0u8);
-        crate::memory::copy(buffer.as_mut_ptr() as _, from, buffer.len())?;
-
-        // Overwrite the prolog.
-        crate::memory::copy(from, trap_data.as_ptr() as _, trap_data.len())?;
-        return Ok(build_hook_data(HookType::Trap, 0, &buffer));
+        let data = build_hook_data(HookType::Trap, 0, &buffer);
+        return Ok((data, trap_data));
     }
 
     // We cant hook the address.
     Err(Error::NoMemory)
 }
 
+#[allow(unused)]
+pub(crate) unsafe fn place_internal(
+    from: Address,
+    to: Address,
+    expected: Option<&Pattern>,
+) -> Result<HookData> {
+    let (data, payload) = self::prepare_internal(from, to, expected)?;
+
+    // Overwrite the prolog, and the paddings when backjumping.
+    let code = arch::native().code_address(from);
+    crate::memory::copy(code.sub(data.offset), payload.as_ptr() as _, payload.len())?;
+    Ok(data)
+}
+
 pub unsafe fn place(from: Address, to: Address) -> Result<usize> {
-    let buffer = arch::get_jump_data(to);
-    crate::memory::copy(from, buffer.as_ptr() as _, buffer.len())?;
+    let arch = arch::native();
+    let buffer = arch.jump_data(from, to);
+    crate::memory::copy(arch.code_address(from), buffer.as_ptr() as _, buffer.len())?;
     Ok(buffer.len())
 }
 
+pub unsafe fn create_thunk(
+    callee: Address,
+    source: CallConv,
+    target: CallConv,
+    args: usize,
+) -> Result<Address> {
+    if source == target {
+        return Ok(callee);
+    }
+
+    // Conventions only differ on 32-bit x86.
+    if arch::NATIVE != arch::ArchKind::X86 {
+        return Err(Error::Unsupported);
+    }
+
+    let data = arch::x32_64::get_thunk_data(source, target, args, callee)?;
+    crate::buffer::trampolines::insert_data(&data)
+}
+
+pub unsafe fn place_with_conv(
+    from: Address,
+    to: Address,
+    source: CallConv,
+    target: CallConv,
+    args: usize,
+) -> Result<usize> {
+    self::place(from, self::create_thunk(to, source, target, args)?)
+}
+
+unsafe fn place_tracked(from: Address, to: Address) -> Result<usize> {
+    let arch = arch::native();
+    let mut original = vec![0u8; arch.jump_data(from, to).len()];
+    crate::memory::copy(
+        original.as_mut_ptr() as _,
+        arch.code_address(from),
+        original.len(),
+    )?;
+
+    self::place(from, to)?;
+    let size = original.len();
+    owners::track(NULLPTR, Resource::Patch(arch.code_address(from), original));
+    Ok(size)
+}
+
+unsafe fn create_tracked_thunk(
+    callee: Address,
+    source: CallConv,
+    target: CallConv,
+    args: usize,
+) -> Result<Address> {
+    let thunk = self::create_thunk(callee, source, target, args)?;
+
+    // Matching conventions call the callee directly, there is nothing to free.
+    if thunk != callee {
+        owners::track(NULLPTR, Resource::Thunk(thunk));
+    }
+
+    Ok(thunk)
+}
+
 // Bindings
 
 #[no_mangle]
 unsafe extern "C" fn MLHookSize(to: Address) -> usize {
-    arch::get_jump_data(to).len()
+    arch::native().jump_data(NULLPTR, to).len()
 }
 
 #[no_mangle]
 unsafe extern "C" fn MLPlaceHook(from: Address, to: Address) -> Error {
-    match self::place(from, to) {
+    match self::place_tracked(from, to) {
+        Ok(_) => Error::Success,
+        Err(e) => e,
+    }
+}
+
+#[no_mangle]
+unsafe extern "C" fn MLPlaceHookConv(
+    from: Address,
+    to: Address,
+    source: CallConv,
+    target: CallConv,
+    args: usize,
+) -> Error {
+    match self::create_tracked_thunk(to, source, target, args)
+        .and_then(|thunk| self::place_tracked(from, thunk))
+    {
         Ok(_) => Error::Success,
         Err(e) => e,
     }
 }
+
+#[no_mangle]
+unsafe extern "C" fn MLCreateThunk(
+    callee: Address,
+    source: CallConv,
+    target: CallConv,
+    args: usize,
+    out: *mut Address,
+) -> Error {
+    if out.is_null() {
+        return Error::InvalidArgument;
+    }
+
+    match self::create_tracked_thunk(callee, source, target, args) {
+        Ok(thunk) => {
+            *out = thunk;
+            Error::Success
+        }
+        Err(e) => e,
+    }
+}
@@ -0,0 +1,657 @@
+// Records
+
+mod records;
+
+// Includes
+
+use crate::buffer::hooks::{self, HookConv, HookKind, HookOptions};
+use crate::types::*;
+use lazy_static::*;
+use mlsys::arch::x32_64::CallConv;
+use mlsys::*;
+
+use self::records::RecordData;
+use mldl::{DynamicEntry, FingerprintEntry, HookEntry, HookTable, HookTarget, MLMetadata};
+use std::path::{Path, PathBuf};
+
+// Types
+
+struct Binding {
+    slot: Address,
+    sym: String,
+    fallback: Address,
+    provider: Option<Address>,
+}
+
+struct InstalledHook {
+    callback: Address,
+    thunks: Vec<Address>,
+}
+
+struct BinaryData {
+    handle: Handle,
+    path: PathBuf,
+    mid: String,
+    refs: usize,
+    hooks: Vec<InstalledHook>,
+    bindings: Vec<Binding>,
+    records: Vec<RecordData>,
+}
+
+pub(crate) struct BinaryState {
+    pub(crate) path: PathBuf,
+    pub(crate) mid: String,
+    pub(crate) refs: usize,
+    pub(crate) records: Vec<(String, usize)>,
+}
+
+type Binaries = NoHashMap<SyncAddress, BinaryData>;
+
+// Globals
+
+lazy_static! {
+    static ref BINARIES: Mutex<Binaries> = Mutex::new(NoHashMap::default());
+}
+
+// Helpers
+
+unsafe impl Send for BinaryData {}
+
+fn wrap_conv(conv: mldl::CallConv) -> CallConv {
+    match conv {
+        mldl::CallConv::Cdecl => CallConv::Cdecl,
+        mldl::CallConv::Stdcall => CallConv::Stdcall,
+        mldl::CallConv::Thiscall => CallConv::Thiscall,
+        mldl::CallConv::Fastcall => CallConv::Fastcall,
+    }
+}
+
+fn describe_module(module: &str) -> &str {
+    match module.is_empty() {
+        true => "the game executable",
+        false => module,
+    }
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+unsafe fn find_segments(base: Address) -> Vec<mldl::ImageSegment> {
+    // Only what the loader mapped is read, the gaps between segments may not be.
+    crate::process::enumerate_mapped()
+        .find(|m| m.base == base)
+        .map(|m| {
+            m.segments
+                .into_iter()
+                .map(|s| mldl::ImageSegment {
+                    address: s.address as usize,
+                    size: s.size,
+                    offset: s.offset,
+                    readable: (s.mask & MEM_R) != 0,
+                })
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+unsafe fn find_code(base: Address) -> Vec<(Address, usize)> {
+    crate::process::enumerate_mapped()
+        .find(|m| m.base == base)
+        .map(|m| {
+            m.segments
+                .into_iter()
+                .filter(|s| (s.mask & MEM_X) != 0)
+                .map(|s| (s.address, s.size))
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+unsafe fn find_segments(_base: Address) -> Vec<mldl::ImageSegment> {
+    Vec::new()
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+unsafe fn find_code(_base: Address) -> Vec<(Address, usize)> {
+    Vec::new()
+}
+
+unsafe fn find_pattern(base: Address, pattern: &[Option<u8>]) -> Result<Address> {
+    let mut found = None;
+
+    for (address, size) in self::find_code(base) {
+        let code = std::slice::from_raw_parts(address as *const u8, size);
+
+        let matches = code.windows(pattern.len()).enumerate().filter(|(_, w)| {
+            w.iter()
+                .zip(pattern)
+                .all(|(&b, p)| p.is_none_or(|p| b == p))
+        });
+
+        // A second match means the pattern can't tell its target apart.
+        for (i, _) in matches {
+            if found.replace(address.add(i)).is_some() {
+                return Err(Error::Conflict);
+            }
+        }
+    }
+
+    found.ok_or(Error::ItemNotFound)
+}
+
+unsafe fn find_own_symbol(h: Handle, symbol: &str) -> Result<Address> {
+    let address = crate::process::get_module_symbol_address(h, symbol)? as usize;
+    let segments = self::find_segments(crate::process::get_module_base(h)?);
+
+    // The loader also searches the dependencies, only a definition in the module counts.
+    match segments
+        .iter()
+        .any(|s| address >= s.address && address - s.address < s.size)
+    {
+        true => Ok(address as Address),
+        false => Err(Error::ItemNotFound),
+    }
+}
+
+unsafe fn with_module<T>(module: &str, f: impl FnOnce(Handle) -> Result<T>) -> Result<T> {
+    if module.is_empty() {
+        return f(crate::process::get_handle());
+    }
+
+    let h = crate::process::get_module(module)?;
+    let result = f(h);
+
+    // Lookups take a reference, don't keep the module alive.
+    let _ = crate::process::free_module_internal(h);
+    result
+}
+
+unsafe fn parse_metadata(
+    base: Address,
+    path: &Path,
+    buffer: Option<&[u8]>,
+) -> mldl::MLError<MLMetadata> {
+    // The mapped image is what runs, its file may have been replaced or deleted since.
+    if let Some(metadata) = mldl::parse_ml_image(base as _, &self::find_segments(base))? {
+        return Ok(metadata);
+    }
+
+    // Modules loaded from memory have no file to fall back to.
+    match buffer {
+        Some(buffer) => mldl::parse_ml_buffer(base as _, buffer),
+        None => mldl::parse_ml_binary(base as _, path),
+    }
+}
+
+pub(crate) unsafe fn check_fingerprint(mid: &str, entry: &FingerprintEntry) -> Result<()> {
+    let h = match entry.module.is_empty() {
+        true => crate::process::get_handle(),
+        false => match crate::process::get_module(&entry.module) {
+            Ok(h) => {
+                // Lookups take a reference, don't keep the module alive.
+                let _ = crate::process::free_module_internal(h);
+                h
+            }
+            Err(_) => {
+                crate::log::write(&format!(
+                    "{} requires {}, which is not loaded.",
+                    mid, entry.module
+                ));
+
+                return Err(Error::Incompatible);
+            }
+        },
+    };
+
+    let fingerprint = crate::process::get_module_fingerprint(h)?;
+
+    if let Err(reason) = entry.check(&fingerprint) {
+        crate::log::write(&format!(
+            "{} targets another build of {}: {}, its hooks were not applied.",
+            mid,
+            self::describe_module(&entry.module),
+            reason
+        ));
+
+        return Err(Error::Incompatible);
+    }
+
+    Ok(())
+}
+
+unsafe fn resolve_target(target: &HookTarget) -> Result<Address> {
+    match target {
+        HookTarget::Address(address) => Ok(*address as _),
+        HookTarget::Export { module, symbol } => self::with_module(module, |h| {
+            crate::process::get_module_symbol_address(h, symbol)
+        }),
+        // The file may have changed since it was mapped, ask the loader instead.
+        HookTarget::Symbol { module, symbol } => {
+            self::with_module(module, |h| self::find_own_symbol(h, symbol))
+        }
+        HookTarget::Pattern {
+            module,
+            pattern,
+            offset,
+        } => self::with_module(module, |h| {
+            match self::find_pattern(crate::process::get_module_base(h)?, pattern) {
+                Ok(address) => Ok(address.wrapping_offset(*offset as isize)),
+                Err(Error::Conflict) => {
+                    crate::log::write(&format!(
+                        "Pattern {} matches more than once in {}.",
+                        target,
+                        self::describe_module(module)
+                    ));
+
+                    Err(Error::Conflict)
+                }
+                Err(e) => Err(e),
+            }
+        }),
+        HookTarget::Rva { module, rva } => {
+            self::with_module(
+                module,
+                |h| Ok(crate::process::get_module_base(h)?.add(*rva)),
+            )
+        }
+    }
+}
+
+unsafe fn install_hook(h: Handle, entry: &HookEntry) -> Result<InstalledHook> {
+    let target = self::resolve_target(&entry.target)?;
+    let callback = entry.callback as Address;
+    let conv = HookConv {
+        target: self::wrap_conv(entry.target_conv),
+        hook: self::wrap_conv(entry.hook_conv),
+        args: entry.args,
+    };
+
+    let mut options = HookOptions::new(HookKind::Before);
+    options.owner = h;
+    options.guard = entry.guard;
+    options.priority = entry.priority;
+    options.locking = entry.locking;
+
+    if !entry.expected.is_empty() {
+        options.expected = entry.expected.as_ptr();
+        options.expected_size = entry.expected.len();
+
+        if entry.expected_mask.len() == entry.expected.len() {
+            options.expected_mask = entry.expected_mask.as_ptr();
+        }
+    }
+
+    // The chain knows the hook by its own callback, the thunks only adapt its convention.
+    let thunks = hooks::enable_hook_with_conv(target, callback, &conv, &options)?;
+    Ok(InstalledHook { callback, thunks })
+}
+
+unsafe fn resolve_symbol(
+    binaries: &Binaries,
+    own: Address,
+    sym: &str,
+) -> Option<(Address, Address)> {
+    let lookup = |h: Handle| {
+        crate::process::get_module_symbol_address(h, sym)
+            .ok()
+            .filter(|a| !a.is_null())
+    };
+
+    // The game provides symbols first, then the other ML modules.
+    if let Some(address) = lookup(crate::process::get_handle()) {
+        // Modules are loaded globally, the game scope sees their exports too.
+        let provider = crate::process::get_module_base_from_address(address)
+            .ok()
+            .filter(|base| binaries.contains_key(&SyncAddress::from(*base)))
+            .unwrap_or(NULLPTR);
+
+        if provider != own {
+            return Some((address, provider));
+        }
+    }
+
+    binaries
+        .iter()
+        .filter(|(key, _)| key.extract() != own)
+        .find_map(|(key, b)| lookup(b.handle).map(|a| (a, key.extract())))
+}
+
+unsafe fn write_slot(slot: Address, value: Address) -> Result<()> {
+    crate::memory::copy(
+        slot,
+        &value as *const Address as Address,
+        std::mem::size_of::<Address>(),
+    )
+}
+
+unsafe fn bind_dynamic<'a>(
+    binaries: &Binaries,
+    own: Address,
+    mid: &str,
+    entries: impl IntoIterator<Item = &'a DynamicEntry>,
+) -> Result<Vec<Binding>> {
+    let mut bindings = Vec::new();
+
+    for entry in entries {
+        let slot = entry.address as Address;
+
+        // The initial value of the slot is the local fallback.
+        let mut binding = Binding {
+            slot,
+            sym: entry.sym.clone(),
+            fallback: std::ptr::read_unaligned(slot as *const Address),
+            provider: None,
+        };
+
+        match self::resolve_symbol(binaries, own, &entry.sym) {
+            Some((address, provider)) => {
+                self::write_slot(slot, address)?;
+                binding.provider = Some(provider);
+            }
+            None if !binding.fallback.is_null() => crate::log::write(&format!(
+                "{}: {} is unresolved, its fallback is used until a provider is loaded.",
+                mid, entry.sym
+            )),
+            None => {
+                crate::log::write(&format!("{}: could not resolve {}.", mid, entry.sym));
+                self::unbind(&bindings);
+                return Err(Error::ItemNotFound);
+            }
+        }
+
+        bindings.push(binding);
+    }
+
+    Ok(bindings)
+}
+
+unsafe fn unbind(bindings: &[Binding]) {
+    for binding in bindings {
+        let _ = self::write_slot(binding.slot, binding.fallback);
+    }
+}
+
+fn bindings(binary: &BinaryData) -> impl Iterator<Item = &Binding> {
+    binary
+        .bindings
+        .iter()
+        .chain(binary.records.iter().flat_map(|r| r.bindings.iter()))
+}
+
+fn bindings_mut(binary: &mut BinaryData) -> impl Iterator<Item = &mut Binding> {
+    binary.bindings.iter_mut().chain(
+        binary
+            .records
+            .iter_mut()
+            .flat_map(|r| r.bindings.iter_mut()),
+    )
+}
+
+unsafe fn rebind(binaries: &mut Binaries, unloaded: Option<Address>) {
+    let mut reverted = Vec::new();
+
+    // Slots bound to an unloaded provider fall back until another one is found.
+    for (key, binary) in binaries.iter_mut() {
+        for (i, binding) in self::bindings_mut(binary).enumerate() {
+            if unloaded.is_some() && binding.provider == unloaded {
+                let _ = self::write_slot(binding.slot, binding.fallback);
+                binding.provider = None;
+                reverted.push((*key, i));
+            }
+        }
+    }
+
+    let mut resolved = Vec::new();
+
+    for (key, binary) in binaries.iter() {
+        for (i, binding) in self::bindings(binary).enumerate() {
+            if binding.provider.is_none() {
+                if let Some(found) = self::resolve_symbol(binaries, key.extract(), &binding.sym) {
+                    resolved.push((*key, i, found));
+                }
+            }
+        }
+    }
+
+    for (key, i, (address, provider)) in resolved {
+        let binding = self::bindings_mut(binaries.get_mut(&key).unwrap())
+            .nth(i)
+            .unwrap();
+
+        if self::write_slot(binding.slot, address).is_ok() {
+            binding.provider = Some(provider);
+        }
+    }
+
+    for (key, i) in reverted {
+        let binary = &binaries[&key];
+        let binding = self::bindings(binary).nth(i).unwrap();
+
+        if binding.provider.is_none() && binding.fallback.is_null() {
+            crate::log::write(&format!(
+                "{}: {} lost its provider and has no fallback.",
+                binary.mid, binding.sym
+            ));
+        }
+    }
+}
+
+unsafe fn install_hooks<'a>(
+    h: Handle,
+    mid: &str,
+    entries: impl IntoIterator<Item = &'a HookEntry>,
+) -> Result<Vec<InstalledHook>> {
+    let mut installed = Vec::new();
+
+    for entry in entries {
+        match self::install_hook(h, entry) {
+            Ok(hook) => installed.push(hook),
+            Err(e) if entry.optional => crate::log::write(&format!(
+                "{}: optional hook on {} was skipped: {:?}.",
+                mid, entry.target, e
+            )),
+            Err(e) => {
+                crate::log::write(&format!(
+                    "{}: hook on {} could not be applied: {:?}.",
+                    mid, entry.target, e
+                ));
+
+                self::remove_hooks(&installed);
+                return Err(e);
+            }
+        }
+    }
+
+    Ok(installed)
+}
+
+unsafe fn disable_hooks(installed: &[InstalledHook]) {
+    for hook in installed.iter().rev() {
+        let _ = hooks::disable_hook(hook.callback);
+    }
+}
+
+unsafe fn remove_hooks(installed: &[InstalledHook]) {
+    self::disable_hooks(installed);
+
+    for hook in installed {
+        self::free_thunks(&hook.thunks);
+    }
+}
+
+// Loader
+
+unsafe fn check_metadata(
+    path: &Path,
+    mid: &str,
+    metadata: mldl::MLError<MLMetadata>,
+) -> Result<MLMetadata> {
+    let metadata = match metadata {
+        Ok(m) => m,
+        Err(e) => {
+            crate::log::write(&format!("Could not parse {}: {}.", path.display(), e));
+            return Err(Error::InvalidData);
+        }
+    };
+
+    // Refuse the whole binary, patching another build corrupts the game.
+    for entry in metadata.fingerprints.iter() {
+        self::check_fingerprint(mid, entry)?;
+    }
+
+    Ok(metadata)
+}
+
+pub(crate) unsafe fn validate_ml_binary(path: &Path, buffer: &[u8], mid: &str) -> Result<()> {
+    // Nothing is mapped yet, addresses don't matter to the checks.
+    self::check_metadata(path, mid, mldl::parse_ml_buffer(0, buffer)).map(|_| ())
+}
+
+pub(crate) unsafe fn initialize_ml_binary(
+    base: Address,
+    path: &Path,
+    buffer: Option<&[u8]>,
+    h: Handle,
+    mid: &str,
+) -> Result<()> {
+    let metadata = self::check_metadata(path, mid, self::parse_metadata(base, path, buffer))?;
+
+    let MLMetadata {
+        dynamic,
+        hooks: HookTable {
+            hooks,
+            locking_hooks,
+            ..
+        },
+        ..
+    } = metadata;
+
+    // Record members wait for their record, untagged hooks without preload form the default one.
+    let (dynamic, record_dynamic): (Vec<_>, Vec<_>) =
+        dynamic.into_iter().partition(|e| e.record.is_empty());
+
+    let (preload, record_hooks): (Vec<_>, Vec<_>) = locking_hooks
+        .into_iter()
+        .chain(hooks)
+        .partition(|e| e.record.is_empty() && e.preload);
+
+    // Imports are bound first, preload hooks may call them.
+    let bindings = self::bind_dynamic(&*BINARIES.lock(), base, mid, &dynamic)?;
+
+    let installed = match self::install_hooks(h, mid, &preload) {
+        Ok(installed) => installed,
+        Err(e) => {
+            self::unbind(&bindings);
+            return Err(e);
+        }
+    };
+
+    let binaries = &mut *BINARIES.lock();
+
+    binaries.insert(
+        SyncAddress::from(base),
+        BinaryData {
+            handle: h,
+            path: path.to_path_buf(),
+            mid: mid.to_string(),
+            refs: 1,
+            hooks: installed,
+            bindings,
+            records: records::group_records(record_dynamic, record_hooks),
+        },
+    );
+
+    self::rebind(binaries, None);
+
+    Ok(())
+}
+
+pub(crate) fn get_ml_state(base: Address) -> Option<BinaryState> {
+    BINARIES
+        .lock()
+        .get(&SyncAddress::from(base))
+        .map(|binary| BinaryState {
+            path: binary.path.clone(),
+            mid: binary.mid.clone(),
+            refs: binary.refs,
+            records: binary
+                .records
+                .iter()
+                .filter(|r| r.refs != 0)
+                .map(|r| (r.name.clone(), r.refs))
+                .collect(),
+        })
+}
+
+pub(crate) unsafe fn restore_records(h: Handle, records: &[(String, usize)]) {
+    // Records the old instance had initialized are initialized as often on the new one.
+    for (name, refs) in records {
+        for _ in 0..*refs {
+            if let Err(e) = records::init_record(h, name) {
+                crate::log::write(&format!("Could not restore record {:?}: {:?}.", name, e));
+                break;
+            }
+        }
+    }
+}
+
+pub(crate) fn retain_ml_binary(base: Address) -> bool {
+    match BINARIES.lock().get_mut(&SyncAddress::from(base)) {
+        Some(binary) => {
+            binary.refs += 1;
+            true
+        }
+        None => false,
+    }
+}
+
+pub(crate) fn release_ml_binary(base: Address) -> Option<bool> {
+    // Only the last reference unloads the binary.
+    BINARIES
+        .lock()
+        .get_mut(&SyncAddress::from(base))
+        .map(|binary| {
+            binary.refs -= 1;
+            binary.refs == 0
+        })
+}
+
+pub(crate) unsafe fn cleanup_ml_binary(
+    base: Address,
+    path: &Path,
+    h: Handle,
+) -> Result<Vec<Address>> {
+    let key = SyncAddress::from(base);
+
+    let binary = {
+        let binaries = &mut *BINARIES.lock();
+
+        let binary = match binaries.get(&key) {
+            Some(b) if b.handle == h && b.path == path => binaries.remove(&key).unwrap(),
+            Some(_) => return Err(Error::InvalidArgument),
+            None => return Err(Error::ItemNotFound),
+        };
+
+        self::rebind(binaries, Some(base));
+        binary
+    };
+
+    let mut thunks = Vec::new();
+
+    for record in binary.records.into_iter().filter(|r| r.refs != 0) {
+        self::disable_hooks(&record.installed);
+        thunks.extend(record.installed.into_iter().flat_map(|hook| hook.thunks));
+    }
+
+    self::disable_hooks(&binary.hooks);
+    thunks.extend(binary.hooks.into_iter().flat_map(|hook| hook.thunks));
+
+    // Threads may still run the thunks, they are freed once the module is quiescent.
+    Ok(thunks)
+}
+
+pub(crate) unsafe fn free_thunks(thunks: &[Address]) {
+    for &thunk in thunks {
+        let _ = crate::buffer::trampolines::remove(thunk);
+    }
+}
@@ -0,0 +1,243 @@
+// Includes
+
+use super::{BinaryData, Binding, InstalledHook, BINARIES};
+use crate::types::*;
+use mlsys::*;
+
+use mldl::{DynamicEntry, HookEntry};
+
+// Types
+
+pub(super) struct RecordData {
+    pub(super) name: String,
+    dynamic: Vec<DynamicEntry>,
+    hooks: Vec<HookEntry>,
+    pub(super) refs: usize,
+    pub(super) installed: Vec<InstalledHook>,
+    pub(super) bindings: Vec<Binding>,
+}
+
+#[repr(C)]
+pub struct RecordInfo {
+    pub handle: Handle,
+    pub name: RawString,
+    pub refs: usize,
+    pub hooks: usize,
+    pub bindings: usize,
+}
+
+// Helpers
+
+fn record_mut<'a>(records: &'a mut Vec<RecordData>, name: &str) -> &'a mut RecordData {
+    match records.iter().position(|r| r.name == name) {
+        Some(i) => &mut records[i],
+        None => {
+            records.push(RecordData {
+                name: name.to_string(),
+                dynamic: Vec::new(),
+                hooks: Vec::new(),
+                refs: 0,
+                installed: Vec::new(),
+                bindings: Vec::new(),
+            });
+
+            records.last_mut().unwrap()
+        }
+    }
+}
+
+pub(super) fn group_records(dynamic: Vec<DynamicEntry>, hooks: Vec<HookEntry>) -> Vec<RecordData> {
+    let mut records = Vec::new();
+
+    // The default record always exists, even when the module has no lazy hooks.
+    self::record_mut(&mut records, "");
+
+    for entry in dynamic {
+        self::record_mut(&mut records, &entry.record)
+            .dynamic
+            .push(entry);
+    }
+
+    for entry in hooks {
+        self::record_mut(&mut records, &entry.record)
+            .hooks
+            .push(entry);
+    }
+
+    records
+}
+
+fn read_id(id: RawString) -> Option<String> {
+    // A null id selects the default record, the untagged hooks loaded without preload.
+    match id.to_bytes() {
+        Some(bytes) => std::str::from_utf8(bytes).ok().map(|s| s.to_string()),
+        None => Some(String::new()),
+    }
+}
+
+fn find_binary(
+    binaries: &mut super::Binaries,
+    h: Handle,
+) -> Result<(SyncAddress, &mut BinaryData)> {
+    binaries
+        .iter_mut()
+        .find(|(_, b)| b.handle == h)
+        .map(|(key, b)| (*key, b))
+        .ok_or(Error::ItemNotFound)
+}
+
+unsafe fn to_record_info(binary: &BinaryData, record: &RecordData) -> RecordInfo {
+    RecordInfo {
+        handle: binary.handle,
+        name: RawString::from_bytes(&[record.name.as_bytes(), b"\0"].concat()),
+        refs: record.refs,
+        hooks: record.hooks.len(),
+        bindings: record.dynamic.len(),
+    }
+}
+
+// Records
+
+pub unsafe fn init_record(h: Handle, name: &str) -> Result<()> {
+    let binaries = &mut *BINARIES.lock();
+
+    let (key, binary) = self::find_binary(binaries, h)?;
+    let mid = binary.mid.clone();
+
+    let record = binary
+        .records
+        .iter_mut()
+        .find(|r| r.name == name)
+        .ok_or(Error::ItemNotFound)?;
+
+    // Other modules share the record, only the first init applies it.
+    if record.refs != 0 {
+        record.refs += 1;
+        return Ok(());
+    }
+
+    let dynamic = std::mem::take(&mut record.dynamic);
+    let entries = std::mem::take(&mut record.hooks);
+
+    let result =
+        super::bind_dynamic(binaries, key.extract(), &mid, &dynamic).and_then(|bindings| {
+            match super::install_hooks(h, &mid, &entries) {
+                Ok(installed) => Ok((bindings, installed)),
+                Err(e) => {
+                    super::unbind(&bindings);
+                    Err(e)
+                }
+            }
+        });
+
+    let record = binaries
+        .get_mut(&key)
+        .unwrap()
+        .records
+        .iter_mut()
+        .find(|r| r.name == name)
+        .unwrap();
+
+    record.dynamic = dynamic;
+    record.hooks = entries;
+
+    let (bindings, installed) = result?;
+
+    record.bindings = bindings;
+    record.installed = installed;
+    record.refs = 1;
+
+    Ok(())
+}
+
+pub unsafe fn cleanup_record(h: Handle, name: &str) -> Result<()> {
+    let binaries = &mut *BINARIES.lock();
+    let (_, binary) = self::find_binary(binaries, h)?;
+
+    let record = binary
+        .records
+        .iter_mut()
+        .find(|r| r.name == name)
+        .ok_or(Error::ItemNotFound)?;
+
+    match record.refs {
+        0 => return Err(Error::InvalidArgument),
+        1 => {
+            super::remove_hooks(&record.installed);
+            super::unbind(&record.bindings);
+
+            record.installed.clear();
+            record.bindings.clear();
+        }
+        _ => {}
+    }
+
+    record.refs -= 1;
+    Ok(())
+}
+
+// Bindings
+
+#[no_mangle]
+unsafe extern "C" fn MLInitRecord(handle: Handle, id: RawString) -> Bool {
+    match self::read_id(id).map(|name| self::init_record(handle, &name)) {
+        Some(Ok(_)) => Bool::True,
+        _ => Bool::False,
+    }
+}
+
+#[no_mangle]
+unsafe extern "C" fn MLCleanupRecord(handle: Handle, id: RawString) -> Bool {
+    match self::read_id(id).map(|name| self::cleanup_record(handle, &name)) {
+        Some(Ok(_)) => Bool::True,
+        _ => Bool::False,
+    }
+}
+
+#[no_mangle]
+unsafe extern "C" fn MLEnumerateRecords(
+    handle: Handle,
+    out: *mut RecordInfo,
+    capacity: usize,
+    count: *mut usize,
+) -> Error {
+    if count.is_null() || (out.is_null() && capacity != 0) {
+        return Error::InvalidArgument;
+    }
+
+    let binaries = BINARIES.lock();
+
+    // A null handle lists the records of every loaded module.
+    let records = binaries
+        .values()
+        .filter(|b| handle.is_null() || b.handle == handle)
+        .flat_map(|b| b.records.iter().map(move |r| (b, r)))
+        .collect::<Vec<_>>();
+
+    if records.is_empty() && !handle.is_null() && !binaries.values().any(|b| b.handle == handle) {
+        return Error::ItemNotFound;
+    }
+
+    *count = records.len();
+
+    for (i, (binary, record)) in records.into_iter().take(capacity).enumerate() {
+        out.add(i).write(self::to_record_info(binary, record));
+    }
+
+    Error::Success
+}
+
+#[no_mangle]
+unsafe extern "C" fn MLFreeRecords(infos: *mut RecordInfo, count: usize) -> Error {
+    if infos.is_null() {
+        return Error::InvalidArgument;
+    }
+
+    for i in 0..count {
+        let info = &mut *infos.add(i);
+        info.name.free();
+        info.name = std::ptr::null();
+    }
+
+    Error::Success
+}
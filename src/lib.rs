@@ -1,5 +1,7 @@
 mod buffer;
 mod hook;
+mod ldr;
+mod log;
 mod memory;
 mod types;
 mod process;
\ No newline at end of file
@@ -0,0 +1,35 @@
+// Includes
+
+use crate::types::*;
+use lazy_static::*;
+
+use std::ffi::CString;
+use std::os::raw::c_char;
+
+// Types
+
+pub(crate) type LogCallback = unsafe extern "C" fn(*const c_char);
+
+// Globals
+
+lazy_static! {
+    static ref CALLBACK: Mutex<Option<LogCallback>> = Mutex::new(None);
+}
+
+// Log
+
+pub(crate) fn write(message: &str) {
+    let callback = *CALLBACK.lock();
+
+    match (callback, CString::new(message)) {
+        (Some(callback), Ok(s)) => unsafe { callback(s.as_ptr()) },
+        _ => eprintln!("[mlrt] {}", message),
+    }
+}
+
+// Bindings
+
+#[no_mangle]
+unsafe extern "C" fn MLSetLogCallback(callback: Option<LogCallback>) {
+    *CALLBACK.lock() = callback;
+}
@@ -1,5 +1,6 @@
 // Includes
 
+use crate::process::owners::{self, Resource};
 use mlsys::*;
 
 // Platform
@@ -84,6 +85,7 @@ unsafe extern "C" fn MLMemoryAlloc(size: usize, mask: u32, out: *mut Address, hi
 
     match self::allocate(size, mask, hint) {
         Ok(address) => {
+            owners::track(NULLPTR, Resource::Allocation(address));
             *out = address;
             Error::Success
         }
@@ -94,7 +96,10 @@ unsafe extern "C" fn MLMemoryAlloc(size: usize, mask: u32, out: *mut Address, hi
 #[no_mangle]
 unsafe extern "C" fn MLMemoryFree(address: Address) -> Error {
     match self::free(address) {
-        Ok(()) => Error::Success,
+        Ok(()) => {
+            owners::untrack(|r| matches!(r, Resource::Allocation(a) if *a == address));
+            Error::Success
+        }
         Err(e) => e,
     }
 }
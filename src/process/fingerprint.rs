@@ -0,0 +1,77 @@
+// Includes
+
+use crate::types::*;
+use lazy_static::*;
+use mlsys::*;
+
+use mldl::Fingerprint;
+use std::path::PathBuf;
+
+// Types
+
+#[repr(C)]
+pub struct FingerprintInfo {
+    pub build_id_size: usize,
+    pub build_id: [u8; MAX_FINGERPRINT_ID],
+    pub file_size: u64,
+    pub text_hash: u64,
+}
+
+// Globals
+
+pub const MAX_FINGERPRINT_ID: usize = 64;
+
+lazy_static! {
+    static ref CACHE: Mutex<NoHashMap<SyncAddress, (PathBuf, Fingerprint)>> =
+        Mutex::new(NoHashMap::default());
+}
+
+// Fingerprint
+
+pub unsafe fn get_module_fingerprint(h: Handle) -> Result<Fingerprint> {
+    let path = super::get_module_path(h)?;
+    let key = SyncAddress::from(h as _);
+
+    // Hashing the game takes a while, reuse it while the handle maps the same file.
+    if let Some((p, fingerprint)) = CACHE.lock().get(&key) {
+        if *p == path {
+            return Ok(fingerprint.clone());
+        }
+    }
+
+    let fingerprint = match mldl::fingerprint_binary(&path) {
+        Ok(f) => f,
+        Err(e) => {
+            crate::log::write(&format!("Could not fingerprint {}: {}.", path.display(), e));
+
+            return Err(Error::InvalidData);
+        }
+    };
+
+    CACHE.lock().insert(key, (path, fingerprint.clone()));
+    Ok(fingerprint)
+}
+
+// Bindings
+
+#[no_mangle]
+unsafe extern "C" fn MLProcGetModuleFingerprint(h: Handle, out: *mut FingerprintInfo) -> Error {
+    if out.is_null() {
+        return Error::InvalidArgument;
+    }
+
+    match self::get_module_fingerprint(h) {
+        Ok(f) => {
+            let size = f.build_id.len().min(MAX_FINGERPRINT_ID);
+            let out = &mut *out;
+
+            out.build_id_size = size;
+            out.build_id = [0; MAX_FINGERPRINT_ID];
+            out.build_id[..size].copy_from_slice(&f.build_id[..size]);
+            out.file_size = f.file_size;
+            out.text_hash = f.text_hash;
+            Error::Success
+        }
+        Err(e) => e,
+    }
+}
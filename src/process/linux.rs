@@ -2,7 +2,7 @@
 
 use mlsys::*;
 
-use std::ffi::OsString;
+use std::ffi::{CString, OsStr, OsString};
 use std::os::unix::ffi::OsStrExt;
 use std::os::unix::ffi::OsStringExt;
 use std::path::{Path, PathBuf};
@@ -93,26 +93,51 @@ pub unsafe fn remove_linker_path(p: &Path) -> Result<()> {
 }
 
 #[cfg(not(target_os = "android"))]
-pub unsafe fn add_linker_path(_p: &Path) -> Result<()> {
-    panic!("Unimplemented!") // TODO
+pub unsafe fn add_linker_path(p: &Path) -> Result<()> {
+    // The linker reads LD_LIBRARY_PATH once at startup, we keep our own list.
+    super::search::add_linker_path(p)
 }
 
 #[cfg(not(target_os = "android"))]
-pub unsafe fn remove_linker_path(_p: &Path) -> Result<()> {
-    panic!("Unimplemented!") // TODO
+pub unsafe fn remove_linker_path(p: &Path) -> Result<()> {
+    super::search::remove_linker_path(p)
 }
 
+unsafe fn find_loaded_module(s: &OsStr) -> Option<Handle> {
+    let name = CString::new(s.as_bytes()).ok()?;
+    let m = platform::dlopen(name.as_ptr(), platform::RTLD_LAZY | platform::RTLD_NOLOAD);
+
+    match m.is_null() {
+        true => None,
+        false => Some(m as Handle),
+    }
+}
+
+#[cfg(target_os = "android")]
 pub unsafe fn get_module(s: &str) -> Result<Handle> {
-    let m = platform::dlopen(
-        s.as_bytes().as_ptr() as _,
-        platform::RTLD_LAZY | platform::RTLD_NOLOAD,
-    );
+    if s.contains('\0') {
+        return Err(Error::InvalidArgument);
+    }
 
-    if !m.is_null() {
-        return Ok(m as Handle);
+    // The dynamic linker doesn't report through errno.
+    self::find_loaded_module(OsStr::new(s)).ok_or(Error::ItemNotFound)
+}
+
+#[cfg(not(target_os = "android"))]
+pub unsafe fn get_module(s: &str) -> Result<Handle> {
+    if s.contains('\0') {
+        return Err(Error::InvalidArgument);
     }
 
-    Err(platform::get_system_error_wrapped())
+    if let Some(h) = self::find_loaded_module(OsStr::new(s)) {
+        return Ok(h);
+    }
+
+    // Modules from our paths are known to the linker by their full path.
+    super::search::find_module_in_linker_paths(s)
+        .iter()
+        .find_map(|p| self::find_loaded_module(p.as_os_str()))
+        .ok_or(Error::ItemNotFound)
 }
 
 pub unsafe fn get_module_from_address(address: Address) -> Result<Handle> {
@@ -129,12 +154,38 @@ pub unsafe fn get_module_from_address(address: Address) -> Result<Handle> {
     Err(platform::get_system_error_wrapped())
 }
 
-pub unsafe fn load_module_internal(p: &Path) -> Result<Handle> {
-    let m = platform::dlopen(
-        p.as_os_str().as_bytes().as_ptr() as _,
-        platform::RTLD_LAZY | platform::RTLD_GLOBAL,
+pub unsafe fn get_module_base_from_address(address: Address) -> Result<Address> {
+    let mut info = platform::get_empty_dlinfo();
+
+    if platform::dladdr(address as _, &mut info) != 0 && !info.dli_fbase.is_null() {
+        return Ok(info.dli_fbase as Address);
+    }
+
+    Err(Error::ItemNotFound)
+}
+
+pub unsafe fn get_caller_base() -> Result<Address> {
+    let own = self::get_module_base_from_address(get_caller_base as Address)?;
+    let mut caller = Err(Error::ItemNotFound);
+
+    // Skip our own frames, return addresses point past the call.
+    platform::walk_stack(
+        |ip| match self::get_module_base_from_address(ip.wrapping_sub(1)) {
+            Ok(base) if base != own => {
+                caller = Ok(base);
+                false
+            }
+            _ => true,
+        },
     );
 
+    caller
+}
+
+pub(crate) unsafe fn open_module(p: &Path) -> Result<Handle> {
+    let path = CString::new(p.as_os_str().as_bytes()).map_err(|_| Error::InvalidArgument)?;
+    let m = platform::dlopen(path.as_ptr(), platform::RTLD_LAZY | platform::RTLD_GLOBAL);
+
     if !m.is_null() {
         return Ok(m as Handle);
     }
@@ -142,7 +193,7 @@ pub unsafe fn load_module_internal(p: &Path) -> Result<Handle> {
     Err(platform::get_system_error_wrapped())
 }
 
-pub unsafe fn free_module_internal(h: Handle) -> Result<()> {
+pub(crate) unsafe fn close_module(h: Handle) -> Result<()> {
     if h == get_handle() {
         return Err(mlsys::Error::InvalidArgument);
     }
@@ -154,6 +205,52 @@ pub unsafe fn free_module_internal(h: Handle) -> Result<()> {
     Err(platform::get_system_error_wrapped())
 }
 
+#[cfg(target_os = "android")]
+pub unsafe fn load_module_internal(p: &Path) -> Result<Handle> {
+    self::open_module(p)
+}
+
+#[cfg(target_os = "android")]
+pub unsafe fn free_module_internal(h: Handle) -> Result<()> {
+    self::close_module(h)
+}
+
+#[cfg(not(target_os = "android"))]
+pub unsafe fn load_module_internal(p: &Path) -> Result<Handle> {
+    let path = super::search::resolve_module_path(p);
+
+    // Dependencies only found in our paths have to be mapped before the module.
+    let loaded = super::search::load_dependencies(&path);
+
+    match self::open_module(&path) {
+        Ok(h) => {
+            match self::get_module_base(h) {
+                Ok(base) => super::search::retain_dependencies(base, loaded),
+                Err(_) => super::search::free_dependencies(loaded),
+            }
+
+            Ok(h)
+        }
+        Err(e) => {
+            super::search::free_dependencies(loaded);
+            Err(e)
+        }
+    }
+}
+
+#[cfg(not(target_os = "android"))]
+pub unsafe fn free_module_internal(h: Handle) -> Result<()> {
+    let base = self::get_module_base(h)?;
+    self::close_module(h)?;
+
+    // Preloaded dependencies go with the last reference.
+    if !super::enumerate_mapped().any(|m| m.base == base) {
+        super::search::release_dependencies(base);
+    }
+
+    Ok(())
+}
+
 pub unsafe fn get_module_path(h: Handle) -> Result<PathBuf> {
     match h == get_handle() {
         true => get_main_module_path(h),
@@ -184,8 +281,9 @@ pub unsafe fn get_module_base(h: Handle) -> Result<Address> {
 }
 
 pub unsafe fn get_module_symbol_address(h: mlsys::Handle, sym: &str) -> Result<Address> {
+    let sym = CString::new(sym).map_err(|_| Error::InvalidArgument)?;
     platform::dlerror();
-    let address = platform::dlsym(h as _, sym.as_bytes().as_ptr() as _);
+    let address = platform::dlsym(h as _, sym.as_ptr());
 
     if platform::dlerror().is_null() {
         return Ok(address as Address);
@@ -0,0 +1,133 @@
+// Includes
+
+use crate::types::*;
+use lazy_static::*;
+use mlsys::*;
+
+use std::ffi::{CStr, CString};
+use std::os::raw::c_char;
+use std::os::unix::io::RawFd;
+use std::path::PathBuf;
+
+// Globals
+
+lazy_static! {
+    static ref IMAGES: Mutex<NoHashMap<SyncAddress, RawFd>> = Mutex::new(NoHashMap::default());
+}
+
+// Helpers
+
+unsafe fn write_image(fd: RawFd, bytes: &[u8]) -> Result<()> {
+    let mut written = 0;
+
+    while written < bytes.len() {
+        match platform::write(fd, &bytes[written..]) {
+            Ok(0) => return Err(Error::NoMemory),
+            Ok(n) => written += n,
+            Err(platform::Errno::EINTR) => {}
+            Err(e) => return Err(platform::wrap_system_error(e)),
+        }
+    }
+
+    // The linker maps the image, nothing may change it afterwards.
+    let seals = platform::SealFlag::F_SEAL_SHRINK
+        | platform::SealFlag::F_SEAL_GROW
+        | platform::SealFlag::F_SEAL_WRITE
+        | platform::SealFlag::F_SEAL_SEAL;
+
+    match platform::fcntl(fd, platform::FcntlArg::F_ADD_SEALS(seals)) {
+        Ok(_) => Ok(()),
+        Err(e) => Err(platform::wrap_system_error(e)),
+    }
+}
+
+// Memfd
+
+pub unsafe fn load_module_from_memory_internal(bytes: &[u8], name: &str) -> Result<Handle> {
+    let name = CString::new(name).map_err(|_| Error::InvalidArgument)?;
+    let flags =
+        platform::MemFdCreateFlag::MFD_CLOEXEC | platform::MemFdCreateFlag::MFD_ALLOW_SEALING;
+    let fd = platform::memfd_create(&name, flags).map_err(|e| platform::wrap_system_error(e))?;
+
+    let result = self::write_image(fd, bytes)
+        .and_then(|_| super::load_module_internal(&PathBuf::from(format!("/proc/self/fd/{}", fd))))
+        .and_then(|h| match super::get_module_base(h) {
+            Ok(base) => Ok((h, base)),
+            Err(e) => {
+                let _ = super::free_module_internal(h);
+                Err(e)
+            }
+        });
+
+    match result {
+        Ok((h, base)) => {
+            // The linker matches modules by name, keep the descriptor so its path isn't reused.
+            if let Some(old) = IMAGES.lock().insert(SyncAddress::from(base), fd) {
+                let _ = platform::close(old);
+            }
+
+            Ok(h)
+        }
+        Err(e) => {
+            let _ = platform::close(fd);
+            Err(e)
+        }
+    }
+}
+
+pub unsafe fn load_module_from_memory(bytes: &[u8], mid: &str) -> Result<Handle> {
+    let name = match mid.is_empty() {
+        true => "mlrt-module",
+        false => mid,
+    };
+
+    let h = self::load_module_from_memory_internal(bytes, name)?;
+
+    // The descriptor stays open while the module is loaded, its path remains valid.
+    let path = match super::get_module_path(h) {
+        Ok(path) => path,
+        Err(e) => {
+            let _ = super::free_module(h);
+            return Err(e);
+        }
+    };
+
+    super::initialize_module(h, &path, Some(bytes), mid)
+}
+
+pub unsafe fn close_module_image(base: Address) {
+    if let Some(fd) = IMAGES.lock().remove(&SyncAddress::from(base)) {
+        let _ = platform::close(fd);
+    }
+}
+
+// Bindings
+
+#[no_mangle]
+unsafe extern "C" fn MLProcLoadModuleFromMemory(
+    bytes: *const u8,
+    len: usize,
+    mid: *const c_char,
+    out: *mut Handle,
+) -> Error {
+    if bytes.is_null() || len == 0 || out.is_null() {
+        return Error::InvalidArgument;
+    }
+
+    let mid = if mid.is_null() {
+        String::new()
+    } else {
+        match CStr::from_ptr(mid).to_str() {
+            Ok(s) => s.to_string(),
+            Err(_) => return Error::InvalidArgument,
+        }
+    };
+
+    match self::load_module_from_memory(std::slice::from_raw_parts(bytes, len), &mid) {
+        Ok(h) => {
+            *out = h;
+            Error::Success
+        }
+        Err(e) => e,
+    }
+}
@@ -12,33 +12,119 @@ mod linux;
 #[cfg(any(target_os = "linux", target_os = "android"))]
 pub use self::linux::*;
 
+#[cfg(any(target_os = "linux", target_os = "android"))]
+mod modules;
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub use self::modules::*;
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+mod notify;
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+mod quiesce;
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+mod memfd;
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+mod reload;
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+mod packages;
+
+#[cfg(target_os = "linux")]
+mod search;
+
+mod fingerprint;
+
+pub(crate) mod owners;
+
+pub use self::fingerprint::*;
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub use self::notify::*;
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub(crate) use self::quiesce::*;
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub use self::memfd::*;
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub use self::packages::*;
+
 // Includes
 
+use crate::ldr;
 use std::ffi::CStr;
 use std::path::{Path, PathBuf};
 use std::os::raw::c_char;
 
 // Proc
 
+pub(crate) unsafe fn initialize_module(
+    h: mlsys::Handle,
+    path: &Path,
+    buffer: Option<&[u8]>,
+    mid: &str,
+) -> mlsys::Result<mlsys::Handle> {
+    // Loading a module again only takes a reference.
+    if ldr::retain_ml_binary(self::get_module_base(h)?) {
+        return Ok(h);
+    }
+
+    // Binaries rejected by the loader don't stay mapped.
+    if let Err(e) = ldr::initialize_ml_binary(self::get_module_base(h)?, path, buffer, h, mid) {
+        let _ = self::free_module(h);
+        return Err(e);
+    }
+
+    crate::buffer::deferred::refresh();
+    Ok(h)
+}
+
 pub unsafe fn load_module(path: &Path, mid: &str) -> mlsys::Result<mlsys::Handle> {
     match self::load_module_internal(path) {
-        Ok(h) => {
-            ldr::initialize_ml_binary(self::get_module_base(h)?, path, h, mid)?;
-            Ok(h)
-        }
+        Ok(h) => self::initialize_module(h, path, None, mid),
         Err(e) => Err(e),
     }
 }
 
 pub unsafe fn free_module(h: mlsys::Handle) -> mlsys::Result<()> {
     let base = self::get_module_base(h)?;
-    let path = self::get_module_path(h)?;
-    self::free_module_internal(h)?;
 
-    if self::get_module_base(h).unwrap_or(0) != base {
-        return ldr::cleanup_ml_binary(base, &path, h);
+    // Modules loaded from memory are known by the path they were read from.
+    let path = match ldr::get_ml_state(base) {
+        Some(state) => state.path,
+        None => self::get_module_path(h)?,
+    };
+
+    // Release what the module owns while its code is still mapped.
+    match ldr::release_ml_binary(base) {
+        Some(true) => {
+            let thunks = ldr::cleanup_ml_binary(base, &path, h)?;
+
+            // Nothing calls into the module anymore, wait for threads to leave it.
+            owners::release(base, &path, &thunks)?;
+            self::free_module_internal(h)?;
+        }
+        Some(false) => self::free_module_internal(h)?,
+        None => {
+            // The handle is gone after the close, its resources have to go first.
+            owners::release(base, &path, &[])?;
+            self::free_module_internal(h)?;
+        }
+    }
+
+    // The handle dangles once the module is unmapped, ask the linker about its base instead.
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    if self::get_module_base_from_address(base).ok() != Some(base) {
+        self::close_module_image(base);
+        self::release_package(base);
     }
 
+    crate::buffer::deferred::refresh();
     Ok(())
 }
 
@@ -0,0 +1,308 @@
+// Includes
+
+use mlsys::*;
+
+use std::ffi::{CStr, OsStr};
+use std::os::unix::ffi::OsStrExt;
+use std::path::PathBuf;
+
+// Types
+
+#[derive(Clone, Debug)]
+pub struct Segment {
+    pub address: Address,
+    pub size: usize,
+    pub offset: usize,
+    pub mask: u32,
+}
+
+#[derive(Clone, Debug)]
+pub struct Module {
+    pub handle: Handle,
+    pub base: Address,
+    pub size: usize,
+    pub segments: Vec<Segment>,
+    pub build_id: Vec<u8>,
+    pub path: PathBuf,
+}
+
+pub struct Modules(std::vec::IntoIter<Module>);
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct SegmentInfo {
+    pub address: Address,
+    pub size: usize,
+    pub offset: usize,
+    pub mask: u32,
+}
+
+#[repr(C)]
+pub struct ModuleInfo {
+    pub handle: Handle,
+    pub base: Address,
+    pub size: usize,
+    pub segment_count: usize,
+    pub segments: [SegmentInfo; MAX_SEGMENTS],
+    pub build_id_size: usize,
+    pub build_id: [u8; MAX_BUILD_ID],
+    pub path: RawString,
+}
+
+// Globals
+
+pub const MAX_SEGMENTS: usize = 16;
+pub const MAX_BUILD_ID: usize = 64;
+
+const PF_X: u32 = 0x1;
+const PF_W: u32 = 0x2;
+const PF_R: u32 = 0x4;
+
+const NT_GNU_BUILD_ID: u32 = 3;
+
+// Helpers
+
+fn wrap_segment_flags(flags: u32) -> u32 {
+    let mut mask = MEM_N;
+
+    if flags & PF_R == PF_R {
+        mask |= MEM_R;
+    }
+
+    if flags & PF_W == PF_W {
+        mask |= MEM_W;
+    }
+
+    if flags & PF_X == PF_X {
+        mask |= MEM_X;
+    }
+
+    mask
+}
+
+fn align_note(size: usize) -> usize {
+    (size + 3) & !3
+}
+
+unsafe fn find_build_id(note: *const u8, size: usize) -> Option<Vec<u8>> {
+    let mut offset = 0usize;
+
+    // Each note is a 12 byte header followed by the padded name and descriptor.
+    while offset + 12 <= size {
+        let header = note.add(offset) as *const u32;
+        let name_size = std::ptr::read_unaligned(header) as usize;
+        let desc_size = std::ptr::read_unaligned(header.add(1)) as usize;
+        let note_type = std::ptr::read_unaligned(header.add(2));
+
+        let name = offset + 12;
+        let desc = name + self::align_note(name_size);
+        let next = desc + self::align_note(desc_size);
+
+        if next > size {
+            break;
+        }
+
+        let owner = std::slice::from_raw_parts(note.add(name), name_size);
+
+        if note_type == NT_GNU_BUILD_ID && owner == b"GNU\0" {
+            return Some(std::slice::from_raw_parts(note.add(desc), desc_size).to_vec());
+        }
+
+        offset = next;
+    }
+
+    None
+}
+
+unsafe fn resolve_module(name: &CStr) -> (Handle, PathBuf) {
+    // The main program reports an empty name.
+    if name.to_bytes().is_empty() {
+        let h = super::get_handle();
+        return (h, super::get_module_path(h).unwrap_or_default());
+    }
+
+    let h = platform::dlopen(name.as_ptr(), platform::RTLD_LAZY | platform::RTLD_NOLOAD);
+
+    // Lookups take a reference, don't keep the module alive.
+    if !h.is_null() {
+        platform::dlclose(h);
+    }
+
+    (
+        h as Handle,
+        PathBuf::from(OsStr::from_bytes(name.to_bytes())),
+    )
+}
+
+unsafe extern "C" fn collect_module(
+    info: *mut platform::dl_phdr_info,
+    _size: platform::size_t,
+    data: *mut platform::c_void,
+) -> platform::c_int {
+    let modules = &mut *(data as *mut Vec<(Module, Vec<u8>)>);
+    let info = &*info;
+    let base = info.dlpi_addr as Address;
+
+    let mut low = usize::MAX;
+    let mut high = 0usize;
+    let mut segments = Vec::new();
+    let mut build_id = Vec::new();
+
+    for i in 0..info.dlpi_phnum as usize {
+        let phdr = &*info.dlpi_phdr.add(i);
+
+        if phdr.p_type == platform::PT_LOAD {
+            low = low.min(phdr.p_vaddr as usize);
+            high = high.max((phdr.p_vaddr + phdr.p_memsz) as usize);
+
+            segments.push(Segment {
+                address: base.add(phdr.p_vaddr as usize),
+                size: phdr.p_memsz as usize,
+                offset: phdr.p_offset as usize,
+                mask: self::wrap_segment_flags(phdr.p_flags),
+            });
+        }
+
+        if phdr.p_type == platform::PT_NOTE && build_id.is_empty() {
+            let note = base.add(phdr.p_vaddr as usize) as *const u8;
+            build_id = self::find_build_id(note, phdr.p_memsz as usize).unwrap_or_default();
+        }
+    }
+
+    let name = match info.dlpi_name.is_null() {
+        true => Vec::new(),
+        false => CStr::from_ptr(info.dlpi_name).to_bytes_with_nul().to_vec(),
+    };
+
+    // Handles are resolved once the loader lock is released.
+    modules.push((
+        Module {
+            handle: NULLPTR,
+            base,
+            size: high.saturating_sub(low),
+            segments,
+            build_id,
+            path: PathBuf::new(),
+        },
+        name,
+    ));
+
+    0
+}
+
+unsafe fn collect_modules() -> Vec<(Module, Vec<u8>)> {
+    let mut found: Vec<(Module, Vec<u8>)> = Vec::new();
+    platform::dl_iterate_phdr(Some(collect_module), &mut found as *mut _ as _);
+    found
+}
+
+unsafe fn to_module_info(module: &Module) -> ModuleInfo {
+    let mut info = ModuleInfo {
+        handle: module.handle,
+        base: module.base,
+        size: module.size,
+        segment_count: module.segments.len().min(MAX_SEGMENTS),
+        segments: [SegmentInfo {
+            address: NULLPTR,
+            size: 0,
+            offset: 0,
+            mask: MEM_N,
+        }; MAX_SEGMENTS],
+        build_id_size: module.build_id.len().min(MAX_BUILD_ID),
+        build_id: [0; MAX_BUILD_ID],
+        path: RawString::from_bytes(&[module.path.as_os_str().as_bytes(), b"\0"].concat()),
+    };
+
+    for (i, segment) in module.segments.iter().take(MAX_SEGMENTS).enumerate() {
+        info.segments[i] = SegmentInfo {
+            address: segment.address,
+            size: segment.size,
+            offset: segment.offset,
+            mask: segment.mask,
+        };
+    }
+
+    info.build_id[..info.build_id_size].copy_from_slice(&module.build_id[..info.build_id_size]);
+    info
+}
+
+// Modules
+
+impl Iterator for Modules {
+    type Item = Module;
+
+    fn next(&mut self) -> Option<Module> {
+        self.0.next()
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.0.size_hint()
+    }
+}
+
+impl ExactSizeIterator for Modules {}
+
+/// Lists mapped modules in a single pass, without reopening them. Handles are left null.
+pub(crate) unsafe fn enumerate_mapped() -> Modules {
+    let modules = self::collect_modules()
+        .into_iter()
+        .map(|(mut module, name)| {
+            let name = CStr::from_bytes_with_nul(&name).unwrap_or_default();
+            module.path = PathBuf::from(OsStr::from_bytes(name.to_bytes()));
+            module
+        })
+        .collect::<Vec<_>>();
+
+    Modules(modules.into_iter())
+}
+
+pub unsafe fn enumerate_modules() -> Modules {
+    let modules = self::collect_modules()
+        .into_iter()
+        .map(|(mut module, name)| {
+            let name = CStr::from_bytes_with_nul(&name).unwrap_or_default();
+            (module.handle, module.path) = self::resolve_module(name);
+            module
+        })
+        .collect::<Vec<_>>();
+
+    Modules(modules.into_iter())
+}
+
+// Bindings
+
+#[no_mangle]
+unsafe extern "C" fn MLProcEnumerateModules(
+    out: *mut ModuleInfo,
+    capacity: usize,
+    count: *mut usize,
+) -> Error {
+    if count.is_null() || (out.is_null() && capacity != 0) {
+        return Error::InvalidArgument;
+    }
+
+    let modules = self::enumerate_modules();
+    *count = modules.len();
+
+    // Query the count first with a null buffer, then fill as many as fit.
+    for (i, module) in modules.take(capacity).enumerate() {
+        out.add(i).write(self::to_module_info(&module));
+    }
+
+    Error::Success
+}
+
+#[no_mangle]
+unsafe extern "C" fn MLProcFreeModules(infos: *mut ModuleInfo, count: usize) -> Error {
+    if infos.is_null() {
+        return Error::InvalidArgument;
+    }
+
+    for i in 0..count {
+        let info = &mut *infos.add(i);
+        info.path.free();
+        info.path = std::ptr::null();
+    }
+
+    Error::Success
+}
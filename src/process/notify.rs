@@ -0,0 +1,277 @@
+// Includes
+
+use super::owners::{self, Resource};
+use crate::buffer::hooks::{self, HookKind, HookOptions};
+use crate::types::*;
+use lazy_static::*;
+use mlsys::*;
+
+use std::cell::Cell;
+use std::ffi::CString;
+use std::os::raw::c_char;
+use std::os::unix::ffi::OsStrExt;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+// Types
+
+#[repr(C)]
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ModuleEvent {
+    Load,
+    Unload,
+}
+
+#[repr(C)]
+pub struct ModuleEventInfo {
+    pub handle: Handle,
+    pub base: Address,
+    pub size: usize,
+    pub path: *const c_char,
+}
+
+pub type ModuleCallback = unsafe extern "C" fn(ModuleEvent, *const ModuleEventInfo, Address);
+
+#[derive(Clone)]
+struct ModuleRecord {
+    handle: Handle,
+    size: usize,
+    path: CString,
+}
+
+#[derive(Default)]
+struct ModuleState {
+    installed: bool,
+    modules: NoHashMap<SyncAddress, ModuleRecord>,
+}
+
+type DebugStateFn = unsafe extern "C" fn();
+
+// Globals
+
+lazy_static! {
+    static ref STATE: Mutex<ModuleState> = Mutex::new(ModuleState::default());
+    static ref CALLBACKS: Mutex<Vec<(ModuleCallback, SyncAddress)>> = Mutex::new(Vec::new());
+}
+
+static DEBUG_STATE: AtomicUsize = AtomicUsize::new(0);
+
+thread_local! {
+    static DEPTH: Cell<usize> = const { Cell::new(0) };
+}
+
+// Helpers
+
+unsafe impl Send for ModuleRecord {}
+
+unsafe fn get_debug_state() -> *mut platform::r_debug {
+    DEBUG_STATE.load(Ordering::Acquire) as _
+}
+
+unsafe fn scan_handles() -> NoHashMap<SyncAddress, Handle> {
+    let mut handles = NoHashMap::default();
+    let mut map = (*self::get_debug_state()).r_map;
+
+    // The loader's own list, its entries are the handles dlopen hands out.
+    while !map.is_null() {
+        handles.insert(SyncAddress::from((*map).l_addr as Address), map as Handle);
+        map = (*map).l_next;
+    }
+
+    handles
+}
+
+unsafe fn scan_modules() -> NoHashMap<SyncAddress, ModuleRecord> {
+    let handles = self::scan_handles();
+
+    super::enumerate_mapped()
+        .map(|module| {
+            let key = SyncAddress::from(module.base);
+            let record = ModuleRecord {
+                handle: handles.get(&key).copied().unwrap_or(NULLPTR),
+                size: module.size,
+                path: CString::new(module.path.as_os_str().as_bytes()).unwrap_or_default(),
+            };
+
+            (key, record)
+        })
+        .collect()
+}
+
+unsafe fn dispatch_once() -> bool {
+    let current = self::scan_modules();
+    let mut events = Vec::new();
+
+    {
+        let state = &mut *STATE.lock();
+
+        for (key, record) in state.modules.iter() {
+            if !current.contains_key(key) {
+                events.push((ModuleEvent::Unload, key.extract(), record.clone()));
+            }
+        }
+
+        for (key, record) in current.iter() {
+            if !state.modules.contains_key(key) {
+                events.push((ModuleEvent::Load, key.extract(), record.clone()));
+            }
+        }
+
+        state.modules = current;
+    }
+
+    if events.is_empty() {
+        return false;
+    }
+
+    crate::buffer::deferred::refresh();
+
+    // Subscribers may register or unregister from inside the callback.
+    let callbacks = CALLBACKS.lock().clone();
+
+    for (event, base, record) in events.iter() {
+        let info = ModuleEventInfo {
+            handle: record.handle,
+            base: *base,
+            size: record.size,
+            path: record.path.as_ptr(),
+        };
+
+        for (callback, data) in callbacks.iter() {
+            callback(*event, &info, data.extract());
+        }
+    }
+
+    true
+}
+
+unsafe fn dispatch() {
+    DEPTH.with(|depth| depth.set(depth.get() + 1));
+
+    // Modules loaded by the callbacks are reported in the next round.
+    while self::dispatch_once() {}
+
+    DEPTH.with(|depth| depth.set(depth.get() - 1));
+}
+
+unsafe extern "C" fn hooked_debug_state() {
+    let debug = self::get_debug_state();
+    let target = (*debug).r_brk as Address;
+    let next: DebugStateFn =
+        std::mem::transmute(hooks::get_next_hook(target, hooked_debug_state as _));
+    next();
+
+    // The loader calls in before and after each change, its list is only whole once consistent.
+    if (*debug).r_state != platform::RT_CONSISTENT {
+        return;
+    }
+
+    if DEPTH.with(|depth| depth.get()) == 0 {
+        self::dispatch();
+    }
+}
+
+unsafe fn install() -> Result<()> {
+    // Held throughout, so concurrent callers can't hook twice.
+    let state = &mut *STATE.lock();
+
+    if state.installed {
+        return Ok(());
+    }
+
+    let debug = platform::find_debug_state().ok_or(Error::Unsupported)?;
+    let target = (*debug).r_brk as Address;
+
+    if target.is_null() {
+        return Err(Error::Unsupported);
+    }
+
+    DEBUG_STATE.store(debug as usize, Ordering::Release);
+    hooks::enable_hook(
+        target,
+        hooked_debug_state as _,
+        &HookOptions::new(HookKind::Before),
+    )?;
+
+    // Modules already mapped are the baseline, they aren't reported.
+    state.modules = self::scan_modules();
+    state.installed = true;
+    Ok(())
+}
+
+// Notify
+
+/// Callbacks run on the loading thread with the loader lock held, before new modules run
+/// their constructors.
+pub unsafe fn register_module_callback(callback: ModuleCallback, data: Address) -> Result<()> {
+    self::install()?;
+
+    let callbacks = &mut *CALLBACKS.lock();
+
+    if callbacks
+        .iter()
+        .any(|(c, d)| *c as usize == callback as usize && d.extract() == data)
+    {
+        return Err(Error::InvalidArgument);
+    }
+
+    callbacks.push((callback, SyncAddress::from(data)));
+    Ok(())
+}
+
+pub unsafe fn unregister_module_callback(callback: ModuleCallback, data: Address) -> Result<()> {
+    let callbacks = &mut *CALLBACKS.lock();
+
+    match callbacks
+        .iter()
+        .position(|(c, d)| *c as usize == callback as usize && d.extract() == data)
+    {
+        Some(i) => {
+            callbacks.remove(i);
+            Ok(())
+        }
+        None => Err(Error::ItemNotFound),
+    }
+}
+
+pub unsafe fn watch_modules() -> Result<()> {
+    self::install()
+}
+
+// Bindings
+
+#[no_mangle]
+unsafe extern "C" fn MLRegisterModuleCallback(
+    callback: Option<ModuleCallback>,
+    data: Address,
+) -> Error {
+    match callback {
+        Some(callback) => match self::register_module_callback(callback, data) {
+            Ok(()) => {
+                owners::track(NULLPTR, Resource::Callback(callback, data));
+                Error::Success
+            }
+            Err(e) => e,
+        },
+        None => Error::InvalidArgument,
+    }
+}
+
+#[no_mangle]
+unsafe extern "C" fn MLUnregisterModuleCallback(
+    callback: Option<ModuleCallback>,
+    data: Address,
+) -> Error {
+    match callback {
+        Some(callback) => match self::unregister_module_callback(callback, data) {
+            Ok(()) => {
+                owners::untrack(|r| {
+                    matches!(r, Resource::Callback(c, d)
+                        if *c as usize == callback as usize && *d == data)
+                });
+                Error::Success
+            }
+            Err(e) => e,
+        },
+        None => Error::InvalidArgument,
+    }
+}
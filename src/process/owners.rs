@@ -0,0 +1,183 @@
+// Includes
+
+use crate::types::*;
+use lazy_static::*;
+use mlsys::*;
+
+use std::fmt::{self, Display};
+use std::path::Path;
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+use super::ModuleCallback;
+
+// Types
+
+pub(crate) enum Resource {
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    Callback(ModuleCallback, Address),
+    Deferred(usize),
+    Hook(Address),
+    Patch(Address, Vec<u8>),
+    Extension(FNV),
+    Thunk(Address),
+    Allocation(Address),
+}
+
+// Globals
+
+lazy_static! {
+    static ref OWNED: Mutex<NoHashMap<SyncAddress, Vec<Resource>>> =
+        Mutex::new(NoHashMap::default());
+}
+
+// Helpers
+
+unsafe impl Send for Resource {}
+
+impl Resource {
+    fn rank(&self) -> usize {
+        // Stop callers reaching the module first, free the memory they used last.
+        match self {
+            #[cfg(any(target_os = "linux", target_os = "android"))]
+            Resource::Callback(..) => 0,
+            Resource::Deferred(_) => 1,
+            Resource::Hook(_) => 2,
+            Resource::Patch(..) => 3,
+            Resource::Extension(_) => 4,
+            Resource::Thunk(_) => 5,
+            Resource::Allocation(_) => 6,
+        }
+    }
+
+    unsafe fn release(&self) -> Result<()> {
+        match self {
+            #[cfg(any(target_os = "linux", target_os = "android"))]
+            Resource::Callback(callback, data) => {
+                super::unregister_module_callback(*callback, *data)
+            }
+            Resource::Deferred(id) => crate::buffer::deferred::cancel(*id),
+            Resource::Hook(callback) => crate::buffer::hooks::disable_hook(*callback),
+            Resource::Patch(at, original) => {
+                crate::memory::copy(*at, original.as_ptr() as _, original.len())
+            }
+            Resource::Extension(id) => crate::buffer::ext_data::remove(*id),
+            Resource::Thunk(thunk) => crate::buffer::trampolines::remove(*thunk),
+            Resource::Allocation(address) => crate::memory::free(*address),
+        }
+    }
+}
+
+impl Display for Resource {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            #[cfg(any(target_os = "linux", target_os = "android"))]
+            Resource::Callback(callback, _) => write!(f, "module callback {:p}", *callback),
+            Resource::Deferred(id) => write!(f, "deferred hook {}", id),
+            Resource::Hook(callback) => write!(f, "hook {:p}", callback),
+            Resource::Patch(at, _) => write!(f, "patch at {:p}", at),
+            Resource::Extension(id) => write!(f, "extension {:#x}", id),
+            Resource::Thunk(thunk) => write!(f, "thunk {:p}", thunk),
+            Resource::Allocation(address) => write!(f, "allocation {:p}", address),
+        }
+    }
+}
+
+unsafe fn find_owner(owner: Handle) -> Result<Address> {
+    match owner.is_null() {
+        true => super::get_caller_base(),
+        false => super::get_module_base(owner).or_else(|_| super::get_caller_base()),
+    }
+}
+
+// Owners
+
+pub(crate) unsafe fn track(owner: Handle, resource: Resource) {
+    match self::find_owner(owner) {
+        Ok(base) => OWNED
+            .lock()
+            .entry(SyncAddress::from(base))
+            .or_default()
+            .push(resource),
+        Err(_) => crate::log::write(&format!("Could not find the owner of {}.", resource)),
+    }
+}
+
+pub(crate) fn untrack<F: Fn(&Resource) -> bool>(f: F) {
+    // Resources are released through any module, not only their owner.
+    for resources in OWNED.lock().values_mut() {
+        if let Some(i) = resources.iter().rposition(&f) {
+            resources.remove(i);
+            return;
+        }
+    }
+}
+
+unsafe fn release_all(path: &Path, resources: Vec<Resource>) {
+    for resource in resources {
+        crate::log::write(&format!(
+            "{}: {} was leaked, releasing it.",
+            path.display(),
+            resource
+        ));
+
+        if let Err(e) = resource.release() {
+            crate::log::write(&format!("Could not release {}: {:?}.", resource, e));
+        }
+    }
+}
+
+pub(crate) fn detach<F: Fn(&Resource) -> bool>(base: Address, f: F) -> Vec<Resource> {
+    let owned = &mut *OWNED.lock();
+
+    let resources = match owned.get_mut(&SyncAddress::from(base)) {
+        Some(resources) => resources,
+        None => return Vec::new(),
+    };
+
+    let (detached, kept) = std::mem::take(resources).into_iter().partition(f);
+    *resources = kept;
+    detached
+}
+
+pub(crate) unsafe fn release(base: Address, path: &Path, thunks: &[Address]) -> Result<()> {
+    let key = SyncAddress::from(base);
+    let mut resources = OWNED.lock().remove(&key).unwrap_or_default();
+
+    // Newest first within a rank, later resources may depend on earlier ones.
+    resources.reverse();
+    resources.sort_by_key(|resource| resource.rank());
+
+    let split = resources
+        .iter()
+        .position(|resource| matches!(resource, Resource::Thunk(_) | Resource::Allocation(_)))
+        .unwrap_or(resources.len());
+
+    let memory = resources.split_off(split);
+    self::release_all(path, resources);
+
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    {
+        let mut owned = thunks.to_vec();
+
+        owned.extend(memory.iter().filter_map(|resource| match resource {
+            Resource::Thunk(thunk) => Some(*thunk),
+            _ => None,
+        }));
+
+        if let Err(e) = super::wait_quiescent(base, path, &owned) {
+            // Freeing memory still in use crashes its threads, keep it for the next attempt.
+            let mut stash = thunks
+                .iter()
+                .map(|&t| Resource::Thunk(t))
+                .collect::<Vec<_>>();
+            stash.extend(memory);
+
+            OWNED.lock().entry(key).or_default().extend(stash);
+            return Err(e);
+        }
+    }
+
+    crate::ldr::free_thunks(thunks);
+    self::release_all(path, memory);
+    Ok(())
+}
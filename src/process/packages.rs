@@ -0,0 +1,309 @@
+// Includes
+
+use crate::ldr;
+use crate::types::*;
+use lazy_static::*;
+use mlsys::*;
+
+use mldl::{Package, PackageEntry};
+use std::ffi::CStr;
+use std::os::raw::c_char;
+use std::path::{Path, PathBuf};
+
+// Types
+
+#[repr(C)]
+pub struct AssetInfo {
+    pub name: RawString,
+    pub data: *const u8,
+    pub size: usize,
+}
+
+struct PackageData {
+    base: Option<SyncAddress>,
+    buffer: Vec<u8>,
+    package: Package,
+}
+
+// Globals
+
+lazy_static! {
+    static ref PACKAGES: Mutex<Vec<PackageData>> = Mutex::new(Vec::new());
+}
+
+// Helpers
+
+fn native_arch() -> &'static str {
+    match arch::NATIVE {
+        arch::ArchKind::X86 => "x86",
+        arch::ArchKind::X86_64 => "x86_64",
+        arch::ArchKind::A32 => "arm",
+        arch::ArchKind::A64 => "aarch64",
+    }
+}
+
+fn check_dependencies(packages: &[PackageData], package: &Package) -> Result<()> {
+    let manifest = &package.manifest;
+
+    for dependency in manifest.dependencies.iter() {
+        let loaded = packages
+            .iter()
+            .find(|p| p.package.manifest.id == dependency.id);
+
+        match (loaded, dependency.version) {
+            (None, _) => {
+                crate::log::write(&format!(
+                    "{} requires package {}, which is not loaded.",
+                    manifest.id, dependency.id
+                ));
+
+                return Err(Error::ItemNotFound);
+            }
+            (Some(p), Some(version)) if p.package.manifest.version < version => {
+                crate::log::write(&format!(
+                    "{} requires package {} {} or later, {} is loaded.",
+                    manifest.id, dependency.id, version, p.package.manifest.version
+                ));
+
+                return Err(Error::Incompatible);
+            }
+            _ => {}
+        }
+    }
+
+    Ok(())
+}
+
+unsafe fn find_package(packages: &[PackageData], h: Handle) -> Result<&PackageData> {
+    let base = match h.is_null() {
+        true => super::get_caller_base()?,
+        false => super::get_module_base(h)?,
+    };
+
+    // Constructors of a package being loaded run before its module is known.
+    packages
+        .iter()
+        .find(|p| p.base == Some(SyncAddress::from(base)))
+        .or_else(|| match h.is_null() {
+            true => packages.iter().find(|p| p.base.is_none()),
+            false => None,
+        })
+        .ok_or(Error::ItemNotFound)
+}
+
+fn to_asset_info(data: &PackageData, entry: &PackageEntry) -> AssetInfo {
+    let bytes = data.package.data(&data.buffer, entry);
+
+    AssetInfo {
+        name: RawString::from_bytes(&[entry.name.as_bytes(), b"\0"].concat()),
+        data: bytes.as_ptr(),
+        size: bytes.len(),
+    }
+}
+
+// Packages
+
+pub unsafe fn load_package_from_memory(buffer: Vec<u8>) -> Result<Handle> {
+    let package = match mldl::parse_package(&buffer) {
+        Ok(package) => package,
+        Err(e) => {
+            crate::log::write(&format!("Could not parse package: {}.", e));
+            return Err(Error::InvalidData);
+        }
+    };
+
+    let id = package.manifest.id.clone();
+
+    let entry = match package.binary(self::native_arch()) {
+        Some(entry) => entry,
+        None => {
+            crate::log::write(&format!(
+                "{} has no binary for {}.",
+                id,
+                self::native_arch()
+            ));
+
+            return Err(Error::Incompatible);
+        }
+    };
+
+    // Refuse the package before mapping anything, like its binary's own fingerprints.
+    for fingerprint in package.manifest.fingerprints.iter() {
+        ldr::check_fingerprint(&id, fingerprint)?;
+    }
+
+    // The binary is copied to its memfd, assets are read from the package buffer.
+    let binary = package.data(&buffer, entry).to_vec();
+
+    {
+        let packages = &mut *PACKAGES.lock();
+
+        if packages.iter().any(|p| p.package.manifest.id == id) {
+            crate::log::write(&format!("Package {} is already loaded.", id));
+            return Err(Error::Conflict);
+        }
+
+        self::check_dependencies(packages, &package)?;
+
+        packages.push(PackageData {
+            base: None,
+            buffer,
+            package,
+        });
+    }
+
+    let result = super::load_module_from_memory(&binary, &id)
+        .and_then(|h| super::get_module_base(h).map(|base| (h, base)));
+
+    let packages = &mut *PACKAGES.lock();
+    let i = packages.iter().position(|p| p.package.manifest.id == id);
+
+    match (result, i) {
+        (Ok((h, base)), Some(i)) => {
+            packages[i].base = Some(SyncAddress::from(base));
+            Ok(h)
+        }
+        (Err(e), Some(i)) => {
+            packages.remove(i);
+            Err(e)
+        }
+        (Ok(_), None) => Err(Error::ItemNotFound),
+        (Err(e), None) => Err(e),
+    }
+}
+
+pub unsafe fn load_package(p: &Path) -> Result<Handle> {
+    let buffer = std::fs::read(p).map_err(|_| Error::ItemNotFound)?;
+    self::load_package_from_memory(buffer)
+}
+
+pub unsafe fn get_asset(h: Handle, name: &str) -> Result<(*const u8, usize)> {
+    let packages = PACKAGES.lock();
+    let data = self::find_package(&packages, h)?;
+
+    let entry = data
+        .package
+        .assets()
+        .find(|e| e.name == name)
+        .ok_or(Error::ItemNotFound)?;
+
+    // The buffer lives as long as the package's module.
+    let bytes = data.package.data(&data.buffer, entry);
+    Ok((bytes.as_ptr(), bytes.len()))
+}
+
+pub(crate) fn release_package(base: Address) {
+    PACKAGES
+        .lock()
+        .retain(|p| p.base != Some(SyncAddress::from(base)));
+}
+
+// Bindings
+
+#[no_mangle]
+unsafe extern "C" fn MLProcLoadPackage(p: *const c_char, out: *mut Handle) -> Error {
+    if p.is_null() || out.is_null() {
+        return Error::InvalidArgument;
+    }
+
+    let path = match CStr::from_ptr(p).to_str() {
+        Ok(path) => PathBuf::from(path),
+        Err(_) => return Error::InvalidArgument,
+    };
+
+    match self::load_package(&path) {
+        Ok(h) => {
+            *out = h;
+            Error::Success
+        }
+        Err(e) => e,
+    }
+}
+
+#[no_mangle]
+unsafe extern "C" fn MLProcLoadPackageFromMemory(
+    bytes: *const u8,
+    len: usize,
+    out: *mut Handle,
+) -> Error {
+    if bytes.is_null() || len == 0 || out.is_null() {
+        return Error::InvalidArgument;
+    }
+
+    match self::load_package_from_memory(std::slice::from_raw_parts(bytes, len).to_vec()) {
+        Ok(h) => {
+            *out = h;
+            Error::Success
+        }
+        Err(e) => e,
+    }
+}
+
+#[no_mangle]
+unsafe extern "C" fn MLPackageGetAsset(
+    h: Handle,
+    name: *const c_char,
+    data: *mut *const u8,
+    size: *mut usize,
+) -> Error {
+    if name.is_null() || data.is_null() || size.is_null() {
+        return Error::InvalidArgument;
+    }
+
+    let name = match CStr::from_ptr(name).to_str() {
+        Ok(name) => name,
+        Err(_) => return Error::InvalidArgument,
+    };
+
+    // A null handle is the package of the calling module.
+    match self::get_asset(h, name) {
+        Ok((p, len)) => {
+            *data = p;
+            *size = len;
+            Error::Success
+        }
+        Err(e) => e,
+    }
+}
+
+#[no_mangle]
+unsafe extern "C" fn MLEnumerateAssets(
+    h: Handle,
+    out: *mut AssetInfo,
+    capacity: usize,
+    count: *mut usize,
+) -> Error {
+    if count.is_null() || (out.is_null() && capacity != 0) {
+        return Error::InvalidArgument;
+    }
+
+    let packages = PACKAGES.lock();
+
+    let data = match self::find_package(&packages, h) {
+        Ok(data) => data,
+        Err(e) => return e,
+    };
+
+    *count = data.package.assets().count();
+
+    for (i, entry) in data.package.assets().take(capacity).enumerate() {
+        out.add(i).write(self::to_asset_info(data, entry));
+    }
+
+    Error::Success
+}
+
+#[no_mangle]
+unsafe extern "C" fn MLFreeAssets(infos: *mut AssetInfo, count: usize) -> Error {
+    if infos.is_null() {
+        return Error::InvalidArgument;
+    }
+
+    for i in 0..count {
+        let info = &mut *infos.add(i);
+        info.name.free();
+        info.name = std::ptr::null();
+    }
+
+    Error::Success
+}
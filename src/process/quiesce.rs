@@ -0,0 +1,343 @@
+// Includes
+
+use crate::types::*;
+use lazy_static::*;
+use mlsys::*;
+
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, AtomicPtr, AtomicU64, AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+// Types
+
+type Ranges = Vec<(usize, usize)>;
+
+enum Sample {
+    Idle,
+    Inside(Address),
+    Dispatching,
+    Unverifiable(&'static str),
+}
+
+// Globals
+
+const SAMPLE_SIZE: usize = 256;
+
+const SAMPLE_SIGNAL: platform::Signal = platform::Signal::SIGURG;
+
+const SAMPLE_TIMEOUT: Duration = Duration::from_millis(100);
+
+const QUIESCE_TIMEOUT: Duration = Duration::from_secs(5);
+
+const POLL_INTERVAL: Duration = Duration::from_millis(2);
+
+// Requests pack a sequence number, the thread id and one of these tags.
+const SAMPLE_OPEN: u64 = 1;
+const SAMPLE_CLAIMED: u64 = 2;
+const SAMPLE_DONE: u64 = 3;
+const SAMPLE_TAG: u64 = 3;
+
+// The handler can't allocate or lock, samples go through fixed storage.
+static SAMPLE: [AtomicUsize; SAMPLE_SIZE] = [const { AtomicUsize::new(0) }; SAMPLE_SIZE];
+static SAMPLE_COUNT: AtomicUsize = AtomicUsize::new(0);
+static SAMPLE_BUSY: AtomicBool = AtomicBool::new(false);
+static SAMPLE_STATE: AtomicU64 = AtomicU64::new(0);
+static SAMPLE_SEQUENCE: AtomicU64 = AtomicU64::new(0);
+static SAMPLE_STACKS: AtomicPtr<(usize, usize)> = AtomicPtr::new(std::ptr::null_mut());
+static SAMPLE_STACK_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+lazy_static! {
+    static ref PASS: Mutex<()> = Mutex::new(());
+}
+
+// Helpers
+
+fn contains(ranges: &Ranges, address: Address) -> bool {
+    // Return addresses point past the call, which may be the end of the range.
+    let address = address as usize;
+
+    ranges
+        .iter()
+        .any(|&(begin, end)| address >= begin && address <= end)
+}
+
+unsafe fn find_ranges(base: Address, thunks: &[Address]) -> Ranges {
+    let mut ranges = super::enumerate_modules()
+        .find(|m| m.base == base)
+        .map(|m| {
+            m.segments
+                .into_iter()
+                .filter(|s| (s.mask & MEM_X) != 0)
+                .map(|s| (s.address as usize, s.address as usize + s.size))
+                .collect::<Ranges>()
+        })
+        .unwrap_or_default();
+
+    for &thunk in thunks {
+        if let Some(size) = crate::buffer::trampolines::get_block_size(thunk) {
+            ranges.push((thunk as usize, thunk as usize + size));
+        }
+    }
+
+    ranges
+}
+
+fn find_stacks() -> Ranges {
+    // Stacks are private writable mappings, frame pointers are only followed inside them.
+    let access = platform::ProtFlags::PROT_READ | platform::ProtFlags::PROT_WRITE;
+
+    platform::mappings::get()
+        .unwrap_or_default()
+        .into_iter()
+        .filter(|m| !m.is_shared && m.flags.contains(access))
+        .map(|m| (m.base as usize, m.end as usize))
+        .collect()
+}
+
+fn find_blocker(tid: u32) -> Option<&'static str> {
+    let status = std::fs::read_to_string(format!("/proc/self/task/{}/status", tid)).ok()?;
+    let field = |name: &str| {
+        status
+            .lines()
+            .find_map(|l| l.strip_prefix(name))
+            .map(str::trim)
+    };
+
+    // Stopped or traced threads only run the handler once they resume.
+    if field("State:").is_some_and(|s| s.starts_with('T') || s.starts_with('t')) {
+        return Some("is stopped");
+    }
+
+    let blocked = u64::from_str_radix(field("SigBlk:")?, 16).ok()?;
+
+    match blocked & (1 << (SAMPLE_SIGNAL as u64 - 1)) != 0 {
+        true => Some("blocks the sampling signal"),
+        false => None,
+    }
+}
+
+unsafe fn collect_sample(context: *const platform::c_void) -> (usize, bool) {
+    let mut count = 0;
+
+    let mut push = |address: Address| {
+        if count < SAMPLE_SIZE {
+            SAMPLE[count].store(address as usize, Ordering::Relaxed);
+            count += 1;
+        }
+    };
+
+    let stacks = match SAMPLE_STACKS.load(Ordering::Acquire) {
+        stacks if stacks.is_null() => &[][..],
+        stacks => std::slice::from_raw_parts(stacks, SAMPLE_STACK_COUNT.load(Ordering::Acquire)),
+    };
+
+    platform::walk_context(context, stacks, |ip| {
+        push(ip);
+        true
+    });
+
+    // Read through the published table, the handler can't touch thread locals.
+    let inspected = crate::buffer::frames::in_flight(&mut push);
+    (count, inspected)
+}
+
+extern "C" fn sample_handler(
+    _: platform::c_int,
+    _: *mut platform::siginfo_t,
+    context: *mut platform::c_void,
+) {
+    unsafe {
+        let request = SAMPLE_STATE.load(Ordering::Acquire);
+        let tid = (request >> 2) as u32;
+
+        // A late signal from a timed out request must not overwrite another sample.
+        if request & SAMPLE_TAG != SAMPLE_OPEN || tid != platform::get_thread_id() {
+            return;
+        }
+
+        let claimed = request - SAMPLE_OPEN + SAMPLE_CLAIMED;
+
+        if SAMPLE_STATE
+            .compare_exchange(request, claimed, Ordering::AcqRel, Ordering::Relaxed)
+            .is_err()
+        {
+            return;
+        }
+
+        let (count, inspected) = self::collect_sample(context);
+        SAMPLE_COUNT.store(count, Ordering::Relaxed);
+        SAMPLE_BUSY.store(!inspected, Ordering::Relaxed);
+        SAMPLE_STATE.store(request - SAMPLE_OPEN + SAMPLE_DONE, Ordering::Release);
+    }
+}
+
+fn read_sample(ranges: &Ranges) -> Sample {
+    if SAMPLE_BUSY.load(Ordering::Relaxed) {
+        return Sample::Dispatching;
+    }
+
+    let count = SAMPLE_COUNT.load(Ordering::Relaxed);
+
+    SAMPLE[..count]
+        .iter()
+        .map(|address| address.load(Ordering::Relaxed) as Address)
+        .find(|&address| self::contains(ranges, address))
+        .map_or(Sample::Idle, Sample::Inside)
+}
+
+unsafe fn sample_self(ranges: &Ranges) -> Sample {
+    let mut inside = None;
+
+    // Outside a signal handler the unwinder is safe to use.
+    platform::walk_stack(|ip| match self::contains(ranges, ip) {
+        true => {
+            inside = Some(ip);
+            false
+        }
+        false => true,
+    });
+
+    crate::buffer::frames::in_flight(|address| {
+        if inside.is_none() && self::contains(ranges, address) {
+            inside = Some(address);
+        }
+    });
+
+    inside.map_or(Sample::Idle, Sample::Inside)
+}
+
+unsafe fn sample_thread(tid: u32, ranges: &Ranges, stacks: &Ranges) -> Sample {
+    if let Some(reason) = self::find_blocker(tid) {
+        return Sample::Unverifiable(reason);
+    }
+
+    // Published before the request, the handler only reads them once it claimed it.
+    SAMPLE_STACKS.store(stacks.as_ptr() as *mut _, Ordering::Release);
+    SAMPLE_STACK_COUNT.store(stacks.len(), Ordering::Release);
+
+    let sequence = SAMPLE_SEQUENCE.fetch_add(1, Ordering::Relaxed) + 1;
+    let request = (sequence << 34) | ((tid as u64) << 2) | SAMPLE_OPEN;
+    let done = request - SAMPLE_OPEN + SAMPLE_DONE;
+
+    SAMPLE_STATE.store(request, Ordering::Release);
+
+    if platform::signal_thread(tid, SAMPLE_SIGNAL).is_err() {
+        // The thread exited since we listed it.
+        SAMPLE_STATE.store(0, Ordering::Release);
+        return Sample::Idle;
+    }
+
+    let deadline = Instant::now() + SAMPLE_TIMEOUT;
+
+    while SAMPLE_STATE.load(Ordering::Acquire) != done {
+        // Withdraw the request, unless the handler already claimed it and is about to finish.
+        if Instant::now() >= deadline
+            && SAMPLE_STATE
+                .compare_exchange(request, 0, Ordering::AcqRel, Ordering::Relaxed)
+                .is_ok()
+        {
+            return Sample::Unverifiable("did not respond");
+        }
+
+        std::thread::yield_now();
+    }
+
+    let sample = self::read_sample(ranges);
+    SAMPLE_STATE.store(0, Ordering::Release);
+    sample
+}
+
+fn list_threads() -> Vec<u32> {
+    match std::fs::read_dir("/proc/self/task") {
+        Ok(entries) => entries
+            .filter_map(|e| e.ok()?.file_name().to_str()?.parse().ok())
+            .collect(),
+        Err(_) => Vec::new(),
+    }
+}
+
+unsafe fn install_handler() -> Result<platform::SigAction> {
+    let action = platform::SigAction::new(
+        platform::SigHandler::SigAction(sample_handler),
+        platform::SaFlags::SA_RESTART | platform::SaFlags::SA_SIGINFO,
+        platform::SigSet::empty(),
+    );
+
+    platform::sigaction(SAMPLE_SIGNAL, &action).map_err(|e| platform::wrap_system_error(e))
+}
+
+// Quiesce
+
+/// Threads that can't be sampled, because they block the signal, are stopped or never answer,
+/// are logged and assumed to be outside the module, rather than keeping it loaded forever.
+pub(crate) unsafe fn wait_quiescent(base: Address, path: &Path, thunks: &[Address]) -> Result<()> {
+    let ranges = self::find_ranges(base, thunks);
+
+    if ranges.is_empty() {
+        return Ok(());
+    }
+
+    let _pass = PASS.lock();
+
+    // Unloading from the module's own code can never become quiescent.
+    if let Sample::Inside(address) = self::sample_self(&ranges) {
+        crate::log::write(&format!(
+            "{}: freed from its own code at {:p}, it stays loaded.",
+            path.display(),
+            address
+        ));
+
+        return Err(Error::Busy);
+    }
+
+    let old_handler = self::install_handler()?;
+    let deadline = Instant::now() + QUIESCE_TIMEOUT;
+    let own = platform::get_thread_id();
+
+    let result = loop {
+        let stacks = self::find_stacks();
+        let mut busy = Vec::new();
+        let mut unverified = Vec::new();
+
+        for tid in self::list_threads().into_iter().filter(|&tid| tid != own) {
+            match self::sample_thread(tid, &ranges, &stacks) {
+                Sample::Inside(address) => busy.push(format!("thread {} at {:p}", tid, address)),
+                Sample::Dispatching => busy.push(format!("thread {} in a hook stub", tid)),
+                Sample::Unverifiable(reason) => {
+                    unverified.push(format!("thread {} {}", tid, reason))
+                }
+                Sample::Idle => {}
+            }
+        }
+
+        if busy.is_empty() {
+            if !unverified.is_empty() {
+                crate::log::write(&format!(
+                    "{}: could not inspect {}, assuming they are outside.",
+                    path.display(),
+                    unverified.join(", ")
+                ));
+            }
+
+            break Ok(());
+        }
+
+        if Instant::now() >= deadline {
+            crate::log::write(&format!(
+                "{}: still in use after {:?} ({}), it stays loaded.",
+                path.display(),
+                QUIESCE_TIMEOUT,
+                busy.join(", ")
+            ));
+
+            break Err(Error::Busy);
+        }
+
+        std::thread::sleep(POLL_INTERVAL);
+    };
+
+    let _ = platform::sigaction(SAMPLE_SIGNAL, &old_handler);
+    SAMPLE_STACKS.store(std::ptr::null_mut(), Ordering::Release);
+    SAMPLE_STACK_COUNT.store(0, Ordering::Release);
+    result
+}
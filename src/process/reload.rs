@@ -0,0 +1,421 @@
+// Includes
+
+use super::owners::{self, Resource};
+use crate::buffer::ext_data;
+use crate::ldr;
+use crate::types::*;
+use lazy_static::*;
+use mlsys::*;
+
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+// Types
+
+pub type MigrateCallback = unsafe extern "C" fn(FNV, Address, Address) -> Address;
+
+struct Watch {
+    handle: Handle,
+    base: SyncAddress,
+    descriptor: platform::WatchDescriptor,
+    directory: PathBuf,
+    name: OsString,
+    migrate: Option<MigrateCallback>,
+    data: SyncAddress,
+    changed: Option<Instant>,
+}
+
+struct Stash {
+    state: ldr::BinaryState,
+    kept: Vec<Resource>,
+}
+
+#[derive(Default)]
+struct Watcher {
+    inotify: Option<platform::Inotify>,
+    watches: Vec<Watch>,
+}
+
+// Globals
+
+const SETTLE_DELAY: Duration = Duration::from_millis(250);
+
+lazy_static! {
+    static ref WATCHER: Mutex<Watcher> = Mutex::new(Watcher::default());
+    static ref STASHES: Mutex<Vec<Stash>> = Mutex::new(Vec::new());
+}
+
+// Helpers
+
+unsafe impl Send for Watcher {}
+
+unsafe impl Send for Stash {}
+
+fn split_path(path: &Path) -> Result<(PathBuf, OsString)> {
+    match (path.parent(), path.file_name()) {
+        (Some(directory), Some(name)) => Ok((directory.to_path_buf(), name.to_os_string())),
+        _ => Err(Error::InvalidArgument),
+    }
+}
+
+fn image_name(path: &Path) -> String {
+    match path.file_name() {
+        Some(name) => name.to_string_lossy().into_owned(),
+        None => String::from("mlrt-module"),
+    }
+}
+
+unsafe fn reattach(h: Handle, resources: Vec<Resource>) {
+    for resource in resources {
+        // The new instance may have inserted the extension again from its constructors.
+        if let Resource::Extension(id) = resource {
+            owners::untrack(|r| matches!(r, Resource::Extension(e) if *e == id));
+        }
+
+        owners::track(h, resource);
+    }
+}
+
+unsafe fn drop_kept(resources: Vec<Resource>) {
+    for resource in resources {
+        let _ = match resource {
+            Resource::Extension(id) => ext_data::remove(id),
+            Resource::Allocation(address) => crate::memory::free(address),
+            _ => Ok(()),
+        };
+    }
+}
+
+fn take_stash(path: &Path) -> Option<Stash> {
+    let stashes = &mut *STASHES.lock();
+    let i = stashes.iter().position(|s| s.state.path == path)?;
+    Some(stashes.remove(i))
+}
+
+fn is_stashed(path: &Path) -> bool {
+    STASHES.lock().iter().any(|s| s.state.path == path)
+}
+
+fn is_loaded(base: Address) -> bool {
+    // Handles dangle once their module is unmapped, the linker still knows its base.
+    unsafe { super::get_module_base_from_address(base).ok() == Some(base) }
+}
+
+unsafe fn load_build(
+    old: Handle,
+    stash: Stash,
+    image: &[u8],
+    migrate: Option<MigrateCallback>,
+    data: Address,
+) -> Result<Handle> {
+    let Stash { state, kept } = stash;
+
+    // The linker returns the unloaded instance for the same path, load a copy of the file.
+    let loaded = super::load_module_from_memory_internal(image, &self::image_name(&state.path))
+        .and_then(|h| super::initialize_module(h, &state.path, Some(image), &state.mid));
+
+    let new = match loaded {
+        Ok(new) => new,
+        Err(e) => {
+            crate::log::write(&format!(
+                "{}: the new build could not be loaded: {:?}, its extensions wait for the next one.",
+                state.path.display(),
+                e
+            ));
+
+            STASHES.lock().push(Stash { state, kept });
+            return Err(e);
+        }
+    };
+
+    ldr::restore_records(new, &state.records);
+
+    for resource in kept.iter() {
+        if let Resource::Extension(id) = *resource {
+            if let (Some(migrate), Some(ext)) = (migrate, ext_data::get(id)) {
+                ext_data::replace(id, migrate(id, ext, data));
+            }
+        }
+    }
+
+    self::reattach(new, kept);
+
+    // The watch follows the module to its new instance.
+    let base = super::get_module_base(new).unwrap_or(NULLPTR);
+
+    for watch in WATCHER.lock().watches.iter_mut() {
+        if watch.handle == old {
+            watch.handle = new;
+            watch.base = SyncAddress::from(base);
+        }
+    }
+
+    Ok(new)
+}
+
+unsafe fn retry_reload(
+    old: Handle,
+    path: &Path,
+    migrate: Option<MigrateCallback>,
+    data: Address,
+) -> Result<Handle> {
+    let stash = self::take_stash(path).ok_or(Error::ItemNotFound)?;
+
+    let image = std::fs::read(path)
+        .map_err(|_| Error::ItemNotFound)
+        .and_then(|image| {
+            ldr::validate_ml_binary(path, &image, &stash.state.mid)?;
+            Ok(image)
+        });
+
+    match image {
+        Ok(image) => self::load_build(old, stash, &image, migrate, data),
+        Err(e) => {
+            STASHES.lock().push(stash);
+            Err(e)
+        }
+    }
+}
+
+fn get_inotify(watcher: &mut Watcher) -> Result<platform::Inotify> {
+    if let Some(inotify) = watcher.inotify {
+        return Ok(inotify);
+    }
+
+    let flags = platform::InitFlags::IN_NONBLOCK | platform::InitFlags::IN_CLOEXEC;
+    let inotify =
+        platform::Inotify::init(flags).map_err(|e| unsafe { platform::wrap_system_error(e) })?;
+
+    watcher.inotify = Some(inotify);
+    Ok(inotify)
+}
+
+// Reload
+
+pub unsafe fn reload_module(
+    h: Handle,
+    migrate: Option<MigrateCallback>,
+    data: Address,
+) -> Result<Handle> {
+    let base = super::get_module_base(h)?;
+    let state = ldr::get_ml_state(base).ok_or(Error::InvalidArgument)?;
+
+    // Other references would keep the old instance mapped.
+    if state.refs != 1 {
+        crate::log::write(&format!(
+            "{}: loaded {} times, it can't be reloaded.",
+            state.path.display(),
+            state.refs
+        ));
+
+        return Err(Error::Busy);
+    }
+
+    // Read and check the new build first, a missing or broken file leaves the old instance running.
+    let image = std::fs::read(&state.path).map_err(|_| Error::ItemNotFound)?;
+    ldr::validate_ml_binary(&state.path, &image, &state.mid)?;
+
+    let mut kept = owners::detach(base, |r| matches!(r, Resource::Extension(_)));
+
+    let exts = kept
+        .iter()
+        .filter_map(|r| match r {
+            Resource::Extension(id) => ext_data::get(*id),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+
+    // Extensions living in memory the module allocated keep that memory.
+    kept.extend(owners::detach(
+        base,
+        |r| matches!(r, Resource::Allocation(a) if exts.contains(a)),
+    ));
+
+    if let Err(e) = super::free_module(h) {
+        self::reattach(h, kept);
+        return Err(e);
+    }
+
+    self::load_build(h, Stash { state, kept }, &image, migrate, data)
+}
+
+pub unsafe fn watch_module(
+    h: Handle,
+    migrate: Option<MigrateCallback>,
+    data: Address,
+) -> Result<()> {
+    let base = super::get_module_base(h)?;
+    let state = ldr::get_ml_state(base).ok_or(Error::InvalidArgument)?;
+    let (directory, name) = self::split_path(&state.path)?;
+
+    let mut watcher = WATCHER.lock();
+
+    if watcher.watches.iter().any(|w| w.handle == h) {
+        return Err(Error::InvalidArgument);
+    }
+
+    // Builds replace the file, watch its directory for writes and renames.
+    let flags = platform::AddWatchFlags::IN_CLOSE_WRITE | platform::AddWatchFlags::IN_MOVED_TO;
+
+    let descriptor = self::get_inotify(&mut watcher)?
+        .add_watch(&directory, flags)
+        .map_err(|e| platform::wrap_system_error(e))?;
+
+    watcher.watches.push(Watch {
+        handle: h,
+        base: SyncAddress::from(base),
+        descriptor,
+        directory,
+        name,
+        migrate,
+        data: SyncAddress::from(data),
+        changed: None,
+    });
+
+    Ok(())
+}
+
+pub unsafe fn unwatch_module(h: Handle) -> Result<()> {
+    let watch = {
+        let watcher = &mut *WATCHER.lock();
+
+        let watch = match watcher.watches.iter().position(|w| w.handle == h) {
+            Some(i) => watcher.watches.remove(i),
+            None => return Err(Error::ItemNotFound),
+        };
+
+        // Watches of the same directory share a descriptor.
+        if !watcher
+            .watches
+            .iter()
+            .any(|w| w.directory == watch.directory)
+        {
+            if let Some(inotify) = watcher.inotify {
+                let _ = inotify.rm_watch(watch.descriptor);
+            }
+        }
+
+        watch
+    };
+
+    // Nothing retries a module which is gone once its watch is, drop what it kept.
+    if !self::is_loaded(watch.base.extract()) {
+        if let Some(stash) = self::take_stash(&watch.directory.join(&watch.name)) {
+            self::drop_kept(stash.kept);
+        }
+    }
+
+    Ok(())
+}
+
+pub unsafe fn poll_module_changes() -> usize {
+    let pending = {
+        let watcher = &mut *WATCHER.lock();
+        let now = Instant::now();
+
+        let events = match watcher.inotify {
+            Some(inotify) => inotify.read_events().unwrap_or_default(),
+            None => return 0,
+        };
+
+        for event in events {
+            for watch in watcher.watches.iter_mut() {
+                if watch.descriptor == event.wd && event.name.as_ref() == Some(&watch.name) {
+                    watch.changed = Some(now);
+                }
+            }
+        }
+
+        // Wait until the build stopped writing before picking the file up.
+        watcher
+            .watches
+            .iter_mut()
+            .filter(|w| {
+                w.changed
+                    .is_some_and(|t| now.duration_since(t) >= SETTLE_DELAY)
+            })
+            .map(|w| {
+                w.changed = None;
+                (
+                    w.handle,
+                    w.base,
+                    w.directory.join(&w.name),
+                    w.migrate,
+                    w.data,
+                )
+            })
+            .collect::<Vec<_>>()
+    };
+
+    let mut reloaded = 0;
+
+    for (h, base, path, migrate, data) in pending {
+        // A build which failed to load is retried from what its predecessor kept.
+        let result = match self::is_loaded(base.extract()) {
+            true => self::reload_module(h, migrate, data.extract()),
+            false => self::retry_reload(h, &path, migrate, data.extract()),
+        };
+
+        match result {
+            Ok(_) => reloaded += 1,
+            Err(e) => {
+                crate::log::write(&format!("Could not reload {}: {:?}.", path.display(), e));
+
+                // The module is gone for good, stop watching it.
+                if !self::is_loaded(base.extract()) && !self::is_stashed(&path) {
+                    let _ = self::unwatch_module(h);
+                }
+            }
+        }
+    }
+
+    reloaded
+}
+
+// Bindings
+
+#[no_mangle]
+unsafe extern "C" fn MLProcReloadModule(
+    h: Handle,
+    migrate: Option<MigrateCallback>,
+    data: Address,
+    out: *mut Handle,
+) -> Error {
+    if out.is_null() {
+        return Error::InvalidArgument;
+    }
+
+    match self::reload_module(h, migrate, data) {
+        Ok(new) => {
+            *out = new;
+            Error::Success
+        }
+        Err(e) => e,
+    }
+}
+
+#[no_mangle]
+unsafe extern "C" fn MLProcWatchModule(
+    h: Handle,
+    migrate: Option<MigrateCallback>,
+    data: Address,
+) -> Error {
+    match self::watch_module(h, migrate, data) {
+        Ok(()) => Error::Success,
+        Err(e) => e,
+    }
+}
+
+#[no_mangle]
+unsafe extern "C" fn MLProcUnwatchModule(h: Handle) -> Error {
+    match self::unwatch_module(h) {
+        Ok(()) => Error::Success,
+        Err(e) => e,
+    }
+}
+
+#[no_mangle]
+unsafe extern "C" fn MLProcPollModuleChanges() -> usize {
+    self::poll_module_changes()
+}
@@ -0,0 +1,155 @@
+// Includes
+
+use crate::types::*;
+use lazy_static::*;
+use mlsys::*;
+
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+
+// Globals
+
+lazy_static! {
+    static ref LINKER_PATHS: Mutex<Vec<PathBuf>> = Mutex::new(Vec::new());
+    static ref DEPENDENCIES: Mutex<NoHashMap<SyncAddress, Vec<SyncAddress>>> =
+        Mutex::new(NoHashMap::default());
+}
+
+// Helpers
+
+fn is_bare_name(p: &Path) -> bool {
+    // The linker searches its paths only for names without a directory.
+    p.components().count() == 1 && p.is_relative()
+}
+
+fn find_in_linker_paths(name: &Path) -> Option<PathBuf> {
+    LINKER_PATHS
+        .lock()
+        .iter()
+        .map(|directory| directory.join(name))
+        .find(|p| p.is_file())
+}
+
+unsafe fn preload_dependencies(
+    p: &Path,
+    visited: &mut HashSet<PathBuf>,
+    loaded: &mut Vec<SyncAddress>,
+) {
+    let needed = match mldl::find_needed(p) {
+        Ok(needed) => needed,
+        Err(_) => return,
+    };
+
+    for name in needed {
+        // Dependencies the linker already knows or finds by itself are left to it.
+        if let Ok(h) = super::get_module(&name) {
+            let _ = super::free_module_internal(h);
+            continue;
+        }
+
+        let found = match self::find_in_linker_paths(Path::new(&name)) {
+            Some(found) => found,
+            None => continue,
+        };
+
+        // Cyclic or shared dependencies are only walked once.
+        if !visited.insert(found.clone()) {
+            continue;
+        }
+
+        // Their own dependencies may only be in our paths too.
+        self::preload_dependencies(&found, visited, loaded);
+
+        match super::open_module(&found) {
+            Ok(h) => loaded.push(SyncAddress::from(h as Address)),
+            Err(e) => crate::log::write(&format!(
+                "{}: could not preload {}: {:?}.",
+                p.display(),
+                found.display(),
+                e
+            )),
+        }
+    }
+}
+
+// Search
+
+pub unsafe fn add_linker_path(p: &Path) -> Result<()> {
+    let path = std::fs::canonicalize(p).map_err(|_| Error::ItemNotFound)?;
+
+    if !path.is_dir() {
+        return Err(Error::InvalidArgument);
+    }
+
+    let paths = &mut *LINKER_PATHS.lock();
+
+    if paths.contains(&path) {
+        return Err(Error::InvalidArgument);
+    }
+
+    paths.push(path);
+    Ok(())
+}
+
+pub unsafe fn remove_linker_path(p: &Path) -> Result<()> {
+    // The directory may have been deleted since it was added.
+    let path = std::fs::canonicalize(p).unwrap_or_else(|_| p.to_path_buf());
+    let paths = &mut *LINKER_PATHS.lock();
+
+    match paths.iter().position(|x| *x == path) {
+        Some(i) => {
+            paths.remove(i);
+            Ok(())
+        }
+        None => Err(Error::ItemNotFound),
+    }
+}
+
+pub(crate) fn resolve_module_path(p: &Path) -> PathBuf {
+    match self::is_bare_name(p) {
+        true => self::find_in_linker_paths(p).unwrap_or_else(|| p.to_path_buf()),
+        false => p.to_path_buf(),
+    }
+}
+
+pub(crate) fn find_module_in_linker_paths(name: &str) -> Vec<PathBuf> {
+    match self::is_bare_name(Path::new(name)) {
+        true => LINKER_PATHS
+            .lock()
+            .iter()
+            .map(|directory| directory.join(name))
+            .collect(),
+        false => Vec::new(),
+    }
+}
+
+pub(crate) unsafe fn load_dependencies(p: &Path) -> Vec<SyncAddress> {
+    let mut loaded = Vec::new();
+    self::preload_dependencies(p, &mut HashSet::from([p.to_path_buf()]), &mut loaded);
+    loaded
+}
+
+pub(crate) fn retain_dependencies(base: Address, loaded: Vec<SyncAddress>) {
+    if !loaded.is_empty() {
+        DEPENDENCIES
+            .lock()
+            .entry(SyncAddress::from(base))
+            .or_default()
+            .extend(loaded);
+    }
+}
+
+pub(crate) unsafe fn free_dependencies(loaded: Vec<SyncAddress>) {
+    // Dependents go first, they were preloaded last.
+    for h in loaded.into_iter().rev() {
+        let _ = super::free_module_internal(h.extract() as Handle);
+    }
+}
+
+pub(crate) unsafe fn release_dependencies(base: Address) {
+    let loaded = DEPENDENCIES.lock().remove(&SyncAddress::from(base));
+
+    if let Some(loaded) = loaded {
+        self::free_dependencies(loaded);
+    }
+}